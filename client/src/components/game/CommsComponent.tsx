@@ -18,6 +18,8 @@ export interface MoveTokenMessage {
     token_id: string,
     dx: number,
     dy: number,
+    // Ids of earlier moves the server merged into this one
+    merged_ids?: string[],
 }
 
 export interface RenameTokenMessage {
@@ -45,10 +47,19 @@ export interface DisconnectMessage {
     username: string,
 }
 
+export interface RenameUserMessage {
+    username: string,
+    new_username: string,
+}
+
 export interface FailedConnectionMessage {
     reason: string,
 }
 
+export interface ServerShutdownMessage {
+    reason: string,
+}
+
 export interface MoveToken {
     id: string,
     token_id: string,
@@ -69,10 +80,16 @@ export class Comms {
 
     connectListeners: Record<string, (msg: ConnectMessage) => void> = {};
     disconnectListeners: Record<string, (msg: DisconnectMessage) => void> = {};
+    renameUserListeners: Record<string, (msg: RenameUserMessage) => void> = {};
 
     failedListeners: Record<string, (msg: FailedConnectionMessage) => void> = {};
+    // Called when the server is about to send the whole game state again, after we fell behind
+    resyncListeners: Record<string, () => void> = {};
+    shutdownListeners: Record<string, (msg: ServerShutdownMessage) => void> = {};
 
     shouldShowRefresh = true;
+    // Set once the server has said why it's closing the connection
+    shutdownReason: string = null;
     isHost = false;
     hostId = -1;
     user: User = null;
@@ -90,7 +107,12 @@ export class Comms {
             socket.send(props.gameToken);
         };
 
-        socket.onclose = props.onDisconnect;
+        socket.onclose = () => {
+            // Shutdown listeners have already told the user what happened
+            if (this.shutdownReason == null) {
+                props.onDisconnect();
+            }
+        };
 
         socket.onmessage = message => {
             // Interface with Rust JSON serialiser
@@ -137,6 +159,22 @@ export class Comms {
                     Object.values(this.disconnectListeners).forEach(op => op(data));
                     break;
                 }
+                case 'RenameUser': {
+                    if (data.username == this.user.username) {
+                        this.user.username = data.new_username;
+                    }
+                    Object.values(this.renameUserListeners).forEach(op => op(data));
+                    break;
+                }
+                case 'ServerShutdown': {
+                    this.shutdownReason = data.reason;
+                    Object.values(this.shutdownListeners).forEach(op => op(data));
+                    break;
+                }
+                case 'Resync': {
+                    Object.values(this.resyncListeners).forEach(op => op());
+                    break;
+                }
                 case 'FailedConnection': {
                     // If the websocket failed, don't pop up since the user will see a splash screen
                     this.shouldShowRefresh = false;
@@ -255,6 +293,18 @@ export class Comms {
         this.failedListeners[ref] = listener;
     }
 
+    addRenameUserListener(ref: string, listener: ((msg: RenameUserMessage) => void)): void {
+        this.renameUserListeners[ref] = listener;
+    }
+
+    addResyncListener(ref: string, listener: (() => void)): void {
+        this.resyncListeners[ref] = listener;
+    }
+
+    addShutdownListener(ref: string, listener: ((msg: ServerShutdownMessage) => void)): void {
+        this.shutdownListeners[ref] = listener;
+    }
+
     async loadObjs(setObjs: (objs: GameObj[]) => void): Promise<void> {
         if (this.hostId >= 0) {
             const allObjs = this.isHost
@@ -51,24 +51,33 @@ export const GameLanding = function (props: GameLandingProps): React.ReactElemen
             comms.isHost = true;
         }
 
-        // check that we didn't already have this player
-        for (const existing of players) {
-            if (existing.name == player.name) {
-                return;
+        // Updated from the latest list, as several players can arrive before the next render
+        setPlayers(players => {
+            // check that we didn't already have this player
+            if (players.some(existing => existing.name == player.name)) {
+                return players;
             }
-        }
-
-        // put host first
-        if (player.host) {
-            setPlayers([player].concat(players));
-        } else {
-            setPlayers(players.concat([player]));
-        }
 
+            // put host first
+            return player.host ? [player].concat(players) : players.concat([player]);
+        });
     });
 
     comms?.addDisconnectListener('GameLandingDisconnect', msg => {
-        setPlayers(players.filter(({name}) => name != msg.username));
+        setPlayers(players => players.filter(({name}) => name != msg.username));
+    });
+
+    comms?.addRenameUserListener('GameLandingRename', msg => {
+        setPlayers(players => players.map(player => player.name == msg.username
+            ? {...player, name: msg.new_username}
+            : player));
+    });
+
+    // Everyone still here is sent again after a resync
+    comms?.addResyncListener('GameLandingResync', () => setPlayers([]));
+
+    comms?.addShutdownListener('GameLandingShutdown', msg => {
+        props.setMessage(`The game server stopped: ${msg.reason}. Try reloading the page.`);
     });
 
     comms?.addFailedListener('GameLandingFailed', msg => {
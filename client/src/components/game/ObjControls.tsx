@@ -106,7 +106,7 @@ export function ObjControls(props: ObjControlsProps): React.ReactElement {
             <button onClick={openModal}>New...</button>
             <div className="object-list-container">
                 {objs.map(obj => (
-                    <ObjThumbnail key={obj.name} selected={obj.name === props.selectedObj} name={obj.name} url={obj.url}
+                    <ObjThumbnail key={obj.name} selected={obj.name === props.selectedObj} name={obj.name} url={obj.thumbnails?.[0]?.url ?? obj.url}
                                   deleteObjSync={() => deleteObjSync(obj.name)}
                                   objSelected={() => objSelected(obj.name)}
                     />))}
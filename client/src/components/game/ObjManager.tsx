@@ -210,6 +210,15 @@ export class ObjManager {
             forceRender();
         });
 
+        comms.addResyncListener('ObjManagerResync', () => {
+            this.objs = {};
+            this.hoveredObj = null;
+            this.selectedObj = null;
+            this.draggingDir = null;
+            this.deleteButton = null;
+            this.forceRender();
+        });
+
         comms.addDeleteObjListener('ObjManagerDelete', (msg) => {
             const obj_id = msg?.obj_id;
             if (obj_id) {
@@ -178,9 +178,11 @@ export class TokenManager {
             this.forceRender();
         });
 
-        comms.addMoveTokenListener('TokenLayerMove', ({ id, token_id, dx, dy }) => {
+        comms.addMoveTokenListener('TokenLayerMove', ({ id, token_id, dx, dy, merged_ids }) => {
             if (token_id in this.tentativeMovements) {
-                delete this.tentativeMovements[token_id][id];
+                for (const move_id of [id, ...(merged_ids ?? [])]) {
+                    delete this.tentativeMovements[token_id][move_id];
+                }
             }
             const delta = { x: dx, y: dy };
             // todo: should use tentative structure
@@ -193,6 +195,27 @@ export class TokenManager {
             this.forceRender();
         });
 
+        comms.addRenameUserListener('TokenLayerRenameUser', ({ username, new_username }) => {
+            for (const token of Object.values(this.tokens)) {
+                if (token.controller == username) {
+                    token.controller = new_username;
+                }
+            }
+            this.forceRender();
+        });
+
+        comms.addResyncListener('TokenLayerResync', () => {
+            this.tokens = {};
+            this.tentativeMovements = {};
+            this.hoveredToken = null;
+            this.selectedToken = null;
+            this.deleteButton = null;
+            this.optionButton = null;
+            this.editButton = null;
+            this.options = null;
+            this.forceRender();
+        });
+
         comms.addRenameTokenListener('TokenLayerRename', ({ token_id, name }) => {
             this.tokens[token_id].name = name;
         });
@@ -1,7 +1,13 @@
+export interface Thumbnail {
+    size: number,
+    url: string,
+}
+
 export interface GameObj {
     id: number,
     name: string,
     width?: number,
     height?: number,
     url?: string,
+    thumbnails?: Thumbnail[],
 }
\ No newline at end of file
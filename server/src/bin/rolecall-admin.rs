@@ -0,0 +1,174 @@
+use std::error::Error;
+use std::io::{self, BufRead, Read};
+use std::{env, fs, process};
+
+use rolecall::config::{self, Args, Config, Settings};
+use rolecall::db::{self, Database, DbError, GameExport};
+use rolecall::logging;
+
+const USAGE: &str = "\
+usage: rolecall-admin [--config FILE] [--SETTING VALUE]... COMMAND [ARGS]
+
+Uses the same configuration as the server, see rolecall --help.
+
+commands:
+  migrate                                create missing tables and upgrade old ones
+  create-user EMAIL NICKNAME [--admin]   create a confirmed user, reading their password
+  reset-password EMAIL                   change a user's password, reading it from stdin
+  games                                  list every game
+  members GAME                           list the people in a game
+  export-game GAME [FILE]                write a game out as JSON, to stdout by default
+  import-game [FILE]                     recreate an exported game, from stdin by default
+  purge-files                            delete unused uploads older than an hour
+";
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (options, command) = args.split_at(command_start(&args));
+    let options = Args::parse(options.iter().cloned()).unwrap_or_else(|e| exit_with(&e));
+    if options.help || (command.is_empty() && !options.print_config) {
+        print!("{}", USAGE);
+        return;
+    }
+    let settings = Settings::gather(&options).unwrap_or_else(|e| exit_with(&e));
+    if options.print_config {
+        print!("{}", settings);
+        return;
+    }
+    let loaded = Config::from_settings(&settings).unwrap_or_else(|e| exit_with(&e));
+    // Exports go to stdout, so logs can't
+    logging::init_stderr(loaded.log_level, loaded.log_format);
+    config::install(loaded);
+
+    let db = db::create_database().unwrap_or_else(|e| exit_with(&e));
+    if let Err(e) = run(db.as_ref(), command).await {
+        exit_with(e.as_ref());
+    }
+}
+
+fn exit_with(e: &dyn Error) -> ! {
+    eprintln!("rolecall-admin: {}", e);
+    process::exit(2);
+}
+
+/// Finds where the command starts, after any options for the config.
+fn command_start(args: &[String]) -> usize {
+    let mut i = 0;
+    while let Some(arg) = args.get(i) {
+        if !arg.starts_with('-') {
+            break;
+        }
+        let takes_value =
+            !arg.contains('=') && !matches!(arg.as_str(), "--help" | "-h" | "--print-config");
+        i += if takes_value { 2 } else { 1 };
+    }
+    i.min(args.len())
+}
+
+/// `DbError::Auth` also means something wasn't found, so gets replaced with what that was.
+fn not_found(what: String) -> impl FnOnce(DbError) -> Box<dyn Error> {
+    move |e| match e {
+        DbError::Auth => what.into(),
+        e => e.into(),
+    }
+}
+
+async fn run(db: &dyn Database, command: &[String]) -> Result<(), Box<dyn Error>> {
+    let args: Vec<&str> = command.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["migrate"] => {
+            db.create_tables().await?;
+            println!("database schema is up to date");
+        }
+        ["create-user", email, nickname, flags @ ..] => {
+            let admin = match flags {
+                [] => false,
+                ["--admin"] => true,
+                _ => return Err(format!("unexpected arguments {:?}", flags).into()),
+            };
+            let password = read_password()?;
+            let token = db.create_user(email, &password, nickname).await?;
+            let token = db.confirm_user(email, &token).await?;
+            if admin {
+                db.set_admin(email, true).await?;
+            }
+            let (_, username) = db.get_account(&token).await?;
+            println!("created {} as {}", email, username);
+        }
+        ["reset-password", email] => {
+            let password = read_password()?;
+            db.set_password(email, &password)
+                .await
+                .map_err(not_found(format!("no user with email {}", email)))?;
+            println!("changed password for {}", email);
+        }
+        ["games"] => {
+            for game in db.list_games().await? {
+                println!(
+                    "{}\t{}\thost {}\t{} players",
+                    game.token, game.name, game.host, game.players
+                );
+            }
+        }
+        ["members", game_token] => {
+            let members = db
+                .get_game_members(game_token)
+                .await
+                .map_err(not_found(format!("no game {}", game_token)))?;
+            for member in members {
+                println!("{}\t{}\t{}", member.id, member.username, member.email);
+            }
+        }
+        ["export-game", game_token, file @ ..] => {
+            let game = db
+                .export_game(game_token)
+                .await
+                .map_err(not_found(format!("no game {}", game_token)))?;
+            let json = serde_json::to_string_pretty(&game)?;
+            match file {
+                [] => println!("{}", json),
+                [file] => fs::write(file, json)?,
+                _ => return Err("export-game takes a single file".into()),
+            }
+        }
+        ["import-game", file @ ..] => {
+            let json = match file {
+                [] => {
+                    let mut json = String::new();
+                    io::stdin().read_to_string(&mut json)?;
+                    json
+                }
+                [file] => fs::read_to_string(file)?,
+                _ => return Err("import-game takes a single file".into()),
+            };
+            let game: GameExport = serde_json::from_str(&json)?;
+            let game_token = db
+                .import_game(&game)
+                .await
+                .map_err(not_found(format!("no user with email {}", game.host)))?;
+            println!("imported {} as game {}", game.name, game_token);
+        }
+        ["purge-files"] => {
+            let purged = db::purge_orphaned_files(db, db::ORPHAN_MIN_AGE).await?;
+            for key in &purged {
+                println!("deleted {}", key);
+            }
+            println!("purged {} files", purged.len());
+        }
+        _ => return Err(format!("unknown command {:?}, see --help", command.join(" ")).into()),
+    }
+    Ok(())
+}
+
+/// Reads a password from the first line of stdin, so it doesn't end up in shell history.
+fn read_password() -> Result<String, Box<dyn Error>> {
+    eprintln!("password:");
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password)?;
+    let password = password.trim_end_matches(&['\r', '\n'][..]).to_string();
+    if password.is_empty() {
+        return Err("the password can't be empty".into());
+    }
+    Ok(password)
+}
@@ -1,7 +1,19 @@
-use simple_logger::SimpleLogger;
+use std::collections::HashMap;
 use std::env;
-use std::fmt::Write;
+use std::fmt::{self, Display, Write};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Mutex;
 use tokio::time::Duration;
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::logging;
+
+/// Where the config file is looked for if neither `--config` nor `RC_CONFIG` say.
+const DEFAULT_CONFIG_FILE: &str = "rolecall.toml";
+/// Shown instead of secrets by `--print-config`.
+const REDACTED: &str = "<redacted>";
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum RunMode {
@@ -9,97 +21,803 @@ pub enum RunMode {
     Release,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    /// One JSON object per line, for log collectors.
+    Json,
+}
+
+pub enum DatabaseConfig {
+    Postgres,
+    Sqlite { path: String },
+}
+
+/// What to do when a game client's outgoing queue fills up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Stop queueing for the client, then send it the whole game state once it catches up.
+    Resync,
+    Disconnect,
+}
+
+/// How messages to game clients are queued.
+pub struct OutboundConfig {
+    /// Messages that can queue up for a client before it counts as too slow.
+    pub buffer: usize,
+    /// Whether to merge queued updates that later ones supersede, e.g. moves of the same token.
+    pub coalesce: bool,
+    pub overflow: OverflowPolicy,
+    /// How long a client can go without taking messages before it's disconnected.
+    pub stall_timeout: Duration,
+}
+
+pub enum StorageConfig {
+    Local,
+    S3 {
+        endpoint: String,
+        region: String,
+        bucket: String,
+        access_key: String,
+        secret_key: String,
+        presign_secs: u32,
+    },
+}
+
+/// How instances sharing games talk to each other.
+pub enum BusConfig {
+    /// Only this instance hosts games.
+    Local,
+    Redis {
+        addr: String,
+    },
+}
+
+/// Where Rocket serves the HTTP API.
+pub struct HttpConfig {
+    pub address: IpAddr,
+    pub port: u16,
+    pub workers: usize,
+    /// Seconds an idle connection is kept open for.
+    pub keep_alive: u32,
+}
+
 pub struct Config {
     pub user_token_timeout: Duration,
     pub game_timeout: Duration,
     pub monitor_interval: Duration,
+    pub outbound: OutboundConfig,
     pub pepper: String,
     pub mode: RunMode,
+    pub log_level: LevelFilter,
+    pub log_format: LogFormat,
+    pub database: DatabaseConfig,
     pub db_addr: String,
     pub db_user: String,
     pub db_password: String,
     pub db_name: String,
+    pub db_pool_size: usize,
+    /// Whether to drop every table when starting, losing all data. Only meant for development.
+    pub db_reset: bool,
+    pub http: HttpConfig,
     pub listen_addr: String,
     pub upload_dir: String,
+    pub storage: StorageConfig,
+    pub bus: BusConfig,
+    /// Emails of users who are made admins when they confirm their account.
+    pub admins: Vec<String>,
     pub max_upload_mb: u64,
+    pub user_quota_mb: u64,
+    pub max_image_dim: u32,
 }
 
-fn load_config() -> Config {
-    dotenv::dotenv().unwrap();
-    let pepper = env::var("RC_PEPPER").unwrap_or_else(|_| {
-        info!("CONFIG: warning: generating random pepper");
-        let bytes = argonautica::utils::generate_random_bytes(32)
-            .expect("CONFIG: Failed to generate pepper");
-        let mut s = String::new();
-        for byte in bytes {
-            write!(&mut s, "{:X}", byte).unwrap();
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file couldn't be read, or isn't TOML.
+    File { path: String, reason: String },
+    /// The command line didn't make sense.
+    Args(String),
+    /// Settings that are missing or have bad values, one message each.
+    Invalid(Vec<String>),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::File { path, reason } => write!(f, "can't load {}: {}", path, reason),
+            ConfigError::Args(msg) => write!(f, "{} (see --help)", msg),
+            ConfigError::Invalid(problems) => {
+                write!(f, "invalid configuration:")?;
+                for problem in problems {
+                    write!(f, "\n  {}", problem)?;
+                }
+                Ok(())
+            }
         }
-        s
-    });
-    let user_token_timeout = Duration::from_secs(
-        env::var("RC_SESSION_TIMEOUT")
-            .unwrap_or("86400".to_string())
-            .parse()
-            .expect("CONFIG: failed to parse session timeout"),
-    );
-    let game_timeout = Duration::from_secs(
-        env::var("RC_GAME_TIMEOUT")
-            .unwrap_or("1800".to_string())
-            .parse()
-            .expect("CONFIG: failed to parse game timeout"),
-    );
-    let monitor_interval = Duration::from_secs(
-        env::var("RC_MONITOR_INTERVAL")
-            .unwrap_or("300".to_string())
-            .parse()
-            .expect("CONFIG: failed to parse monitor interval"),
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// A setting, which can be given in the config file, an environment variable or on the command
+/// line, in increasing order of priority.
+struct Setting {
+    /// Name in the config file, where `[db] addr` and `db_addr` are the same thing.
+    key: &'static str,
+    env: &'static str,
+    default: Option<&'static str>,
+    /// Kept out of `--print-config`.
+    secret: bool,
+}
+
+impl Setting {
+    const fn new(key: &'static str, env: &'static str, default: &'static str) -> Self {
+        Setting {
+            key,
+            env,
+            default: Some(default),
+            secret: false,
+        }
+    }
+
+    const fn secret(key: &'static str, env: &'static str, default: Option<&'static str>) -> Self {
+        Setting {
+            key,
+            env,
+            default,
+            secret: true,
+        }
+    }
+
+    const fn optional(key: &'static str, env: &'static str) -> Self {
+        Setting {
+            key,
+            env,
+            default: None,
+            secret: false,
+        }
+    }
+}
+
+const SETTINGS: &[Setting] = &[
+    Setting::new("mode", "RC_MODE", "release"),
+    Setting::secret("pepper", "RC_PEPPER", None),
+    Setting::new("session_timeout", "RC_SESSION_TIMEOUT", "86400"),
+    Setting::new("game_timeout", "RC_GAME_TIMEOUT", "1800"),
+    Setting::new("monitor_interval", "RC_MONITOR_INTERVAL", "300"),
+    Setting::new("client_buffer", "RC_CLIENT_BUFFER", "256"),
+    Setting::new("client_coalesce", "RC_CLIENT_COALESCE", "true"),
+    Setting::new("client_overflow", "RC_CLIENT_OVERFLOW", "resync"),
+    Setting::new("client_stall_timeout", "RC_CLIENT_STALL_TIMEOUT", "30"),
+    Setting::new("log_level", "RC_LOG_LEVEL", "info"),
+    Setting::new("log_format", "RC_LOG_FORMAT", "text"),
+    Setting::new("database", "RC_DB", "postgres"),
+    Setting::new("sqlite_path", "RC_SQLITE_PATH", "rolecall.db"),
+    Setting::new("db_addr", "RC_DB_ADDRESS", "localhost"),
+    Setting::new("db_user", "RC_DB_USER", "postgres"),
+    Setting::secret("db_password", "RC_DB_PASSWORD", Some("password")),
+    Setting::new("db_name", "RC_DB_NAME", "rolecall"),
+    Setting::new("db_pool_size", "RC_DB_POOL_SIZE", "16"),
+    Setting::new("db_reset", "RC_DB_RESET", "false"),
+    Setting::new("http_address", "RC_HTTP_ADDRESS", "0.0.0.0"),
+    Setting::new("http_port", "RC_HTTP_PORT", "8000"),
+    Setting::new("http_workers", "RC_HTTP_WORKERS", "8"),
+    Setting::new("http_keep_alive", "RC_HTTP_KEEP_ALIVE", "5"),
+    Setting::new("websocket_addr", "RC_WEBSOCKET_ADDR", "0.0.0.0:9000"),
+    Setting::new("upload_path", "RC_UPLOAD_PATH", "upload"),
+    Setting::new("storage", "RC_STORAGE", "local"),
+    Setting::optional("s3_endpoint", "RC_S3_ENDPOINT"),
+    Setting::new("s3_region", "RC_S3_REGION", "us-east-1"),
+    Setting::new("s3_bucket", "RC_S3_BUCKET", "rolecall"),
+    Setting::secret("s3_access_key", "RC_S3_ACCESS_KEY", None),
+    Setting::secret("s3_secret_key", "RC_S3_SECRET_KEY", None),
+    Setting::new("s3_presign_secs", "RC_S3_PRESIGN_SECS", "3600"),
+    Setting::new("bus", "RC_BUS", "local"),
+    Setting::new("redis_addr", "RC_REDIS_ADDR", "127.0.0.1:6379"),
+    Setting::new("admins", "RC_ADMINS", ""),
+    Setting::new("max_upload_mb", "RC_MAX_UPLOAD_MB", "20"),
+    Setting::new("user_quota_mb", "RC_USER_QUOTA_MB", "500"),
+    Setting::new("max_image_dim", "RC_MAX_IMAGE_DIM", "8192"),
+];
+
+fn find_setting(key: &str) -> Option<&'static Setting> {
+    SETTINGS.iter().find(|setting| setting.key == key)
+}
+
+/// What the server was asked to do on the command line.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Args {
+    pub config_file: Option<String>,
+    pub print_config: bool,
+    pub help: bool,
+    /// Settings given as `--key value` or `--key=value`.
+    overrides: Vec<(&'static str, String)>,
+}
+
+impl Args {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, ConfigError> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let name = match arg.strip_prefix("--") {
+                Some(name) => name,
+                None if arg == "-h" => "help",
+                None if arg == "-c" => "config",
+                None => return Err(ConfigError::Args(format!("unexpected argument {:?}", arg))),
+            };
+            let (name, inline_value) = match name.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (name, None),
+            };
+            match name {
+                "help" => parsed.help = true,
+                "print-config" => parsed.print_config = true,
+                _ => {
+                    let value = match inline_value.or_else(|| args.next()) {
+                        Some(value) => value,
+                        None => return Err(ConfigError::Args(format!("--{} needs a value", name))),
+                    };
+                    if name == "config" {
+                        parsed.config_file = Some(value);
+                        continue;
+                    }
+                    let setting = find_setting(&name.replace('-', "_"))
+                        .ok_or_else(|| ConfigError::Args(format!("unknown option --{}", name)))?;
+                    parsed.overrides.push((setting.key, value));
+                }
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// What `--help` prints.
+pub fn usage() -> String {
+    let mut usage = String::from(
+        "usage: rolecall [--config FILE] [--print-config] [--SETTING VALUE]...\n\n\
+         Settings come from the config file (rolecall.toml, or RC_CONFIG), then environment\n\
+         variables, then the command line, with later ones taking priority.\n\n\
+         settings:\n",
     );
-    let mode = if env::var("RC_MODE").unwrap_or("release".to_string()) == "debug" {
-        RunMode::Debug
-    } else {
-        RunMode::Release
-    };
+    for setting in SETTINGS {
+        let option = format!("--{}", setting.key.replace('_', "-"));
+        write!(usage, "  {:<24} {:<24}", option, setting.env).unwrap();
+        let result = match setting.default {
+            Some(default) if !setting.secret => writeln!(usage, " [default: {:?}]", default),
+            _ => writeln!(usage),
+        };
+        result.unwrap();
+    }
+    usage
+}
+
+/// Where a setting's value came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Source {
+    Default,
+    File(String),
+    Env(&'static str),
+    Args,
+}
 
-    SimpleLogger::new()
-        .with_utc_timestamps() // see https://github.com/borntyping/rust-simple_logger/issues/52
-        .with_level(
-            env::var("RC_LOG_LEVEL")
+impl Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File(path) => write!(f, "{}", path),
+            Source::Env(var) => write!(f, "{}", var),
+            Source::Args => write!(f, "command line"),
+        }
+    }
+}
+
+/// Every setting's value, before it's been checked, along with where it came from.
+pub struct Settings {
+    values: HashMap<&'static str, (String, Source)>,
+}
+
+impl Settings {
+    /// Reads the config file and environment, and layers the command line on top.
+    pub fn gather(args: &Args) -> Result<Self, ConfigError> {
+        // A missing .env is fine, everything has a default or can be given another way
+        dotenv::dotenv().ok();
+        let path = args
+            .config_file
+            .clone()
+            .or_else(|| env::var("RC_CONFIG").ok());
+        let file = match path {
+            Some(path) => match fs::read_to_string(&path) {
+                Ok(contents) => Some((path, contents)),
+                Err(e) => {
+                    return Err(ConfigError::File {
+                        path,
+                        reason: e.to_string(),
+                    })
+                }
+            },
+            // The default file is optional
+            None => fs::read_to_string(DEFAULT_CONFIG_FILE)
                 .ok()
-                .and_then(|level| level.parse().ok())
-                .unwrap_or(log::LevelFilter::Info),
-        )
-        .init()
-        .expect("CONFIG: failed to initialise logger");
-
-    let db_addr = env::var("RC_DB_ADDRESS").unwrap_or("localhost".to_string());
-    let db_user = env::var("RC_DB_USER").unwrap_or("postgres".to_string());
-    let db_password = env::var("RC_DB_PASSWORD").unwrap_or("password".to_string());
-    let db_name = env::var("RC_DB_NAME").unwrap_or("rolecall".to_string());
-    let listen_addr = env::var("RC_WEBSOCKET_ADDR").unwrap_or("0.0.0.0:9000".to_string());
-    let upload_dir = env::var("RC_UPLOAD_PATH").unwrap_or("upload".to_string());
-    let max_upload_mb = env::var("RC_MAX_UPLOAD_MB")
-        .ok()
-        .and_then(|s| s.parse::<u64>().ok())
-        .unwrap_or(20)
-        * 1024
-        * 1024;
-
-    Config {
-        user_token_timeout,
-        game_timeout,
-        monitor_interval,
-        pepper,
-        mode,
-        db_addr,
-        db_user,
-        db_password,
-        db_name,
-        listen_addr,
-        upload_dir,
-        max_upload_mb,
+                .map(|contents| (DEFAULT_CONFIG_FILE.to_string(), contents)),
+        };
+        let file = file
+            .as_ref()
+            .map(|(path, contents)| (path.as_str(), contents.as_str()));
+        Self::layer(file, |var| env::var(var).ok(), args)
+    }
+
+    fn layer(
+        file: Option<(&str, &str)>,
+        env: impl Fn(&str) -> Option<String>,
+        args: &Args,
+    ) -> Result<Self, ConfigError> {
+        let mut values = HashMap::new();
+        for setting in SETTINGS {
+            if let Some(default) = setting.default {
+                values.insert(setting.key, (default.to_string(), Source::Default));
+            }
+        }
+
+        if let Some((path, contents)) = file {
+            let table = contents
+                .parse::<toml::Value>()
+                .map_err(|e| ConfigError::File {
+                    path: path.to_string(),
+                    reason: e.to_string(),
+                })?;
+            let mut flattened = Vec::new();
+            let mut problems = Vec::new();
+            flatten("", &table, &mut flattened, &mut problems);
+            for (key, value) in flattened {
+                match find_setting(&key) {
+                    Some(setting) => {
+                        values.insert(setting.key, (value, Source::File(path.to_string())));
+                    }
+                    None => problems.push(format!("{}: unknown setting {:?}", path, key)),
+                }
+            }
+            if !problems.is_empty() {
+                return Err(ConfigError::Invalid(problems));
+            }
+        }
+
+        for setting in SETTINGS {
+            if let Some(value) = env(setting.env) {
+                values.insert(setting.key, (value, Source::Env(setting.env)));
+            }
+        }
+
+        for (key, value) in &args.overrides {
+            values.insert(*key, (value.clone(), Source::Args));
+        }
+
+        Ok(Settings { values })
     }
+
+    fn raw(&self, key: &str) -> Option<&str> {
+        self.values
+            .get(key)
+            .map(|(value, _)| value.as_str())
+            .filter(|value| !value.is_empty())
+    }
+}
+
+/// Flattens tables in the config file into settings, so `[db] addr = ".."` sets `db_addr`.
+/// Arrays become comma-separated lists.
+fn flatten(
+    prefix: &str,
+    value: &toml::Value,
+    out: &mut Vec<(String, String)>,
+    problems: &mut Vec<String>,
+) {
+    use toml::Value;
+    let scalar = |value: &Value| match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Integer(i) => Some(i.to_string()),
+        Value::Float(f) => Some(f.to_string()),
+        Value::Boolean(b) => Some(b.to_string()),
+        _ => None,
+    };
+    match value {
+        Value::Table(table) => {
+            for (key, value) in table {
+                let key = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}_{}", prefix, key)
+                };
+                flatten(&key, value, out, problems);
+            }
+        }
+        Value::Array(items) => match items.iter().map(scalar).collect::<Option<Vec<_>>>() {
+            Some(items) => out.push((prefix.to_string(), items.join(","))),
+            None => problems.push(format!("{}: expected a list of plain values", prefix)),
+        },
+        value => match scalar(value) {
+            Some(value) => out.push((prefix.to_string(), value)),
+            None => problems.push(format!("{}: unsupported value {}", prefix, value)),
+        },
+    }
+}
+
+/// Prints the settings as a config file, noting where each came from.
+impl Display for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# Effective configuration, secrets are redacted")?;
+        for setting in SETTINGS {
+            let (value, source) = match self.values.get(setting.key) {
+                Some(entry) => entry,
+                None => {
+                    writeln!(f, "# {} is not set", setting.key)?;
+                    continue;
+                }
+            };
+            let value = if setting.secret {
+                toml::Value::String(REDACTED.to_string())
+            } else if let Ok(n) = value.parse::<i64>() {
+                toml::Value::Integer(n)
+            } else if let Ok(b) = value.parse::<bool>() {
+                toml::Value::Boolean(b)
+            } else {
+                toml::Value::String(value.clone())
+            };
+            writeln!(f, "{} = {}  # {}", setting.key, value, source)?;
+        }
+        Ok(())
+    }
+}
+
+/// Turns settings into typed values, noting every problem rather than stopping at the first.
+struct Checker<'a> {
+    settings: &'a Settings,
+    problems: Vec<String>,
+}
+
+impl Checker<'_> {
+    /// Parses a setting, or notes what was expected and returns `None`.
+    fn parse<T>(
+        &mut self,
+        key: &str,
+        expected: &str,
+        parse: impl Fn(&str) -> Option<T>,
+    ) -> Option<T> {
+        let parsed = self.settings.raw(key).and_then(|value| parse(value));
+        if parsed.is_none() {
+            let problem = match self.settings.values.get(key) {
+                Some((value, source)) if !value.is_empty() => format!(
+                    "{} = {:?} (from {}): expected {}",
+                    key, value, source, expected
+                ),
+                _ => format!("{} is not set: expected {}", key, expected),
+            };
+            self.problems.push(problem);
+        }
+        parsed
+    }
+
+    fn get<T: FromStr + Default>(&mut self, key: &str, expected: &str) -> T {
+        self.parse(key, expected, |value| value.parse().ok())
+            .unwrap_or_default()
+    }
+
+    fn positive<T: FromStr + Default + PartialOrd>(&mut self, key: &str) -> T {
+        self.parse(key, "a positive number", |value| {
+            value.parse().ok().filter(|n| *n > T::default())
+        })
+        .unwrap_or_default()
+    }
+
+    fn seconds(&mut self, key: &str) -> Duration {
+        Duration::from_secs(self.get(key, "a number of seconds"))
+    }
+
+    fn megabytes(&mut self, key: &str) -> u64 {
+        self.get::<u64>(key, "a number of megabytes") * 1024 * 1024
+    }
+
+    fn string(&mut self, key: &str) -> String {
+        self.settings.raw(key).unwrap_or_default().to_string()
+    }
+
+    fn required(&mut self, key: &str, expected: &str) -> String {
+        self.parse(key, expected, |value| Some(value.to_string()))
+            .unwrap_or_default()
+    }
+
+    /// One of a fixed set of options, with the first being used if the setting is bad.
+    fn choice<T: Copy>(&mut self, key: &str, options: &[(&str, T)]) -> T {
+        let names: Vec<String> = options
+            .iter()
+            .map(|(name, _)| format!("{:?}", name))
+            .collect();
+        let expected = format!("one of {}", names.join(", "));
+        self.parse(key, &expected, |value| {
+            options
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(value))
+                .map(|(_, option)| *option)
+        })
+        .unwrap_or(options[0].1)
+    }
+}
+
+fn random_pepper() -> String {
+    let bytes =
+        argonautica::utils::generate_random_bytes(32).expect("CONFIG: Failed to generate pepper");
+    let mut s = String::new();
+    for byte in bytes {
+        write!(&mut s, "{:X}", byte).unwrap();
+    }
+    s
+}
+
+impl Config {
+    /// Checks every setting, failing with all the problems found.
+    pub fn from_settings(settings: &Settings) -> Result<Self, ConfigError> {
+        let mut check = Checker {
+            settings,
+            problems: Vec::new(),
+        };
+
+        let pepper = settings
+            .raw("pepper")
+            .map(str::to_string)
+            .unwrap_or_else(|| {
+                // Logging isn't set up yet
+                eprintln!("CONFIG: warning: generating random pepper");
+                random_pepper()
+            });
+        let outbound = OutboundConfig {
+            buffer: check.positive("client_buffer"),
+            coalesce: check.get("client_coalesce", "true or false"),
+            overflow: check.choice(
+                "client_overflow",
+                &[
+                    ("resync", OverflowPolicy::Resync),
+                    ("disconnect", OverflowPolicy::Disconnect),
+                ],
+            ),
+            stall_timeout: check.seconds("client_stall_timeout"),
+        };
+        let database = if check.choice("database", &[("postgres", false), ("sqlite", true)]) {
+            DatabaseConfig::Sqlite {
+                path: check.required("sqlite_path", "a file path"),
+            }
+        } else {
+            DatabaseConfig::Postgres
+        };
+        let http = HttpConfig {
+            address: check
+                .parse("http_address", "an IP address", |value| value.parse().ok())
+                .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            port: check.get("http_port", "a port number"),
+            workers: check.positive("http_workers"),
+            keep_alive: check.get("http_keep_alive", "a number of seconds"),
+        };
+        let listen_addr = check
+            .parse("websocket_addr", "an address like 0.0.0.0:9000", |value| {
+                value.parse::<SocketAddr>().ok().map(|_| value.to_string())
+            })
+            .unwrap_or_default();
+        let storage = if check.choice("storage", &[("local", false), ("s3", true)]) {
+            StorageConfig::S3 {
+                endpoint: check.required("s3_endpoint", "a URL when storage is \"s3\""),
+                region: check.string("s3_region"),
+                bucket: check.required("s3_bucket", "a bucket name"),
+                access_key: check.required("s3_access_key", "a key when storage is \"s3\""),
+                secret_key: check.required("s3_secret_key", "a key when storage is \"s3\""),
+                presign_secs: check.get("s3_presign_secs", "a number of seconds"),
+            }
+        } else {
+            StorageConfig::Local
+        };
+        let bus = if check.choice("bus", &[("local", false), ("redis", true)]) {
+            BusConfig::Redis {
+                addr: check.required("redis_addr", "an address like 127.0.0.1:6379"),
+            }
+        } else {
+            BusConfig::Local
+        };
+        let admins = check
+            .string("admins")
+            .split(',')
+            .map(|email| email.trim().to_string())
+            .filter(|email| !email.is_empty())
+            .collect();
+
+        let config = Config {
+            user_token_timeout: check.seconds("session_timeout"),
+            game_timeout: check.seconds("game_timeout"),
+            monitor_interval: check.seconds("monitor_interval"),
+            outbound,
+            pepper,
+            mode: check.choice(
+                "mode",
+                &[("release", RunMode::Release), ("debug", RunMode::Debug)],
+            ),
+            log_level: check
+                .parse(
+                    "log_level",
+                    "one of off, error, warn, info, debug, trace",
+                    |value| value.parse().ok(),
+                )
+                .unwrap_or(LevelFilter::INFO),
+            log_format: check.choice(
+                "log_format",
+                &[("text", LogFormat::Text), ("json", LogFormat::Json)],
+            ),
+            database,
+            db_addr: check.string("db_addr"),
+            db_user: check.string("db_user"),
+            db_password: check.string("db_password"),
+            db_name: check.required("db_name", "a database name"),
+            db_pool_size: check.positive("db_pool_size"),
+            db_reset: check.get("db_reset", "true or false"),
+            http,
+            listen_addr,
+            upload_dir: check.required("upload_path", "a directory"),
+            storage,
+            bus,
+            admins,
+            max_upload_mb: check.megabytes("max_upload_mb"),
+            user_quota_mb: check.megabytes("user_quota_mb"),
+            max_image_dim: check.positive("max_image_dim"),
+        };
+
+        if check.problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError::Invalid(check.problems))
+        }
+    }
+
+    /// Loads the config without any command-line settings.
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::from_settings(&Settings::gather(&Args::default())?)
+    }
+}
+
+/// Makes `CONFIG` use a config `main` has loaded, e.g. with command-line settings. Has to be
+/// called before anything reads `CONFIG`.
+pub fn install(config: Config) {
+    *INSTALLED.lock().unwrap() = Some(config);
+}
+
+fn init_config() -> Config {
+    let installed = INSTALLED.lock().unwrap().take();
+    let config = installed.unwrap_or_else(|| match Config::load() {
+        Ok(config) => config,
+        Err(e) => panic!("CONFIG: {}", e),
+    });
+    logging::init(config.log_level, config.log_format);
+    config
 }
 
 lazy_static! {
-    pub static ref CONFIG: Config = load_config();
+    static ref INSTALLED: Mutex<Option<Config>> = Mutex::new(None);
+    pub static ref CONFIG: Config = init_config();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Args, Config, ConfigError, Settings, StorageConfig};
+
+    fn args(args: &[&str]) -> Args {
+        Args::parse(args.iter().map(|arg| arg.to_string())).unwrap()
+    }
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn test_args() {
+        let parsed = args(&["-c", "prod.toml", "--print-config", "--http-port", "9000"]);
+        assert_eq!(parsed.config_file.as_deref(), Some("prod.toml"));
+        assert!(parsed.print_config);
+        assert_eq!(parsed.overrides, vec![("http_port", "9000".to_string())]);
+
+        let parsed = args(&["--db_name=test", "--config=x.toml"]);
+        assert_eq!(parsed.overrides, vec![("db_name", "test".to_string())]);
+        assert_eq!(parsed.config_file.as_deref(), Some("x.toml"));
+
+        let parse = |args: &[&str]| Args::parse(args.iter().map(|arg| arg.to_string()));
+        assert!(matches!(
+            parse(&["--no-such-thing", "1"]),
+            Err(ConfigError::Args(_))
+        ));
+        assert!(matches!(parse(&["--http-port"]), Err(ConfigError::Args(_))));
+        assert!(matches!(parse(&["serve"]), Err(ConfigError::Args(_))));
+    }
+
+    #[test]
+    fn test_layers() {
+        let file = "
+            session_timeout = 60
+            admins = [\"a@example.com\", \"b@example.com\"]
+
+            [http]
+            port = 8080
+            address = \"127.0.0.1\"
+        ";
+        let env = |var: &str| match var {
+            "RC_HTTP_PORT" => Some("8081".to_string()),
+            "RC_GAME_TIMEOUT" => Some("120".to_string()),
+            _ => None,
+        };
+        let settings = Settings::layer(
+            Some(("test.toml", file)),
+            env,
+            &args(&["--http-port", "8082"]),
+        )
+        .unwrap();
+        let config = Config::from_settings(&settings).unwrap();
+        assert_eq!(config.user_token_timeout.as_secs(), 60);
+        assert_eq!(config.game_timeout.as_secs(), 120);
+        assert_eq!(config.http.port, 8082);
+        assert_eq!(config.http.address.to_string(), "127.0.0.1");
+        assert_eq!(config.admins, vec!["a@example.com", "b@example.com"]);
+        // Untouched settings keep their defaults
+        assert_eq!(config.monitor_interval.as_secs(), 300);
+        assert_eq!(config.db_pool_size, 16);
+        assert!(!config.db_reset);
+
+        let printed = settings.to_string();
+        assert!(printed.contains("http_port = 8082  # command line"));
+        assert!(printed.contains("game_timeout = 120  # RC_GAME_TIMEOUT"));
+        assert!(printed.contains("session_timeout = 60  # test.toml"));
+        assert!(printed.contains("# pepper is not set"));
+        assert!(printed.contains("db_password = \"<redacted>\""));
+        assert!(!printed.contains("\"password\""));
+    }
+
+    #[test]
+    fn test_example_file() {
+        let example = include_str!("../rolecall.example.toml");
+        let settings =
+            Settings::layer(Some(("rolecall.example.toml", example)), no_env, &args(&[])).unwrap();
+        let config = Config::from_settings(&settings).unwrap();
+        assert_eq!(config.http.port, 8000);
+        assert_eq!(config.listen_addr, "0.0.0.0:9000");
+        assert!(config.admins.is_empty());
+    }
+
+    #[test]
+    fn test_validation() {
+        let file = "
+            session_timeout = \"soon\"
+            storage = \"s3\"
+            [db]
+            pool_size = 0
+        ";
+        let settings = Settings::layer(Some(("test.toml", file)), no_env, &args(&[])).unwrap();
+        let problems = match Config::from_settings(&settings) {
+            Err(ConfigError::Invalid(problems)) => problems,
+            _ => panic!("expected the config to be invalid"),
+        };
+        let all = problems.join("\n");
+        assert!(all.contains("session_timeout = \"soon\" (from test.toml)"));
+        assert!(all.contains("db_pool_size = \"0\""));
+        assert!(all.contains("s3_endpoint is not set"));
+        assert!(all.contains("s3_access_key is not set"));
+
+        let settings = Settings::layer(None, no_env, &args(&["--mode", "fast"])).unwrap();
+        assert!(Config::from_settings(&settings).is_err());
+
+        let settings = Settings::layer(Some(("test.toml", "colour = 1")), no_env, &args(&[]));
+        assert!(matches!(settings, Err(ConfigError::Invalid(_))));
+        let settings = Settings::layer(Some(("test.toml", "port = ")), no_env, &args(&[]));
+        assert!(matches!(settings, Err(ConfigError::File { .. })));
+
+        let env = |var: &str| match var {
+            "RC_STORAGE" => Some("s3".to_string()),
+            "RC_S3_ENDPOINT" => Some("http://localhost:9100".to_string()),
+            "RC_S3_ACCESS_KEY" => Some("key".to_string()),
+            "RC_S3_SECRET_KEY" => Some("secret".to_string()),
+            _ => None,
+        };
+        let settings = Settings::layer(None, env, &args(&[])).unwrap();
+        let config = Config::from_settings(&settings).unwrap();
+        assert!(matches!(config.storage, StorageConfig::S3 { .. }));
+    }
 }
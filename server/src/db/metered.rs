@@ -0,0 +1,287 @@
+use super::*;
+use crate::metrics::time_query;
+
+/// Wraps another database, recording how long each operation takes.
+pub struct MeteredDb {
+    db: Arc<dyn Database>,
+}
+
+impl MeteredDb {
+    pub fn new(db: Arc<dyn Database>) -> Self {
+        Self { db }
+    }
+}
+
+#[rocket::async_trait]
+impl Database for MeteredDb {
+    fn storage(&self) -> &dyn Storage {
+        self.db.storage()
+    }
+
+    async fn clear_tables(&self) -> Result<(), DbError> {
+        time_query("clear_tables", self.db.clear_tables()).await
+    }
+
+    async fn create_tables(&self) -> Result<(), DbError> {
+        time_query("create_tables", self.db.create_tables()).await
+    }
+
+    async fn ping(&self) -> Result<(), DbError> {
+        time_query("ping", self.db.ping()).await
+    }
+
+    async fn create_user(
+        &self,
+        email: &str,
+        password: &str,
+        nickname: &str,
+    ) -> Result<String, DbError> {
+        time_query(
+            "create_user",
+            self.db.create_user(email, password, nickname),
+        )
+        .await
+    }
+
+    async fn confirm_user(&self, email: &str, token: &str) -> Result<String, DbError> {
+        time_query("confirm_user", self.db.confirm_user(email, token)).await
+    }
+
+    async fn auth_user(&self, email: &str, password: &str) -> Result<(String, String), DbError> {
+        time_query("auth_user", self.db.auth_user(email, password)).await
+    }
+
+    async fn get_account(&self, token: &str) -> Result<(UserId, String), DbError> {
+        time_query("get_account", self.db.get_account(token)).await
+    }
+
+    async fn check_token(&self, user_token: &str) -> Result<bool, DbError> {
+        time_query("check_token", self.db.check_token(user_token)).await
+    }
+
+    async fn get_profile(&self, user_token: &str) -> Result<Profile, DbError> {
+        time_query("get_profile", self.db.get_profile(user_token)).await
+    }
+
+    async fn set_profile(
+        &self,
+        user_token: &str,
+        settings: &ProfileSettings,
+    ) -> Result<(), DbError> {
+        time_query("set_profile", self.db.set_profile(user_token, settings)).await
+    }
+
+    async fn set_avatar(&self, user_token: &str, file: &StoredFile) -> Result<(), DbError> {
+        time_query("set_avatar", self.db.set_avatar(user_token, file)).await
+    }
+
+    async fn set_nickname(
+        &self,
+        user_token: &str,
+        nickname: &str,
+    ) -> Result<(UserId, String), DbError> {
+        time_query("set_nickname", self.db.set_nickname(user_token, nickname)).await
+    }
+
+    async fn create_game(&self, user_token: &str, name: &str) -> Result<String, DbError> {
+        time_query("create_game", self.db.create_game(user_token, name)).await
+    }
+
+    async fn join_game(&self, user_token: &str, game_token: &str) -> Result<(), DbError> {
+        time_query("join_game", self.db.join_game(user_token, game_token)).await
+    }
+
+    async fn get_hosted_games(&self, user_token: &str) -> Result<Vec<Game>, DbError> {
+        time_query("get_hosted_games", self.db.get_hosted_games(user_token)).await
+    }
+
+    async fn get_joined_games(&self, user_token: &str) -> Result<Vec<Game>, DbError> {
+        time_query("get_joined_games", self.db.get_joined_games(user_token)).await
+    }
+
+    async fn check_game_permissions(
+        &self,
+        user_token: &str,
+        game_token: &str,
+    ) -> Result<GamePermission, DbError> {
+        time_query(
+            "check_game_permissions",
+            self.db.check_game_permissions(user_token, game_token),
+        )
+        .await
+    }
+
+    async fn save_game_state(&self, game_token: &str, state: &str) -> Result<(), DbError> {
+        time_query(
+            "save_game_state",
+            self.db.save_game_state(game_token, state),
+        )
+        .await
+    }
+
+    async fn load_game_state(&self, game_token: &str) -> Result<Option<String>, DbError> {
+        time_query("load_game_state", self.db.load_game_state(game_token)).await
+    }
+
+    async fn update_game_states(
+        &self,
+        containing: &str,
+        update: &(dyn Fn(&str) -> Option<String> + Send + Sync),
+    ) -> Result<usize, DbError> {
+        time_query(
+            "update_game_states",
+            self.db.update_game_states(containing, update),
+        )
+        .await
+    }
+
+    async fn get_usage(&self, user_token: &str) -> Result<(u64, u64), DbError> {
+        time_query("get_usage", self.db.get_usage(user_token)).await
+    }
+
+    async fn create_obj(
+        &self,
+        user_token: &str,
+        name: &str,
+        file: &StoredFile,
+    ) -> Result<(), DbError> {
+        time_query("create_obj", self.db.create_obj(user_token, name, file)).await
+    }
+
+    async fn rename_obj(
+        &self,
+        user_token: &str,
+        name: &str,
+        new_name: &str,
+    ) -> Result<(), DbError> {
+        time_query("rename_obj", self.db.rename_obj(user_token, name, new_name)).await
+    }
+
+    async fn replace_obj(
+        &self,
+        user_token: &str,
+        name: &str,
+        file: &StoredFile,
+    ) -> Result<(), DbError> {
+        time_query("replace_obj", self.db.replace_obj(user_token, name, file)).await
+    }
+
+    async fn get_owned_objs(
+        &self,
+        user_token: &str,
+        filter: &ObjFilter,
+    ) -> Result<Vec<Object>, DbError> {
+        time_query("get_owned_objs", self.db.get_owned_objs(user_token, filter)).await
+    }
+
+    async fn get_obj(&self, user_token: &str, name: &str) -> Result<String, DbError> {
+        time_query("get_obj", self.db.get_obj(user_token, name)).await
+    }
+
+    async fn delete_obj(&self, user_token: &str, name: &str) -> Result<i32, DbError> {
+        time_query("delete_obj", self.db.delete_obj(user_token, name)).await
+    }
+
+    async fn get_other_objs(
+        &self,
+        user_token: &str,
+        other_id: i32,
+    ) -> Result<Vec<Object>, DbError> {
+        time_query(
+            "get_other_objs",
+            self.db.get_other_objs(user_token, other_id),
+        )
+        .await
+    }
+
+    async fn create_folder(&self, user_token: &str, name: &str) -> Result<i32, DbError> {
+        time_query("create_folder", self.db.create_folder(user_token, name)).await
+    }
+
+    async fn get_folders(&self, user_token: &str) -> Result<Vec<Folder>, DbError> {
+        time_query("get_folders", self.db.get_folders(user_token)).await
+    }
+
+    async fn move_obj(
+        &self,
+        user_token: &str,
+        name: &str,
+        folder: Option<i32>,
+    ) -> Result<(), DbError> {
+        time_query("move_obj", self.db.move_obj(user_token, name, folder)).await
+    }
+
+    async fn set_tags(&self, user_token: &str, name: &str, tags: &[String]) -> Result<(), DbError> {
+        time_query("set_tags", self.db.set_tags(user_token, name, tags)).await
+    }
+
+    async fn share(
+        &self,
+        user_token: &str,
+        item: &ShareItem,
+        with: &ShareWith,
+    ) -> Result<(), DbError> {
+        time_query("share", self.db.share(user_token, item, with)).await
+    }
+
+    async fn unshare(
+        &self,
+        user_token: &str,
+        item: &ShareItem,
+        with: &ShareWith,
+    ) -> Result<(), DbError> {
+        time_query("unshare", self.db.unshare(user_token, item, with)).await
+    }
+
+    async fn get_shared_objs(&self, user_token: &str) -> Result<Vec<Object>, DbError> {
+        time_query("get_shared_objs", self.db.get_shared_objs(user_token)).await
+    }
+
+    async fn check_admin(&self, user_token: &str) -> Result<UserId, DbError> {
+        time_query("check_admin", self.db.check_admin(user_token)).await
+    }
+
+    async fn set_admin(&self, email: &str, admin: bool) -> Result<(), DbError> {
+        time_query("set_admin", self.db.set_admin(email, admin)).await
+    }
+
+    async fn list_users(&self, search: Option<&str>) -> Result<Vec<Account>, DbError> {
+        time_query("list_users", self.db.list_users(search)).await
+    }
+
+    async fn set_disabled(&self, user_id: UserId, disabled: bool) -> Result<(), DbError> {
+        time_query("set_disabled", self.db.set_disabled(user_id, disabled)).await
+    }
+
+    async fn list_games(&self) -> Result<Vec<GameDetails>, DbError> {
+        time_query("list_games", self.db.list_games()).await
+    }
+
+    async fn get_file_keys(&self) -> Result<Vec<String>, DbError> {
+        time_query("get_file_keys", self.db.get_file_keys()).await
+    }
+
+    async fn get_unsized_files(&self) -> Result<Vec<StoredFile>, DbError> {
+        time_query("get_unsized_files", self.db.get_unsized_files()).await
+    }
+
+    async fn set_file_details(&self, file: &StoredFile) -> Result<(), DbError> {
+        time_query("set_file_details", self.db.set_file_details(file)).await
+    }
+
+    async fn set_password(&self, email: &str, password: &str) -> Result<(), DbError> {
+        time_query("set_password", self.db.set_password(email, password)).await
+    }
+
+    async fn get_game_members(&self, game_token: &str) -> Result<Vec<Account>, DbError> {
+        time_query("get_game_members", self.db.get_game_members(game_token)).await
+    }
+
+    async fn export_game(&self, game_token: &str) -> Result<GameExport, DbError> {
+        time_query("export_game", self.db.export_game(game_token)).await
+    }
+
+    async fn import_game(&self, game: &GameExport) -> Result<String, DbError> {
+        time_query("import_game", self.db.import_game(game)).await
+    }
+}
@@ -0,0 +1,1276 @@
+use crate::config::*;
+use crate::storage::{self, MemoryStorage, Storage, StorageError};
+use crate::upload::{self, thumbnail_key, StoredFile, THUMBNAIL_SIZES};
+use argonautica::{Hasher, Verifier};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter, Write};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+mod metered;
+mod postgres;
+mod sqlite;
+
+pub use metered::MeteredDb;
+pub use postgres::PostgresDb;
+pub use sqlite::SqliteDb;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Game {
+    token: String,
+    name: String,
+}
+
+/// A game as admins see it, with its host's username and how many players have joined.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GameDetails {
+    pub token: String,
+    pub name: String,
+    pub host: String,
+    pub players: i64,
+}
+
+/// A user account as admins see it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Account {
+    pub id: UserId,
+    pub email: String,
+    pub username: String,
+    pub admin: bool,
+    pub disabled: bool,
+}
+
+/// A game as exported to move it to another server. People are matched up by email, and the
+/// objects the game shows aren't included.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GameExport {
+    pub name: String,
+    /// Email of the host.
+    pub host: String,
+    /// Emails of everyone else in the game.
+    pub players: Vec<String>,
+    pub state: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Object {
+    id: i32,
+    name: String,
+    url: String,
+    width: i32,
+    height: i32,
+    thumbnails: Vec<Thumbnail>,
+    folder: Option<i32>,
+    tags: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Folder {
+    id: i32,
+    name: String,
+}
+
+/// Something an owner can share: a single object (by name) or a whole folder.
+#[derive(Clone, Debug)]
+pub enum ShareItem {
+    Object(String),
+    Folder(i32),
+}
+
+/// Who an item is shared with: a single user (by `nickname#tag`), or everyone in a game.
+#[derive(Clone, Debug)]
+pub enum ShareWith {
+    User(String),
+    Game(String),
+}
+
+/// Restricts which of a user's objects are listed. Empty fields don't filter anything.
+#[derive(Clone, Debug, Default)]
+pub struct ObjFilter {
+    pub folder: Option<i32>,
+    pub tag: Option<String>,
+    pub search: Option<String>,
+}
+
+/// Profile settings a user can change directly. The avatar is uploaded separately.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProfileSettings {
+    /// Default colour for tokens the user places, as `#rrggbb`.
+    pub colour: Option<String>,
+    pub pronouns: Option<String>,
+    /// IANA time zone name, e.g. `Australia/Melbourne`.
+    pub timezone: Option<String>,
+    /// Client preferences, stored as-is.
+    #[serde(default)]
+    pub preferences: serde_json::Value,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub username: String,
+    /// URL of the avatar image.
+    pub avatar: Option<String>,
+    #[serde(flatten)]
+    pub settings: ProfileSettings,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Thumbnail {
+    size: u32,
+    url: String,
+}
+
+/// Builds the thumbnail list for a stored file, with URLs from the storage backend.
+fn thumbnails(storage: &dyn Storage, key: &str) -> Vec<Thumbnail> {
+    THUMBNAIL_SIZES
+        .iter()
+        .map(|&size| Thumbnail {
+            size,
+            url: storage.url(&thumbnail_key(key, size)),
+        })
+        .collect()
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GamePermission {
+    Host,
+    Player,
+    None,
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    Rocket(rocket::error::Error),
+    Sql(tokio_postgres::Error),
+    Sqlite(rusqlite::Error),
+    Pool(deadpool_postgres::PoolError),
+    PoolBuild(deadpool_postgres::BuildError),
+    Hash(argonautica::Error),
+    Time(std::time::SystemTimeError),
+    Storage(StorageError),
+    Config(std::env::VarError),
+    ConfigParse,
+    Auth,
+    /// The user is signed in, but isn't an admin.
+    Forbidden,
+    AlreadyExists,
+    QuotaExceeded,
+    Parse,
+    /// A username that isn't of the form `nickname#tag`.
+    InvalidUsername,
+}
+
+impl From<rocket::error::Error> for DbError {
+    fn from(e: rocket::error::Error) -> Self {
+        Self::Rocket(e)
+    }
+}
+
+impl From<tokio_postgres::Error> for DbError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        Self::Sql(e)
+    }
+}
+
+impl From<rusqlite::Error> for DbError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Sqlite(e)
+    }
+}
+
+impl From<deadpool_postgres::PoolError> for DbError {
+    fn from(e: deadpool_postgres::PoolError) -> Self {
+        Self::Pool(e)
+    }
+}
+
+impl From<deadpool_postgres::BuildError> for DbError {
+    fn from(e: deadpool_postgres::BuildError) -> Self {
+        Self::PoolBuild(e)
+    }
+}
+
+impl From<argonautica::Error> for DbError {
+    fn from(e: argonautica::Error) -> Self {
+        Self::Hash(e)
+    }
+}
+
+impl From<std::time::SystemTimeError> for DbError {
+    fn from(e: std::time::SystemTimeError) -> Self {
+        Self::Time(e)
+    }
+}
+
+impl From<StorageError> for DbError {
+    fn from(e: StorageError) -> Self {
+        Self::Storage(e)
+    }
+}
+
+impl From<std::env::VarError> for DbError {
+    fn from(e: std::env::VarError) -> Self {
+        Self::Config(e)
+    }
+}
+
+impl From<std::num::ParseIntError> for DbError {
+    fn from(_: std::num::ParseIntError) -> Self {
+        Self::ConfigParse
+    }
+}
+
+impl Display for DbError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for DbError {}
+
+pub type UserId = i32;
+pub type Timestamp = i64;
+pub type GameId = i32;
+
+/// Number of distinct tags, i.e. how many users can share a nickname.
+const USER_TAGS: u32 = 10000;
+
+/// Everything RoleCall keeps in its database. Implemented for Postgres, and for SQLite so small
+/// installs don't need a database server.
+#[rocket::async_trait]
+pub trait Database: Send + Sync {
+    fn storage(&self) -> &dyn Storage;
+
+    async fn clear_tables(&self) -> Result<(), DbError>;
+    /// Creates any missing tables and brings existing ones up to the current schema.
+    async fn create_tables(&self) -> Result<(), DbError>;
+    /// Checks the database can be reached.
+    async fn ping(&self) -> Result<(), DbError>;
+
+    async fn create_user(
+        &self,
+        email: &str,
+        password: &str,
+        nickname: &str,
+    ) -> Result<String, DbError>;
+    async fn confirm_user(&self, email: &str, token: &str) -> Result<String, DbError>;
+    async fn auth_user(&self, email: &str, password: &str) -> Result<(String, String), DbError>;
+    async fn get_account(&self, token: &str) -> Result<(UserId, String), DbError>;
+    async fn check_token(&self, user_token: &str) -> Result<bool, DbError>;
+    async fn get_profile(&self, user_token: &str) -> Result<Profile, DbError>;
+    async fn set_profile(
+        &self,
+        user_token: &str,
+        settings: &ProfileSettings,
+    ) -> Result<(), DbError>;
+    /// Replaces the user's avatar, releasing the old image. Avatars count towards the user's
+    /// storage, so this fails with `QuotaExceeded` like `create_obj`.
+    async fn set_avatar(&self, user_token: &str, file: &StoredFile) -> Result<(), DbError>;
+    /// Changes a user's nickname, giving them a new tag. Returns their id and new username.
+    async fn set_nickname(
+        &self,
+        user_token: &str,
+        nickname: &str,
+    ) -> Result<(UserId, String), DbError>;
+
+    async fn create_game(&self, user_token: &str, name: &str) -> Result<String, DbError>;
+    async fn join_game(&self, user_token: &str, game_token: &str) -> Result<(), DbError>;
+    async fn get_hosted_games(&self, user_token: &str) -> Result<Vec<Game>, DbError>;
+    async fn get_joined_games(&self, user_token: &str) -> Result<Vec<Game>, DbError>;
+    async fn check_game_permissions(
+        &self,
+        user_token: &str,
+        game_token: &str,
+    ) -> Result<GamePermission, DbError>;
+    /// Stores the state of a running game, as saved by its server, replacing any earlier state.
+    async fn save_game_state(&self, game_token: &str, state: &str) -> Result<(), DbError>;
+    async fn load_game_state(&self, game_token: &str) -> Result<Option<String>, DbError>;
+    /// Rewrites saved game states that contain `containing`, so changes reach games that aren't
+    /// running. `update` returns the new state, or `None` to leave one as it is. Returns how many
+    /// states were changed.
+    async fn update_game_states(
+        &self,
+        containing: &str,
+        update: &(dyn Fn(&str) -> Option<String> + Send + Sync),
+    ) -> Result<usize, DbError>;
+
+    /// Returns the number of bytes used by the user's objects and avatar, and their quota.
+    async fn get_usage(&self, user_token: &str) -> Result<(u64, u64), DbError>;
+
+    /// Fails with `QuotaExceeded` if the new file takes the user over their quota.
+    async fn create_obj(
+        &self,
+        user_token: &str,
+        name: &str,
+        file: &StoredFile,
+    ) -> Result<(), DbError>;
+    async fn rename_obj(&self, user_token: &str, name: &str, new_name: &str)
+        -> Result<(), DbError>;
+    /// Points an existing object at a new file, keeping its id so placed copies of it in games
+    /// show the new image. Fails with `QuotaExceeded` like `create_obj`.
+    async fn replace_obj(
+        &self,
+        user_token: &str,
+        name: &str,
+        file: &StoredFile,
+    ) -> Result<(), DbError>;
+    async fn get_owned_objs(
+        &self,
+        user_token: &str,
+        filter: &ObjFilter,
+    ) -> Result<Vec<Object>, DbError>;
+    async fn get_obj(&self, user_token: &str, name: &str) -> Result<String, DbError>;
+    /// Deletes an object, returning its id so that copies placed in games can be cleaned up.
+    async fn delete_obj(&self, user_token: &str, name: &str) -> Result<i32, DbError>;
+    async fn get_other_objs(&self, user_token: &str, other_id: i32)
+        -> Result<Vec<Object>, DbError>;
+
+    async fn create_folder(&self, user_token: &str, name: &str) -> Result<i32, DbError>;
+    async fn get_folders(&self, user_token: &str) -> Result<Vec<Folder>, DbError>;
+    /// Moves an object into one of the user's folders, or out of any folder if `folder` is None.
+    async fn move_obj(
+        &self,
+        user_token: &str,
+        name: &str,
+        folder: Option<i32>,
+    ) -> Result<(), DbError>;
+    /// Replaces the tags on an object.
+    async fn set_tags(&self, user_token: &str, name: &str, tags: &[String]) -> Result<(), DbError>;
+
+    /// Gives read-only access to an object or folder to another user or to a game's members.
+    async fn share(
+        &self,
+        user_token: &str,
+        item: &ShareItem,
+        with: &ShareWith,
+    ) -> Result<(), DbError>;
+    async fn unshare(
+        &self,
+        user_token: &str,
+        item: &ShareItem,
+        with: &ShareWith,
+    ) -> Result<(), DbError>;
+    /// Lists objects other users have shared with this user, directly or through a game.
+    async fn get_shared_objs(&self, user_token: &str) -> Result<Vec<Object>, DbError>;
+
+    /// Checks the user is an admin, returning their id.
+    async fn check_admin(&self, user_token: &str) -> Result<UserId, DbError>;
+    async fn set_admin(&self, email: &str, admin: bool) -> Result<(), DbError>;
+    /// Lists accounts whose email or username contains `search`, or every account.
+    async fn list_users(&self, search: Option<&str>) -> Result<Vec<Account>, DbError>;
+    /// Disabled users can't sign in, and their existing sessions stop working.
+    async fn set_disabled(&self, user_id: UserId, disabled: bool) -> Result<(), DbError>;
+    async fn list_games(&self) -> Result<Vec<GameDetails>, DbError>;
+    /// Storage keys of every file in the database, not including thumbnails.
+    async fn get_file_keys(&self) -> Result<Vec<String>, DbError>;
+    /// Files carried over from before uploads were processed, which have no size recorded.
+    async fn get_unsized_files(&self) -> Result<Vec<StoredFile>, DbError>;
+    /// Records a file's dimensions and size.
+    async fn set_file_details(&self, file: &StoredFile) -> Result<(), DbError>;
+    /// Changes a user's password, which also ends their sessions.
+    async fn set_password(&self, email: &str, password: &str) -> Result<(), DbError>;
+    async fn get_game_members(&self, game_token: &str) -> Result<Vec<Account>, DbError>;
+    async fn export_game(&self, game_token: &str) -> Result<GameExport, DbError>;
+    /// Recreates an exported game, returning its new token. Players without an account here are
+    /// left out.
+    async fn import_game(&self, game: &GameExport) -> Result<String, DbError>;
+}
+
+/// Connects to the database backend selected in the config, timing everything it does.
+pub fn create_database() -> Result<Arc<dyn Database>, DbError> {
+    let storage = storage::create_storage()?;
+    let db: Arc<dyn Database> = match &CONFIG.database {
+        DatabaseConfig::Postgres => Arc::new(PostgresDb::new(storage)?),
+        DatabaseConfig::Sqlite { path } => Arc::new(SqliteDb::new(path, storage)?),
+    };
+    Ok(Arc::new(MeteredDb::new(db)))
+}
+
+/// Creates an empty database that lives entirely in memory, files included. Every call gets its
+/// own copy, so tests using it can run in parallel.
+pub async fn create_memory_database() -> Result<Arc<dyn Database>, DbError> {
+    let db = SqliteDb::new(":memory:", Arc::new(MemoryStorage::default()))?;
+    db.create_tables().await?;
+    Ok(Arc::new(db))
+}
+
+fn timestamp() -> Result<Timestamp, DbError> {
+    Ok(SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_millis() as Timestamp)
+}
+
+fn create_user_token() -> Result<(String, Timestamp), DbError> {
+    let token = argonautica::utils::generate_random_base64_encoded_string(32)?;
+    let timestamp = timestamp()?;
+    // Add 1 day to the timeout
+    Ok((
+        token,
+        timestamp + CONFIG.user_token_timeout.as_millis() as Timestamp,
+    ))
+}
+
+/// Picks a tag for a nickname that isn't already taken. The search starts from a random tag, so
+/// usernames can't be guessed, and takes the next free one from there.
+fn allocate_user_tag(used: &HashSet<String>) -> Result<String, DbError> {
+    let start = rand::thread_rng().gen_range(0, USER_TAGS);
+    (0..USER_TAGS)
+        .map(|i| format!("{:04}", (start + i) % USER_TAGS))
+        .find(|tag| !used.contains(tag))
+        .ok_or(DbError::AlreadyExists)
+}
+
+fn create_game_token() -> Result<String, DbError> {
+    let bytes = argonautica::utils::generate_random_bytes(8)?;
+    let mut s = String::new();
+    for byte in bytes {
+        write!(&mut s, "{:X}", byte).unwrap();
+    }
+
+    Ok(s.to_lowercase())
+}
+
+fn hash_password(password: &str) -> Result<String, DbError> {
+    let mut hasher = Hasher::default();
+    Ok(hasher
+        .with_password(password)
+        .with_secret_key(&CONFIG.pepper)
+        .hash()?)
+}
+
+fn verify_password(pw_hash: &str, password: &str) -> Result<bool, DbError> {
+    let mut verifier = Verifier::default();
+    Ok(verifier
+        .with_hash(pw_hash)
+        .with_password(password)
+        .with_secret_key(&CONFIG.pepper)
+        .verify()?)
+}
+
+/// Stores a file's data and thumbnails, unless they're stored already, returning whether
+/// anything was written. Backends call this while holding the file's lock, so
+/// `remove_unreferenced_file` can't delete the data again straight after.
+async fn write_file_data(storage: &dyn Storage, file: &StoredFile) -> Result<bool, DbError> {
+    let image = match &file.image {
+        Some(image) => image,
+        None => return Ok(false),
+    };
+    if storage.exists(&file.key).await? {
+        return Ok(false);
+    }
+    upload::save_image(storage, &file.key, image).await?;
+    Ok(true)
+}
+
+/// Removes a file's data from storage once its database row is gone.
+async fn remove_file_data(storage: &dyn Storage, key: &str) {
+    info!("removing unreferenced file {}", key);
+    if let Err(e) = upload::remove_image(storage, key).await {
+        warn!("failed removing file {}: {}", key, e);
+    }
+}
+
+/// Processes files carried over from before uploads were processed: their data is re-encoded as
+/// PNG, thumbnails are generated, and their dimensions and size recorded. Files that can't be
+/// read or decoded are left as they are. Returns how many files were completed.
+pub async fn complete_legacy_files(db: &dyn Database) -> Result<usize, DbError> {
+    let storage = db.storage();
+    let mut completed = 0;
+    for mut file in db.get_unsized_files().await? {
+        let data = match storage.get(&file.key).await {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("failed reading legacy file {}: {}", file.key, e);
+                continue;
+            }
+        };
+        let image = match upload::process_image(&data) {
+            Ok(image) => image,
+            Err(e) => {
+                warn!("failed processing legacy file {}: {}", file.key, e);
+                continue;
+            }
+        };
+        upload::save_image(storage, &file.key, &image).await?;
+        file.width = image.width as i32;
+        file.height = image.height as i32;
+        file.size = image.size() as i64;
+        db.set_file_details(&file).await?;
+        info!("completed legacy file {}", file.key);
+        completed += 1;
+    }
+    Ok(completed)
+}
+
+/// How long a stored file may go without a database row before it counts as orphaned. Uploads
+/// are written to storage before their row is committed, so anything newer may still be in use.
+pub const ORPHAN_MIN_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Deletes anything in storage that no file in the database accounts for, e.g. uploads that
+/// failed halfway. Files younger than `min_age` are left alone, since they may belong to uploads
+/// still in progress. Returns the deleted keys.
+pub async fn purge_orphaned_files(
+    db: &dyn Database,
+    min_age: Duration,
+) -> Result<Vec<String>, DbError> {
+    let storage = db.storage();
+    let stored = storage.list().await?;
+    let mut known = HashSet::new();
+    for key in db.get_file_keys().await? {
+        for &size in THUMBNAIL_SIZES.iter() {
+            known.insert(thumbnail_key(&key, size));
+        }
+        known.insert(key);
+    }
+
+    let mut purged = Vec::new();
+    for entry in stored {
+        let age = entry.modified.elapsed().unwrap_or_default();
+        if known.contains(&entry.key) || age < min_age {
+            continue;
+        }
+        let key = entry.key;
+        match storage.delete(&key).await {
+            Ok(_) => {
+                info!("purged orphaned file {}", key);
+                purged.push(key);
+            }
+            Err(e) => warn!("failed purging file {}: {}", key, e),
+        }
+    }
+    Ok(purged)
+}
+
+/// In debug mode, creates a couple of users and a game to test with, if there aren't any users
+/// yet.
+async fn create_debug_users(db: &dyn Database) {
+    if CONFIG.mode != RunMode::Debug {
+        return;
+    }
+    if !db.list_users(None).await.unwrap().is_empty() {
+        return;
+    }
+
+    let token = db.create_user("admin", "password", "admin").await.unwrap();
+    let admin_token = db.confirm_user("admin", &token).await.unwrap();
+    db.set_admin("admin", true).await.unwrap();
+    let token = db
+        .create_user("player", "password", "player")
+        .await
+        .unwrap();
+    let player_token = db.confirm_user("player", &token).await.unwrap();
+    let game_token = db.create_game(&admin_token, &"Test Game").await.unwrap();
+    db.join_game(&player_token, &game_token).await.unwrap();
+    info!("DEBUG: admin token: {}", admin_token);
+    info!("DEBUG: player token: {}", player_token);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::{
+        allocate_user_tag, create_memory_database, purge_orphaned_files, Database, DbError, Game,
+        GameExport, ObjFilter, PostgresDb, ProfileSettings, ShareItem, ShareWith, SqliteDb,
+        ORPHAN_MIN_AGE, USER_TAGS,
+    };
+    use crate::storage::{MemoryStorage, Storage};
+    use crate::upload::{thumbnail_key, ProcessedImage, StoredFile, THUMBNAIL_SIZES};
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_postgres() {
+        // The checks share tables, so they run one after another against a real server, e.g.
+        //   RC_TEST_POSTGRES=1 RC_DB_PASSWORD=password cargo test
+        if std::env::var_os("RC_TEST_POSTGRES").is_none() {
+            eprintln!("SKIPPED test_postgres: set RC_TEST_POSTGRES=1 to run against Postgres");
+            return;
+        }
+        let db = PostgresDb::new(Arc::new(MemoryStorage::default())).unwrap();
+        let db: &dyn Database = &db;
+        reset_tables(db).await;
+        check_user_management(db).await;
+        reset_tables(db).await;
+        check_profile_management(db).await;
+        reset_tables(db).await;
+        check_game_management(db).await;
+        reset_tables(db).await;
+        check_object_management(db).await;
+        reset_tables(db).await;
+        check_file_data(db).await;
+        reset_tables(db).await;
+        check_object_replacement(db).await;
+        reset_tables(db).await;
+        check_quota(db).await;
+        reset_tables(db).await;
+        check_object_sharing(db).await;
+        reset_tables(db).await;
+        check_administration(db).await;
+        reset_tables(db).await;
+        check_game_transfer(db).await;
+    }
+
+    async fn reset_tables(db: &dyn Database) {
+        db.clear_tables().await.unwrap();
+        db.create_tables().await.unwrap();
+    }
+
+    async fn new_test_user(db: &dyn Database, name: &str) -> String {
+        let token = db.create_user(name, "password", name).await.unwrap();
+        db.confirm_user(name, &token).await.unwrap()
+    }
+
+    /// An upload of `data`, as it would be once processed. Different data gives a different file.
+    fn stored_file(data: &[u8]) -> StoredFile {
+        StoredFile::new(ProcessedImage {
+            data: data.to_vec(),
+            width: 30,
+            height: 20,
+            thumbnails: vec![b"small".to_vec(), b"large".to_vec()],
+        })
+    }
+
+    #[tokio::test]
+    async fn test_user_management() {
+        let db = create_memory_database().await.unwrap();
+        check_user_management(db.as_ref()).await;
+    }
+
+    async fn check_user_management(db: &dyn Database) {
+        // Create and verify a user
+        let email = "auth_user";
+        new_test_user(db, email).await;
+
+        // Check authentication passes when it should and fails when it should
+        assert!(db.auth_user(email, "password").await.is_ok());
+        assert!(db.auth_user(email, "not-password").await.is_err());
+
+        // Users sharing a nickname get different tags
+        let token = db
+            .create_user("other_user", "password", email)
+            .await
+            .unwrap();
+        let token = db.confirm_user("other_user", &token).await.unwrap();
+        let (_, username) = db.get_account(&token).await.unwrap();
+        let (_, other) = db.auth_user(email, "password").await.unwrap();
+        assert!(username.starts_with("auth_user#"));
+        assert_ne!(username, other);
+
+        // Changing nickname gives a new username
+        let (_, renamed) = db.set_nickname(&token, "renamed").await.unwrap();
+        assert!(renamed.starts_with("renamed#"));
+        assert_eq!(db.get_account(&token).await.unwrap().1, renamed);
+        assert!(matches!(
+            db.set_nickname("not-a-token", "renamed").await,
+            Err(DbError::Auth)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_profile_management() {
+        let db = create_memory_database().await.unwrap();
+        check_profile_management(db.as_ref()).await;
+    }
+
+    async fn check_profile_management(db: &dyn Database) {
+        // New users have an empty profile
+        let token = new_test_user(db, "test_profile").await;
+        let profile = db.get_profile(&token).await.unwrap();
+        assert!(profile.username.starts_with("test_profile#"));
+        assert_eq!(profile.avatar, None);
+        assert_eq!(profile.settings, ProfileSettings::default());
+
+        // Settings are stored as given
+        let settings = ProfileSettings {
+            colour: Some("#00ff00".to_string()),
+            pronouns: Some("they/them".to_string()),
+            timezone: Some("Australia/Melbourne".to_string()),
+            preferences: serde_json::json!({ "grid": true }),
+        };
+        db.set_profile(&token, &settings).await.unwrap();
+        assert_eq!(db.get_profile(&token).await.unwrap().settings, settings);
+
+        // Replacing the avatar releases the old image, without touching the settings
+        let second = stored_file(b"second");
+        db.set_avatar(&token, &stored_file(b"first")).await.unwrap();
+        db.set_avatar(&token, &second).await.unwrap();
+        let profile = db.get_profile(&token).await.unwrap();
+        assert_eq!(profile.avatar, Some(db.storage().url(&second.key)));
+        assert_eq!(profile.settings, settings);
+        assert!(db
+            .set_avatar("not-a-token", &stored_file(b"third"))
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_allocate_user_tag() {
+        // Only one tag is left, so it has to be picked
+        let mut used: HashSet<_> = (1..USER_TAGS).map(|i| format!("{:04}", i)).collect();
+        assert_eq!(allocate_user_tag(&used).unwrap(), "0000");
+
+        used.insert("0000".to_string());
+        assert!(matches!(
+            allocate_user_tag(&used),
+            Err(DbError::AlreadyExists)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_game_management() {
+        let db = create_memory_database().await.unwrap();
+        check_game_management(db.as_ref()).await;
+    }
+
+    async fn check_game_management(db: &dyn Database) {
+        // Create a host and a games
+        let host_token = new_test_user(db, "test_host").await;
+        let game_token = db.create_game(&host_token, "game").await.unwrap();
+
+        // Create a non-host user and join the games
+        let player_token = new_test_user(db, "test_player").await;
+        db.join_game(&player_token, &game_token).await.unwrap();
+
+        // Check hosted games
+        let hosted = db.get_hosted_games(&host_token).await.unwrap();
+        let game = Game {
+            token: game_token.clone(),
+            name: "game".to_string(),
+        };
+        assert_eq!(hosted.len(), 1);
+        assert!(hosted.contains(&game));
+
+        // Check joined games
+        let joined = db.get_joined_games(&player_token).await.unwrap();
+        let game = Game {
+            token: game_token,
+            name: "game".to_string(),
+        };
+        assert_eq!(joined.len(), 1);
+        assert!(joined.contains(&game));
+
+        // Save the game's state, then replace it
+        assert_eq!(db.load_game_state(&game.token).await.unwrap(), None);
+        db.save_game_state(&game.token, "{}").await.unwrap();
+        db.save_game_state(&game.token, "[]").await.unwrap();
+        assert_eq!(
+            db.load_game_state(&game.token).await.unwrap(),
+            Some("[]".to_string())
+        );
+        assert!(db.save_game_state("not-a-game", "{}").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_object_management() {
+        let db = create_memory_database().await.unwrap();
+        check_object_management(db.as_ref()).await;
+    }
+
+    async fn check_object_management(db: &dyn Database) {
+        // Create two objects for a user, sharing the same file
+        let token = new_test_user(db, "test_owner").await;
+        let file = stored_file(b"abc");
+        db.create_obj(&token, "map", &file).await.unwrap();
+        db.create_obj(&token, "other", &file).await.unwrap();
+        assert_eq!(db.get_usage(&token).await.unwrap().0, file.size as u64);
+
+        // A duplicate name is rejected
+        assert!(matches!(
+            db.create_obj(&token, "map", &file).await,
+            Err(DbError::AlreadyExists)
+        ));
+
+        // Renaming to an existing name fails, otherwise the object keeps its id
+        let objs = db
+            .get_owned_objs(&token, &ObjFilter::default())
+            .await
+            .unwrap();
+        let id = objs.iter().find(|obj| obj.name == "map").unwrap().id;
+        assert!(matches!(
+            db.rename_obj(&token, "map", "other").await,
+            Err(DbError::AlreadyExists)
+        ));
+        db.rename_obj(&token, "map", "renamed").await.unwrap();
+        let objs = db
+            .get_owned_objs(&token, &ObjFilter::default())
+            .await
+            .unwrap();
+        assert!(objs.iter().any(|obj| obj.id == id && obj.name == "renamed"));
+        assert!(matches!(
+            db.rename_obj(&token, "map", "again").await,
+            Err(DbError::Auth)
+        ));
+
+        // Filter by folder, tag and name
+        let folder = db.create_folder(&token, "dungeons").await.unwrap();
+        db.move_obj(&token, "renamed", Some(folder)).await.unwrap();
+        db.set_tags(&token, "other", &["cave".to_string()])
+            .await
+            .unwrap();
+        let filter = ObjFilter {
+            folder: Some(folder),
+            ..Default::default()
+        };
+        let objs = db.get_owned_objs(&token, &filter).await.unwrap();
+        assert_eq!(objs.len(), 1);
+        assert_eq!(objs[0].name, "renamed");
+        let filter = ObjFilter {
+            tag: Some("cave".to_string()),
+            ..Default::default()
+        };
+        let objs = db.get_owned_objs(&token, &filter).await.unwrap();
+        assert_eq!(objs.len(), 1);
+        assert_eq!(objs[0].tags, vec!["cave".to_string()]);
+        let filter = ObjFilter {
+            search: Some("NAME".to_string()),
+            ..Default::default()
+        };
+        let objs = db.get_owned_objs(&token, &filter).await.unwrap();
+        assert_eq!(objs.len(), 1);
+        assert_eq!(objs[0].name, "renamed");
+
+        // Deleting returns the object's id, and only works once
+        assert_eq!(db.delete_obj(&token, "renamed").await.unwrap(), id);
+        assert!(matches!(
+            db.delete_obj(&token, "renamed").await,
+            Err(DbError::Auth)
+        ));
+        db.delete_obj(&token, "other").await.unwrap();
+        assert_eq!(db.get_usage(&token).await.unwrap().0, 0);
+    }
+
+    #[tokio::test]
+    async fn test_file_data() {
+        let db = create_memory_database().await.unwrap();
+        check_file_data(db.as_ref()).await;
+    }
+
+    async fn check_file_data(db: &dyn Database) {
+        let token = new_test_user(db, "test_uploader").await;
+        let storage = db.storage();
+
+        // The data and thumbnails are stored along with the object
+        let first = stored_file(b"first");
+        db.create_obj(&token, "map", &first).await.unwrap();
+        assert!(storage.exists(&first.key).await.unwrap());
+        for &size in THUMBNAIL_SIZES.iter() {
+            assert!(storage
+                .exists(&thumbnail_key(&first.key, size))
+                .await
+                .unwrap());
+        }
+
+        // Nothing is left behind when the object can't be created
+        let second = stored_file(b"second");
+        assert!(matches!(
+            db.create_obj(&token, "map", &second).await,
+            Err(DbError::AlreadyExists)
+        ));
+        assert!(!storage.exists(&second.key).await.unwrap());
+        assert!(!db.get_file_keys().await.unwrap().contains(&second.key));
+
+        // The data goes once the last reference is released
+        db.delete_obj(&token, "map").await.unwrap();
+        assert!(!storage.exists(&first.key).await.unwrap());
+        assert!(db.get_file_keys().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_object_replacement() {
+        let db = create_memory_database().await.unwrap();
+        check_object_replacement(db.as_ref()).await;
+    }
+
+    async fn check_object_replacement(db: &dyn Database) {
+        let token = new_test_user(db, "test_replacer").await;
+        let storage = db.storage();
+        let first = stored_file(b"first");
+        let second = stored_file(b"second, a bit larger");
+        db.create_obj(&token, "map", &first).await.unwrap();
+        db.create_obj(&token, "copy", &first).await.unwrap();
+        let filter = ObjFilter::default();
+        let objs = db.get_owned_objs(&token, &filter).await.unwrap();
+        let id = objs.iter().find(|obj| obj.name == "map").unwrap().id;
+
+        // The object keeps its id but shows the new image, while the old file is still in use
+        db.replace_obj(&token, "map", &second).await.unwrap();
+        let objs = db.get_owned_objs(&token, &filter).await.unwrap();
+        let obj = objs.iter().find(|obj| obj.name == "map").unwrap();
+        assert_eq!(obj.id, id);
+        assert_eq!(obj.url, storage.url(&second.key));
+        let urls: Vec<String> = THUMBNAIL_SIZES
+            .iter()
+            .map(|&size| storage.url(&thumbnail_key(&second.key, size)))
+            .collect();
+        let thumbnails: Vec<String> = obj.thumbnails.iter().map(|t| t.url.clone()).collect();
+        assert_eq!(thumbnails, urls);
+        assert!(storage.exists(&first.key).await.unwrap());
+        assert_eq!(
+            db.get_usage(&token).await.unwrap().0,
+            (first.size + second.size) as u64
+        );
+
+        // Replacing the last object using the old file releases it
+        db.replace_obj(&token, "copy", &second).await.unwrap();
+        assert_eq!(db.get_usage(&token).await.unwrap().0, second.size as u64);
+        assert_eq!(db.get_file_keys().await.unwrap(), vec![second.key.clone()]);
+        assert!(!storage.exists(&first.key).await.unwrap());
+        for &size in THUMBNAIL_SIZES.iter() {
+            assert!(!storage
+                .exists(&thumbnail_key(&first.key, size))
+                .await
+                .unwrap());
+        }
+
+        // Objects that don't exist can't be replaced, and nothing is stored for them
+        let third = stored_file(b"third");
+        assert!(matches!(
+            db.replace_obj(&token, "missing", &third).await,
+            Err(DbError::Auth)
+        ));
+        assert!(!storage.exists(&third.key).await.unwrap());
+        assert_eq!(db.get_file_keys().await.unwrap(), vec![second.key.clone()]);
+    }
+
+    #[tokio::test]
+    async fn test_quota() {
+        let db = create_memory_database().await.unwrap();
+        check_quota(db.as_ref()).await;
+    }
+
+    async fn check_quota(db: &dyn Database) {
+        let token = new_test_user(db, "test_hoarder").await;
+        let (_, quota) = db.get_usage(&token).await.unwrap();
+        // Only the recorded sizes matter here, not the data
+        let file = |data: &[u8], size: u64| StoredFile {
+            size: size as i64,
+            ..stored_file(data)
+        };
+
+        // Uploads that would go over the quota are rejected without taking a reference
+        let big = file(b"big", quota - 100);
+        db.create_obj(&token, "big", &big).await.unwrap();
+        assert!(matches!(
+            db.create_obj(&token, "small", &file(b"small", 200)).await,
+            Err(DbError::QuotaExceeded)
+        ));
+        assert!(!db
+            .get_file_keys()
+            .await
+            .unwrap()
+            .contains(&stored_file(b"small").key));
+        assert_eq!(db.get_usage(&token).await.unwrap().0, quota - 100);
+
+        // Files the user already has don't count again, and replacing frees the old file's space
+        db.create_obj(&token, "copy", &big).await.unwrap();
+        assert!(matches!(
+            db.replace_obj(&token, "copy", &file(b"huge", quota + 1))
+                .await,
+            Err(DbError::QuotaExceeded)
+        ));
+        db.delete_obj(&token, "copy").await.unwrap();
+        db.replace_obj(&token, "big", &file(b"bigger", quota))
+            .await
+            .unwrap();
+        assert_eq!(db.get_usage(&token).await.unwrap().0, quota);
+        db.delete_obj(&token, "big").await.unwrap();
+
+        // Concurrent uploads can't get past the quota together
+        let half = quota / 2 + 1;
+        let (first, second) = futures::join!(
+            db.create_obj(&token, "first", &file(b"first", half)),
+            db.create_obj(&token, "second", &file(b"second", half))
+        );
+        assert_ne!(first.is_ok(), second.is_ok());
+        assert_eq!(db.get_usage(&token).await.unwrap().0, half);
+
+        // Avatars count as well
+        assert!(matches!(
+            db.set_avatar(&token, &file(b"avatar", quota)).await,
+            Err(DbError::QuotaExceeded)
+        ));
+        db.set_avatar(&token, &file(b"avatar", 100)).await.unwrap();
+        assert_eq!(db.get_usage(&token).await.unwrap().0, half + 100);
+    }
+
+    #[tokio::test]
+    async fn test_object_sharing() {
+        let db = create_memory_database().await.unwrap();
+        check_object_sharing(db.as_ref()).await;
+    }
+
+    async fn check_object_sharing(db: &dyn Database) {
+        let owner_token = new_test_user(db, "test_sharer").await;
+        let friend_token = new_test_user(db, "test_friend").await;
+        let player_token = new_test_user(db, "test_player").await;
+        let file = stored_file(b"abc");
+        db.create_obj(&owner_token, "map", &file).await.unwrap();
+        db.create_obj(&owner_token, "handout", &file).await.unwrap();
+
+        // Share one object directly with a user
+        let (_, friend_name) = db.get_account(&friend_token).await.unwrap();
+        let item = ShareItem::Object("map".to_string());
+        db.share(&owner_token, &item, &ShareWith::User(friend_name))
+            .await
+            .unwrap();
+        let shared = db.get_shared_objs(&friend_token).await.unwrap();
+        assert_eq!(shared.len(), 1);
+        assert_eq!(shared[0].name, "map");
+        assert!(db.get_shared_objs(&player_token).await.unwrap().is_empty());
+
+        // Share a folder with a game
+        let game_token = db.create_game(&owner_token, "game").await.unwrap();
+        db.join_game(&player_token, &game_token).await.unwrap();
+        let folder = db.create_folder(&owner_token, "handouts").await.unwrap();
+        db.move_obj(&owner_token, "handout", Some(folder))
+            .await
+            .unwrap();
+        let with = ShareWith::Game(game_token);
+        db.share(&owner_token, &ShareItem::Folder(folder), &with)
+            .await
+            .unwrap();
+        let shared = db.get_shared_objs(&player_token).await.unwrap();
+        assert_eq!(shared.len(), 1);
+        assert_eq!(shared[0].name, "handout");
+
+        // Usernames need their tag
+        assert!(matches!(
+            db.share(
+                &owner_token,
+                &item,
+                &ShareWith::User("test_friend".to_string())
+            )
+            .await,
+            Err(DbError::InvalidUsername)
+        ));
+
+        // Only owners can share
+        assert!(matches!(
+            db.share(&player_token, &item, &with).await,
+            Err(DbError::Auth)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_administration() {
+        let db = create_memory_database().await.unwrap();
+        check_administration(db.as_ref()).await;
+    }
+
+    async fn check_administration(db: &dyn Database) {
+        // Debug mode adds its own users and game
+        let existing_users = db.list_users(None).await.unwrap().len();
+
+        // Users aren't admins until made one
+        let admin_token = new_test_user(db, "test_admin").await;
+        let user_token = new_test_user(db, "test_user").await;
+        assert!(matches!(
+            db.check_admin(&admin_token).await,
+            Err(DbError::Forbidden)
+        ));
+        db.set_admin("test_admin", true).await.unwrap();
+        let (admin_id, _) = db.get_account(&admin_token).await.unwrap();
+        assert_eq!(db.check_admin(&admin_token).await.unwrap(), admin_id);
+        assert!(matches!(
+            db.set_admin("missing", true).await,
+            Err(DbError::Auth)
+        ));
+
+        // Users can be found by email or username
+        assert_eq!(db.list_users(None).await.unwrap().len(), existing_users + 2);
+        let users = db.list_users(Some("USER")).await.unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].email, "test_user");
+        assert!(users[0].username.starts_with("test_user#"));
+        assert!(!users[0].admin && !users[0].disabled);
+
+        // Disabled users can't sign in, or keep using their session
+        let user_id = users[0].id;
+        db.set_disabled(user_id, true).await.unwrap();
+        assert!(db.list_users(Some("test_user")).await.unwrap()[0].disabled);
+        assert!(matches!(
+            db.get_account(&user_token).await,
+            Err(DbError::Auth)
+        ));
+        assert!(!db.check_token(&user_token).await.unwrap());
+        assert!(matches!(
+            db.auth_user("test_user", "password").await,
+            Err(DbError::Auth)
+        ));
+        db.set_disabled(user_id, false).await.unwrap();
+        let (user_token, _) = db.auth_user("test_user", "password").await.unwrap();
+        assert!(matches!(
+            db.set_disabled(-1, true).await,
+            Err(DbError::Auth)
+        ));
+
+        // Games are listed with their host and players
+        let game_token = db.create_game(&admin_token, "game").await.unwrap();
+        db.join_game(&user_token, &game_token).await.unwrap();
+        let games = db.list_games().await.unwrap();
+        let game = games.iter().find(|game| game.token == game_token).unwrap();
+        assert!(game.host.starts_with("test_admin#"));
+        assert_eq!(game.players, 2);
+
+        // Only stored files that the database doesn't know about are purged
+        let file = stored_file(b"abc");
+        db.create_obj(&user_token, "map", &file).await.unwrap();
+        let storage = db.storage();
+        storage.put("orphan.png", b"data").await.unwrap();
+        assert_eq!(db.get_file_keys().await.unwrap(), vec![file.key.clone()]);
+        assert!(purge_orphaned_files(db, ORPHAN_MIN_AGE)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(storage.exists("orphan.png").await.unwrap());
+        assert_eq!(
+            purge_orphaned_files(db, Duration::from_secs(0))
+                .await
+                .unwrap(),
+            vec!["orphan.png".to_string()]
+        );
+        assert!(storage.exists(&file.key).await.unwrap());
+        assert!(!storage.exists("orphan.png").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_migration() {
+        // A database from before folders, storage quotas and admins, with an unprocessed image
+        let path =
+            std::env::temp_dir().join(format!("rolecall_migration_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "
+            CREATE TABLE user_accounts(
+                id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                email       text UNIQUE NOT NULL,
+                token       text NOT NULL,
+                nickname    text NOT NULL,
+                tag         text NOT NULL,
+                timeout     bigint NOT NULL,
+                CONSTRAINT unique_user_name UNIQUE(nickname, tag)
+            );
+            CREATE TABLE files(
+                hash    text PRIMARY KEY,
+                key     text NOT NULL,
+                width   integer NOT NULL,
+                height  integer NOT NULL,
+                refs    integer NOT NULL
+            );
+            CREATE TABLE objects(
+                id      INTEGER PRIMARY KEY AUTOINCREMENT,
+                owner   integer NOT NULL,
+                name    text NOT NULL,
+                file    text NOT NULL,
+                UNIQUE (owner, name)
+            );
+            INSERT INTO user_accounts (email, token, nickname, tag, timeout)
+            VALUES ('old_user', 'token', 'old_user', '0001', 0);
+            INSERT INTO files VALUES ('legacy', 'legacy.png', 0, 0, 1);
+            INSERT INTO objects (owner, name, file) VALUES (1, 'map', 'legacy');",
+        )
+        .unwrap();
+        drop(conn);
+
+        let storage = Arc::new(MemoryStorage::default());
+        let mut image = std::io::Cursor::new(Vec::new());
+        image::DynamicImage::new_rgb8(6, 4)
+            .write_to(&mut image, image::ImageOutputFormat::Png)
+            .unwrap();
+        storage.put("legacy.png", image.get_ref()).await.unwrap();
+
+        let db = SqliteDb::new(path.to_str().unwrap(), storage.clone()).unwrap();
+        db.create_tables().await.unwrap();
+
+        // The new columns are there, with their defaults for existing rows
+        db.set_admin("old_user", true).await.unwrap();
+        let users = db.list_users(Some("old_user")).await.unwrap();
+        assert!(users[0].admin);
+        assert!(!users[0].disabled);
+
+        // The image has been processed and its details recorded
+        assert!(db.get_unsized_files().await.unwrap().is_empty());
+        for &size in THUMBNAIL_SIZES.iter() {
+            assert!(storage
+                .exists(&thumbnail_key("legacy.png", size))
+                .await
+                .unwrap());
+        }
+
+        // Migrating again changes nothing
+        db.create_tables().await.unwrap();
+        assert!(db.list_users(Some("old_user")).await.unwrap()[0].admin);
+        drop(db);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_game_transfer() {
+        let db = create_memory_database().await.unwrap();
+        check_game_transfer(db.as_ref()).await;
+    }
+
+    async fn check_game_transfer(db: &dyn Database) {
+        // Resetting a password ends the user's sessions
+        let host_token = new_test_user(db, "transfer_host").await;
+        db.set_password("transfer_host", "new password")
+            .await
+            .unwrap();
+        assert!(matches!(
+            db.get_account(&host_token).await,
+            Err(DbError::Auth)
+        ));
+        assert!(matches!(
+            db.auth_user("transfer_host", "password").await,
+            Err(DbError::Auth)
+        ));
+        let (host_token, _) = db.auth_user("transfer_host", "new password").await.unwrap();
+        assert!(matches!(
+            db.set_password("missing", "password").await,
+            Err(DbError::Auth)
+        ));
+
+        // Members are listed with the host
+        let player_token = new_test_user(db, "transfer_player").await;
+        let game_token = db.create_game(&host_token, "exported").await.unwrap();
+        db.join_game(&player_token, &game_token).await.unwrap();
+        db.save_game_state(&game_token, "{\"layers\":[]}")
+            .await
+            .unwrap();
+        let members = db.get_game_members(&game_token).await.unwrap();
+        let emails: Vec<&str> = members.iter().map(|m| m.email.as_str()).collect();
+        assert_eq!(emails, vec!["transfer_host", "transfer_player"]);
+        assert!(matches!(
+            db.get_game_members("missing").await,
+            Err(DbError::Auth)
+        ));
+
+        // An exported game comes back under a new token, without players who have no account
+        let export = db.export_game(&game_token).await.unwrap();
+        assert_eq!(
+            export,
+            GameExport {
+                name: "exported".to_string(),
+                host: "transfer_host".to_string(),
+                players: vec!["transfer_player".to_string()],
+                state: Some("{\"layers\":[]}".to_string()),
+            }
+        );
+        let mut import = export.clone();
+        import.players.push("stranger".to_string());
+        let imported = db.import_game(&import).await.unwrap();
+        assert_ne!(imported, game_token);
+        assert_eq!(db.export_game(&imported).await.unwrap(), export);
+        assert_eq!(db.get_joined_games(&player_token).await.unwrap().len(), 2);
+
+        import.host = "stranger".to_string();
+        assert!(matches!(db.import_game(&import).await, Err(DbError::Auth)));
+        assert!(matches!(
+            db.export_game("missing").await,
+            Err(DbError::Auth)
+        ));
+    }
+}
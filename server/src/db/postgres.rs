@@ -0,0 +1,1727 @@
+use super::*;
+use deadpool_postgres::{Client, Manager, ManagerConfig, Pool, RecyclingMethod, Transaction};
+use futures::future;
+use tokio_postgres::error::SqlState;
+use tokio_postgres::{NoTls, Row};
+
+/// Columns to select for `object_from_row`.
+const OBJECT_COLUMNS: &str = "
+    objects.id, objects.name, files.key, files.width, files.height, objects.folder,
+    ARRAY(SELECT tag FROM object_tags WHERE object_id=objects.id ORDER BY tag)";
+
+fn object_from_row(storage: &dyn Storage, row: &Row) -> Object {
+    let key: String = row.get(2);
+    Object {
+        id: row.get(0),
+        name: row.get(1),
+        url: storage.url(&key),
+        width: row.get(3),
+        height: row.get(4),
+        thumbnails: thumbnails(storage, &key),
+        folder: row.get(5),
+        tags: row.get(6),
+    }
+}
+
+/// Reads columns `id, email, nickname, tag, admin, disabled` of `user_accounts`.
+fn account_from_row(row: &Row) -> Account {
+    let nickname: String = row.get(2);
+    let tag: String = row.get(3);
+    Account {
+        id: row.get(0),
+        email: row.get(1),
+        username: format!("{}#{}", nickname, tag),
+        admin: row.get(4),
+        disabled: row.get(5),
+    }
+}
+
+/// Changes to the schema since the first release, in order. A database at schema version `n` has
+/// had the first `n` applied; new databases start at the latest version. Tables added since then
+/// come from `create_tables`, and each step leaves alone anything already in the new shape.
+const MIGRATIONS: [&str; 4] = [
+    // Objects used to keep the path of their image, and for a while its dimensions. Each path
+    // becomes a file, whose size and thumbnails `complete_legacy_files` fills in afterwards.
+    "
+    DO $$
+    BEGIN
+        IF EXISTS (
+            SELECT 1
+            FROM information_schema.columns
+            WHERE table_schema=current_schema() AND table_name='objects' AND column_name='path'
+        ) THEN
+            ALTER TABLE objects
+                ADD COLUMN IF NOT EXISTS width integer NOT NULL DEFAULT 0,
+                ADD COLUMN IF NOT EXISTS height integer NOT NULL DEFAULT 0,
+                ADD COLUMN IF NOT EXISTS file text;
+            UPDATE objects
+            SET file=regexp_replace(path, '^.*/', '');
+            INSERT INTO files (hash, key, width, height, size, refs)
+                SELECT file, file, max(width), max(height), 0, count(*)
+                FROM objects
+                GROUP BY file
+            ON CONFLICT (hash) DO NOTHING;
+            ALTER TABLE objects
+                DROP COLUMN path,
+                DROP COLUMN width,
+                DROP COLUMN height,
+                ALTER COLUMN file SET NOT NULL,
+                ADD FOREIGN KEY (file) REFERENCES files(hash);
+        END IF;
+    END $$;",
+    "
+    ALTER TABLE files
+        ADD COLUMN IF NOT EXISTS size bigint NOT NULL DEFAULT 0;",
+    "
+    ALTER TABLE objects
+        ADD COLUMN IF NOT EXISTS folder integer REFERENCES folders(id) ON DELETE SET NULL;",
+    "
+    ALTER TABLE user_accounts
+        ADD COLUMN IF NOT EXISTS admin boolean NOT NULL DEFAULT false,
+        ADD COLUMN IF NOT EXISTS disabled boolean NOT NULL DEFAULT false;",
+];
+
+pub struct PostgresDb {
+    pool: Pool,
+    storage: Arc<dyn Storage>,
+}
+
+impl PostgresDb {
+    pub fn new(storage: Arc<dyn Storage>) -> Result<Self, DbError> {
+        let mut pg_config = tokio_postgres::Config::new();
+        pg_config
+            .host(&CONFIG.db_addr)
+            .user(&CONFIG.db_user)
+            .password(&CONFIG.db_password)
+            .dbname(&CONFIG.db_name);
+        let manager = Manager::from_config(
+            pg_config,
+            NoTls,
+            ManagerConfig {
+                recycling_method: RecyclingMethod::Fast,
+            },
+        );
+        let pool = Pool::builder(manager)
+            .max_size(CONFIG.db_pool_size)
+            .build()?;
+
+        Ok(Self { pool, storage })
+    }
+
+    /// Takes a connection from the pool, waiting for one to be free if necessary.
+    async fn client(&self) -> Result<Client, DbError> {
+        Ok(self.pool.get().await?)
+    }
+
+    async fn remove_unconfirmed(
+        tx: &Transaction<'_>,
+        email: &str,
+        token: &str,
+    ) -> Result<(String, String), DbError> {
+        let statement = "
+            DELETE FROM unconfirmed_identities
+            WHERE email=$1 AND token=$2
+            RETURNING pw_hash, nickname;";
+        let rows = tx.query(statement, &[&email, &token]).await?;
+        if rows.len() > 0 {
+            let row = rows.get(0).ok_or(DbError::Auth)?;
+            Ok((row.get(0), row.get(1)))
+        } else {
+            Err(DbError::Auth)
+        }
+    }
+
+    /// Allocates a tag while holding a lock on the nickname, so concurrent sign-ups can't race.
+    async fn allocate_tag(tx: &Transaction<'_>, nickname: &str) -> Result<String, DbError> {
+        tx.execute("SELECT pg_advisory_xact_lock(hashtext($1));", &[&nickname])
+            .await?;
+        let statement = "
+            SELECT tag
+            FROM user_accounts
+            WHERE nickname=$1;";
+        let used: HashSet<String> = tx
+            .query(statement, &[&nickname])
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        allocate_user_tag(&used)
+    }
+
+    async fn get_identities(&self, email: &str) -> Result<(UserId, String), DbError> {
+        let statement = "
+            SELECT user_id, pw_hash
+            FROM identities
+            WHERE email=$1;";
+        let rows = self.client().await?.query(statement, &[&email]).await?;
+        if rows.len() > 0 {
+            let record = rows.get(0).ok_or(DbError::Auth)?;
+            let user_id: UserId = record.get(0);
+            let pw_hash: &str = record.get(1);
+            Ok((user_id, pw_hash.to_string()))
+        } else {
+            Err(DbError::Auth)
+        }
+    }
+
+    async fn get_game(&self, game_token: &str) -> Result<GameId, DbError> {
+        let statement = "
+            SELECT id
+            FROM games
+            WHERE token=$1;";
+        let rows = self
+            .client()
+            .await?
+            .query(statement, &[&game_token])
+            .await?;
+        if rows.len() > 0 {
+            let game_id: GameId = rows.get(0).ok_or(DbError::Auth)?.get(0);
+            Ok(game_id)
+        } else {
+            Err(DbError::Auth)
+        }
+    }
+
+    /// Bytes stored for a user's objects and avatar, read inside or outside a transaction.
+    async fn get_user_usage(
+        client: &impl deadpool_postgres::GenericClient,
+        user_id: UserId,
+    ) -> Result<u64, DbError> {
+        // Identical files are only counted once per user
+        let statement = "
+            SELECT COALESCE(SUM(size), 0)::bigint
+            FROM files
+            WHERE hash IN (
+                SELECT file
+                FROM objects
+                WHERE owner=$1
+                UNION
+                SELECT avatar
+                FROM user_profiles
+                WHERE user_id=$1
+            );";
+        let row = client.query_one(statement, &[&user_id]).await?;
+        let used: i64 = row.get(0);
+        Ok(used as u64)
+    }
+
+    /// Locks a user's account until the transaction ends, returning their current usage, so
+    /// their uploads are checked against the quota one at a time.
+    async fn lock_usage(tx: &Transaction<'_>, user_id: UserId) -> Result<u64, DbError> {
+        let statement = "
+            SELECT id
+            FROM user_accounts
+            WHERE id=$1
+            FOR UPDATE;";
+        tx.execute(statement, &[&user_id]).await?;
+        Self::get_user_usage(tx, user_id).await
+    }
+
+    /// Fails if the changes made in `tx` took the user over their quota. Changes that don't add
+    /// to their usage, like reusing a file they already have, are always allowed.
+    async fn check_quota(tx: &Transaction<'_>, user_id: UserId, used: u64) -> Result<(), DbError> {
+        let now_used = Self::get_user_usage(tx, user_id).await?;
+        if now_used > used && now_used > CONFIG.user_quota_mb {
+            Err(DbError::QuotaExceeded)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Locks a stored file until the transaction ends, so its data can be written or removed
+    /// without racing another instance doing the opposite.
+    async fn lock_file(tx: &Transaction<'_>, hash: &str) -> Result<(), DbError> {
+        let statement = "SELECT pg_advisory_xact_lock(hashtext('file:' || $1::text));";
+        tx.execute(statement, &[&hash]).await?;
+        Ok(())
+    }
+
+    /// Takes a reference to a stored file, holding its lock until the transaction ends.
+    /// Identical uploads share a file, so this just bumps the count if it's already recorded.
+    /// The file's data is written by `write_file_data` before committing.
+    async fn acquire_file(tx: &Transaction<'_>, file: &StoredFile) -> Result<(), DbError> {
+        Self::lock_file(tx, &file.hash).await?;
+        let statement = "
+            INSERT INTO files (hash, key, width, height, size, refs)
+            VALUES ($1, $2, $3, $4, $5, 1)
+            ON CONFLICT (hash) DO UPDATE
+            SET refs = files.refs + 1;";
+        tx.execute(
+            statement,
+            &[&file.hash, &file.key, &file.width, &file.height, &file.size],
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Removes a stored file if nothing refers to it any more. The file stays locked until its
+    /// data is gone, so an upload of the same file waits and then stores it again. Failing to
+    /// remove the data only leaves an orphaned file behind.
+    async fn remove_unreferenced_file(&self, hash: &str) -> Result<(), DbError> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        Self::lock_file(&tx, hash).await?;
+        let statement = "
+            DELETE FROM files
+            WHERE hash=$1 AND refs <= 0
+            RETURNING key;";
+        let rows = tx.query(statement, &[&hash]).await?;
+        if let Some(row) = rows.get(0) {
+            let key: String = row.get(0);
+            remove_file_data(self.storage(), &key).await;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Resolves a share to the ids stored in the `shares` table, checking the sharer owns the
+    /// item and is in the game they are sharing with.
+    async fn resolve_share(
+        &self,
+        user_id: UserId,
+        item: &ShareItem,
+        with: &ShareWith,
+    ) -> Result<[Option<i32>; 4], DbError> {
+        let (object_id, folder_id) = match item {
+            ShareItem::Object(name) => {
+                let statement = "
+                    SELECT id
+                    FROM objects
+                    WHERE owner=$1 AND name=$2;";
+                let rows = self
+                    .client()
+                    .await?
+                    .query(statement, &[&user_id, name])
+                    .await?;
+                let id: i32 = rows.get(0).ok_or(DbError::Auth)?.get(0);
+                (Some(id), None)
+            }
+            ShareItem::Folder(id) => {
+                let statement = "
+                    SELECT id
+                    FROM folders
+                    WHERE owner=$1 AND id=$2;";
+                let rows = self
+                    .client()
+                    .await?
+                    .query(statement, &[&user_id, id])
+                    .await?;
+                let id: i32 = rows.get(0).ok_or(DbError::Auth)?.get(0);
+                (None, Some(id))
+            }
+        };
+
+        let (target_user, game_id) = match with {
+            ShareWith::User(username) => {
+                let (nickname, tag) = username.rsplit_once('#').ok_or(DbError::InvalidUsername)?;
+                let statement = "
+                    SELECT id
+                    FROM user_accounts
+                    WHERE nickname=$1 AND tag=$2;";
+                let rows = self
+                    .client()
+                    .await?
+                    .query(statement, &[&nickname, &tag])
+                    .await?;
+                let id: UserId = rows.get(0).ok_or(DbError::Auth)?.get(0);
+                (Some(id), None)
+            }
+            ShareWith::Game(game_token) => {
+                let game_id = self.get_game(game_token).await?;
+                let statement = "
+                    SELECT COUNT(1)
+                    FROM user_games
+                    WHERE user_id=$1 AND game_id=$2;";
+                let row = self
+                    .client()
+                    .await?
+                    .query_one(statement, &[&user_id, &game_id])
+                    .await?;
+                let count: i64 = row.get(0);
+                if count == 0 {
+                    return Err(DbError::Auth);
+                }
+                (None, Some(game_id))
+            }
+        };
+
+        Ok([object_id, folder_id, target_user, game_id])
+    }
+}
+
+#[rocket::async_trait]
+impl Database for PostgresDb {
+    fn storage(&self) -> &dyn Storage {
+        self.storage.as_ref()
+    }
+
+    async fn clear_tables(&self) -> Result<(), DbError> {
+        self.client()
+            .await?
+            .execute(
+                "
+            DROP TABLE IF EXISTS
+            user_accounts, identities, unconfirmed_identities, games, user_games, files, folders,
+            objects, object_tags, shares, user_profiles, game_states, schema_version
+            CASCADE;",
+                &[],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn create_tables(&self) -> Result<(), DbError> {
+        // Use a single connection so each batch of statements runs after the previous one
+        let mut client = self.client().await?;
+        let fresh: bool = client
+            .query_one("SELECT to_regclass('user_accounts') IS NULL;", &[])
+            .await?
+            .get(0);
+        future::try_join4(
+            client.execute(
+                "
+                CREATE TABLE IF NOT EXISTS schema_version(
+                    version integer NOT NULL
+                );",
+                &[],
+            ),
+            client.execute(
+                "
+                CREATE TABLE IF NOT EXISTS user_accounts(
+                    id          serial PRIMARY KEY,
+                    email       text UNIQUE NOT NULL,
+                    token       text NOT NULL,
+                    nickname    text NOT NULL,
+                    tag         text NOT NULL,
+                    CONSTRAINT unique_user_name UNIQUE(nickname, tag),
+                    timeout     bigint NOT NULL,
+                    admin       boolean NOT NULL DEFAULT false,
+                    disabled    boolean NOT NULL DEFAULT false
+                );",
+                &[],
+            ),
+            client.execute(
+                "
+                CREATE TABLE IF NOT EXISTS identities(
+                    id      serial PRIMARY KEY,
+                    email   text UNIQUE NOT NULL,
+                    pw_hash text NOT NULL,
+                    user_id integer NOT NULL,
+                    FOREIGN KEY (user_id) REFERENCES user_accounts(id) ON DELETE CASCADE
+                );",
+                &[],
+            ),
+            client.execute(
+                "
+                CREATE TABLE IF NOT EXISTS unconfirmed_identities(
+                    id          serial PRIMARY KEY,
+                    email       text UNIQUE NOT NULL,
+                    pw_hash     text NOT NULL,
+                    nickname    text NOT NULL,
+                    token       text NOT NULL
+                );",
+                &[],
+            ),
+        )
+        .await?;
+
+        future::try_join5(
+            client.execute(
+                "
+                CREATE TABLE IF NOT EXISTS games(
+                    id      serial PRIMARY KEY,
+                    host    integer NOT NULL,
+                    token   text UNIQUE NOT NULL,
+                    name    text NOT NULL,
+                    FOREIGN KEY (host) REFERENCES user_accounts(id) ON DELETE CASCADE
+                );",
+                &[],
+            ),
+            client.execute(
+                "
+                CREATE TABLE IF NOT EXISTS user_games(
+                    user_id integer NOT NULL,
+                    game_id integer NOT NULL,
+                    PRIMARY KEY (user_id, game_id),
+                    FOREIGN KEY (user_id) REFERENCES user_accounts(id)   ON DELETE CASCADE,
+                    FOREIGN KEY (game_id) REFERENCES games(id)           ON DELETE CASCADE
+                );",
+                &[],
+            ),
+            client.execute(
+                "
+                CREATE TABLE IF NOT EXISTS files(
+                    hash    text PRIMARY KEY,
+                    key     text NOT NULL,
+                    width   integer NOT NULL,
+                    height  integer NOT NULL,
+                    size    bigint NOT NULL,
+                    refs    integer NOT NULL
+                );",
+                &[],
+            ),
+            client.execute(
+                "
+                CREATE TABLE IF NOT EXISTS folders(
+                    id      serial PRIMARY KEY,
+                    owner   integer NOT NULL,
+                    name    text NOT NULL,
+                    UNIQUE (owner, name),
+                    FOREIGN KEY (owner) REFERENCES user_accounts(id) ON DELETE CASCADE
+                );",
+                &[],
+            ),
+            client.execute(
+                "
+                CREATE TABLE IF NOT EXISTS objects(
+                    id      serial PRIMARY KEY,
+                    owner   integer NOT NULL,
+                    name    text NOT NULL,
+                    UNIQUE (owner, name),
+                    file    text NOT NULL,
+                    folder  integer,
+                    FOREIGN KEY (owner)  REFERENCES user_accounts(id) ON DELETE CASCADE,
+                    FOREIGN KEY (file)   REFERENCES files(hash),
+                    FOREIGN KEY (folder) REFERENCES folders(id)       ON DELETE SET NULL
+                );",
+                &[],
+            ),
+        )
+        .await?;
+
+        future::try_join4(
+            client.execute(
+                "
+                CREATE TABLE IF NOT EXISTS user_profiles(
+                    user_id     integer PRIMARY KEY,
+                    avatar      text,
+                    colour      text,
+                    pronouns    text,
+                    timezone    text,
+                    preferences text NOT NULL DEFAULT 'null',
+                    FOREIGN KEY (user_id) REFERENCES user_accounts(id) ON DELETE CASCADE,
+                    FOREIGN KEY (avatar)  REFERENCES files(hash)
+                );",
+                &[],
+            ),
+            client.execute(
+                "
+                CREATE TABLE IF NOT EXISTS game_states(
+                    game_id integer PRIMARY KEY,
+                    state   text NOT NULL,
+                    FOREIGN KEY (game_id) REFERENCES games(id) ON DELETE CASCADE
+                );",
+                &[],
+            ),
+            client.execute(
+                "
+                CREATE TABLE IF NOT EXISTS object_tags(
+                    object_id   integer NOT NULL,
+                    tag         text NOT NULL,
+                    PRIMARY KEY (object_id, tag),
+                    FOREIGN KEY (object_id) REFERENCES objects(id) ON DELETE CASCADE
+                );",
+                &[],
+            ),
+            client.execute(
+                "
+                CREATE TABLE IF NOT EXISTS shares(
+                    id          serial PRIMARY KEY,
+                    owner       integer NOT NULL,
+                    object_id   integer,
+                    folder_id   integer,
+                    user_id     integer,
+                    game_id     integer,
+                    CHECK ((object_id IS NULL) <> (folder_id IS NULL)),
+                    CHECK ((user_id IS NULL) <> (game_id IS NULL)),
+                    FOREIGN KEY (owner)     REFERENCES user_accounts(id) ON DELETE CASCADE,
+                    FOREIGN KEY (object_id) REFERENCES objects(id)       ON DELETE CASCADE,
+                    FOREIGN KEY (folder_id) REFERENCES folders(id)       ON DELETE CASCADE,
+                    FOREIGN KEY (user_id)   REFERENCES user_accounts(id) ON DELETE CASCADE,
+                    FOREIGN KEY (game_id)   REFERENCES games(id)         ON DELETE CASCADE
+                );",
+                &[],
+            ),
+        )
+        .await?;
+
+        // Servers starting together wait here, so each migration runs once
+        let tx = client.transaction().await?;
+        tx.batch_execute("LOCK TABLE schema_version IN EXCLUSIVE MODE;")
+            .await?;
+        let recorded: Option<i32> = tx
+            .query_opt("SELECT version FROM schema_version;", &[])
+            .await?
+            .map(|row| row.get(0));
+        let start = match recorded {
+            Some(version) => version as usize,
+            None if fresh => MIGRATIONS.len(),
+            None => 0,
+        };
+        for migration in MIGRATIONS.iter().skip(start) {
+            tx.batch_execute(migration).await?;
+        }
+        let version = MIGRATIONS.len().max(start) as i32;
+        tx.batch_execute("DELETE FROM schema_version;").await?;
+        tx.execute(
+            "INSERT INTO schema_version (version) VALUES ($1);",
+            &[&version],
+        )
+        .await?;
+        tx.commit().await?;
+        drop(client);
+        if start < version as usize {
+            info!(
+                "migrated database schema from version {} to {}",
+                start, version
+            );
+        }
+
+        complete_legacy_files(self).await?;
+        create_debug_users(self).await;
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), DbError> {
+        self.client().await?.query_one("SELECT 1;", &[]).await?;
+        Ok(())
+    }
+
+    async fn create_user(
+        &self,
+        email: &str,
+        password: &str,
+        nickname: &str,
+    ) -> Result<String, DbError> {
+        let (token, _) = create_user_token()?;
+        let pw_hash = hash_password(password)?;
+
+        // Create new unconfirmed user
+        let statement = "
+            INSERT INTO unconfirmed_identities(email, pw_hash, token, nickname)
+            VALUES($1, $2, $3, $4);";
+        self.client()
+            .await?
+            .execute(statement, &[&email, &pw_hash, &token, &nickname])
+            .await?;
+        info!("created new unverified user: {}", email);
+
+        Ok(token)
+    }
+
+    async fn confirm_user(&self, email: &str, token: &str) -> Result<String, DbError> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        let (pw_hash, nickname) = Self::remove_unconfirmed(&tx, email, token).await?;
+        let tag = Self::allocate_tag(&tx, &nickname).await?;
+
+        let (token, timeout) = create_user_token()?;
+        let statement = "
+            INSERT INTO user_accounts(email, token, timeout, nickname, tag, admin)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING (id);";
+        let admin = CONFIG.admins.iter().any(|admin| admin == email);
+        let row = tx
+            .query_one(
+                statement,
+                &[&email, &token, &timeout, &nickname, &tag, &admin],
+            )
+            .await?;
+        let user_id: i32 = row.get(0);
+        info!("generated new token for user #{}", user_id);
+
+        let statement = "
+            INSERT INTO identities(email, pw_hash, user_id)
+            VALUES($1, $2, $3);";
+        tx.execute(statement, &[&email, &pw_hash, &user_id]).await?;
+        tx.commit().await?;
+        info!("verified user id #{}: {}", user_id, email);
+        Ok(token)
+    }
+
+    async fn auth_user(&self, email: &str, password: &str) -> Result<(String, String), DbError> {
+        let (user_id, pw_hash) = self.get_identities(email).await?;
+        if verify_password(&pw_hash, password)? {
+            let (token, timeout) = create_user_token()?;
+            info!("generated new token for user #{}", user_id);
+
+            let statement = "
+                UPDATE user_accounts
+                SET token=$2, timeout=$3
+                WHERE id=$1 AND NOT disabled
+                RETURNING nickname, tag;";
+            let rows = self
+                .client()
+                .await?
+                .query(statement, &[&user_id, &token, &timeout])
+                .await?;
+            let row = rows.get(0).ok_or(DbError::Auth)?;
+            let nickname: String = row.get(0);
+            let tag: String = row.get(1);
+            let username = format!("{}#{}", nickname, tag);
+            Ok((token, username))
+        } else {
+            Err(DbError::Auth)
+        }
+    }
+
+    async fn get_account(&self, token: &str) -> Result<(UserId, String), DbError> {
+        let statement = "
+            SELECT id, timeout, nickname, tag
+            FROM user_accounts
+            WHERE token=$1 AND NOT disabled;";
+        let client = self.client().await?;
+        let statement = client.prepare_cached(statement).await?;
+        let rows = client.query(&statement, &[&token]).await?;
+        if rows.len() > 0 {
+            let record = rows.get(0).ok_or(DbError::Auth)?;
+            let user_id: UserId = record.get(0);
+            let timeout: Timestamp = record.get(1);
+            let nickname: String = record.get(2);
+            let tag: String = record.get(3);
+            let username = format!("{}#{}", nickname, tag);
+            if timestamp()? < timeout {
+                Ok((user_id, username))
+            } else {
+                Err(DbError::Auth)
+            }
+        } else {
+            Err(DbError::Auth)
+        }
+    }
+
+    async fn get_profile(&self, user_token: &str) -> Result<Profile, DbError> {
+        let (user_id, username) = self.get_account(user_token).await?;
+        let statement = "
+            SELECT files.key, colour, pronouns, timezone, preferences
+            FROM user_profiles
+            LEFT JOIN files
+                ON files.hash=user_profiles.avatar
+            WHERE user_id=$1;";
+        let rows = self.client().await?.query(statement, &[&user_id]).await?;
+
+        // Users who haven't set anything yet don't have a row
+        Ok(match rows.get(0) {
+            Some(row) => {
+                let key: Option<String> = row.get(0);
+                let preferences: String = row.get(4);
+                Profile {
+                    username,
+                    avatar: key.map(|key| self.storage.url(&key)),
+                    settings: ProfileSettings {
+                        colour: row.get(1),
+                        pronouns: row.get(2),
+                        timezone: row.get(3),
+                        preferences: serde_json::from_str(&preferences).unwrap_or_default(),
+                    },
+                }
+            }
+            None => Profile {
+                username,
+                avatar: None,
+                settings: ProfileSettings::default(),
+            },
+        })
+    }
+
+    async fn set_profile(
+        &self,
+        user_token: &str,
+        settings: &ProfileSettings,
+    ) -> Result<(), DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let statement = "
+            INSERT INTO user_profiles (user_id, colour, pronouns, timezone, preferences)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (user_id) DO UPDATE
+            SET colour=EXCLUDED.colour, pronouns=EXCLUDED.pronouns, timezone=EXCLUDED.timezone,
+                preferences=EXCLUDED.preferences;";
+        self.client()
+            .await?
+            .execute(
+                statement,
+                &[
+                    &user_id,
+                    &settings.colour,
+                    &settings.pronouns,
+                    &settings.timezone,
+                    &settings.preferences.to_string(),
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn set_avatar(&self, user_token: &str, file: &StoredFile) -> Result<(), DbError> {
+        let (user_id, username) = self.get_account(user_token).await?;
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        let used = Self::lock_usage(&tx, user_id).await?;
+        Self::acquire_file(&tx, file).await?;
+
+        let statement = "
+            SELECT avatar
+            FROM user_profiles
+            WHERE user_id=$1
+            FOR UPDATE;";
+        let rows = tx.query(statement, &[&user_id]).await?;
+        let old_hash: Option<String> = rows.get(0).and_then(|row| row.get(0));
+
+        let statement = "
+            INSERT INTO user_profiles (user_id, avatar)
+            VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE
+            SET avatar=EXCLUDED.avatar;";
+        tx.execute(statement, &[&user_id, &file.hash]).await?;
+
+        if let Some(old_hash) = &old_hash {
+            let statement = "
+                UPDATE files
+                SET refs = refs - 1
+                WHERE hash=$1;";
+            tx.execute(statement, &[old_hash]).await?;
+        }
+        Self::check_quota(&tx, user_id, used).await?;
+        write_file_data(self.storage(), file).await?;
+        tx.commit().await?;
+        info!(
+            "set avatar for user #{} ({}) to {}",
+            user_id, username, file.key
+        );
+
+        match old_hash {
+            Some(old_hash) => self.remove_unreferenced_file(&old_hash).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn set_nickname(
+        &self,
+        user_token: &str,
+        nickname: &str,
+    ) -> Result<(UserId, String), DbError> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        let tag = Self::allocate_tag(&tx, nickname).await?;
+        let statement = "
+            UPDATE user_accounts
+            SET nickname=$2, tag=$3
+            WHERE token=$1 AND timeout>$4 AND NOT disabled
+            RETURNING id;";
+        let rows = tx
+            .query(statement, &[&user_token, &nickname, &tag, &timestamp()?])
+            .await?;
+        let user_id: UserId = rows.get(0).ok_or(DbError::Auth)?.get(0);
+        tx.commit().await?;
+        info!("user #{} changed nickname", user_id);
+        Ok((user_id, format!("{}#{}", nickname, tag)))
+    }
+
+    async fn create_game(&self, user_token: &str, name: &str) -> Result<String, DbError> {
+        let (user_id, username) = self.get_account(user_token).await?;
+        let game_token = create_game_token()?;
+
+        let statement = "
+            INSERT INTO games (host, token, name)
+            VALUES ($1, $2, $3)
+            RETURNING id;";
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        let row = tx
+            .query_one(statement, &[&user_id, &game_token, &name])
+            .await?;
+        let game_id: i32 = row.get(0);
+
+        let statement = "
+            INSERT INTO user_games (user_id, game_id)
+            VALUES ($1, $2);";
+        tx.execute(statement, &[&user_id, &game_id]).await?;
+        tx.commit().await?;
+
+        info!(
+            "created game {} ({}) for user #{} ({})",
+            game_token, name, user_id, username
+        );
+        Ok(game_token)
+    }
+
+    async fn join_game(&self, user_token: &str, game_token: &str) -> Result<(), DbError> {
+        let (user_id, username) = self.get_account(user_token).await?;
+        let game_id = self.get_game(game_token).await?;
+        let statement = "
+            INSERT INTO user_games(user_id, game_id)
+            VALUES ($1, $2)";
+        self.client()
+            .await?
+            .execute(statement, &[&user_id, &game_id])
+            .await?;
+        info!(
+            "user #{} ({}) joined game {}",
+            user_id, username, game_token
+        );
+        Ok(())
+    }
+
+    async fn get_hosted_games(&self, user_token: &str) -> Result<Vec<Game>, DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let statement = "
+            SELECT token, name
+            FROM games
+            WHERE host=$1;";
+        let rows = self.client().await?.query(statement, &[&user_id]).await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| Game {
+                token: row.get(0),
+                name: row.get(1),
+            })
+            .collect())
+    }
+
+    async fn get_joined_games(&self, user_token: &str) -> Result<Vec<Game>, DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let statement = "
+            SELECT games.token, games.name
+            FROM games
+            INNER JOIN user_games
+                ON user_games.user_id=$1
+                    AND games.id=user_games.game_id;";
+        let rows = self.client().await?.query(statement, &[&user_id]).await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| Game {
+                token: row.get(0),
+                name: row.get(1),
+            })
+            .collect())
+    }
+
+    async fn get_usage(&self, user_token: &str) -> Result<(u64, u64), DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let client = self.client().await?;
+        let used = Self::get_user_usage(&*client, user_id).await?;
+        Ok((used, CONFIG.user_quota_mb))
+    }
+
+    async fn create_obj(
+        &self,
+        user_token: &str,
+        name: &str,
+        file: &StoredFile,
+    ) -> Result<(), DbError> {
+        let (user_id, username) = self.get_account(user_token).await?;
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        let used = Self::lock_usage(&tx, user_id).await?;
+        Self::acquire_file(&tx, file).await?;
+
+        let statement = "
+            INSERT INTO objects (owner, name, file)
+            VALUES ($1, $2, $3);";
+
+        // Dropping the transaction without committing releases the file again, before anything
+        // is written
+        match tx.execute(statement, &[&user_id, &name, &file.hash]).await {
+            Ok(_) => {
+                Self::check_quota(&tx, user_id, used).await?;
+                write_file_data(self.storage(), file).await?;
+                tx.commit().await?;
+                info!(
+                    "added object \"{}\" to user #{} ({}) at {}",
+                    name, user_id, username, file.key
+                );
+                Ok(())
+            }
+            Err(e) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => Err(DbError::AlreadyExists),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn rename_obj(
+        &self,
+        user_token: &str,
+        name: &str,
+        new_name: &str,
+    ) -> Result<(), DbError> {
+        let (user_id, username) = self.get_account(user_token).await?;
+        let statement = "
+            UPDATE objects
+            SET name=$3
+            WHERE owner=$1 AND name=$2
+            RETURNING id;";
+
+        match self
+            .client()
+            .await?
+            .query(statement, &[&user_id, &name, &new_name])
+            .await
+        {
+            Ok(rows) if rows.len() > 0 => {
+                info!(
+                    "renamed object \"{}\" to \"{}\" for user #{} ({})",
+                    name, new_name, user_id, username
+                );
+                Ok(())
+            }
+            Ok(_) => Err(DbError::Auth),
+            Err(e) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => Err(DbError::AlreadyExists),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn replace_obj(
+        &self,
+        user_token: &str,
+        name: &str,
+        file: &StoredFile,
+    ) -> Result<(), DbError> {
+        let (user_id, username) = self.get_account(user_token).await?;
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        let used = Self::lock_usage(&tx, user_id).await?;
+        Self::acquire_file(&tx, file).await?;
+
+        let statement = "
+            UPDATE objects
+            SET file=$3
+            FROM (
+                SELECT id, file AS old_file
+                FROM objects
+                WHERE owner=$1 AND name=$2
+            ) old
+            WHERE objects.id=old.id
+            RETURNING old.old_file;";
+        let rows = tx.query(statement, &[&user_id, &name, &file.hash]).await?;
+        let old_hash: String = rows.get(0).ok_or(DbError::Auth)?.get(0);
+
+        let statement = "
+            UPDATE files
+            SET refs = refs - 1
+            WHERE hash=$1;";
+        tx.execute(statement, &[&old_hash]).await?;
+        Self::check_quota(&tx, user_id, used).await?;
+        write_file_data(self.storage(), file).await?;
+        tx.commit().await?;
+        info!(
+            "replaced image of object \"{}\" for user #{} ({}) with {}",
+            name, user_id, username, file.key
+        );
+
+        self.remove_unreferenced_file(&old_hash).await
+    }
+
+    async fn get_owned_objs(
+        &self,
+        user_token: &str,
+        filter: &ObjFilter,
+    ) -> Result<Vec<Object>, DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let statement = format!(
+            "
+            SELECT {}
+            FROM objects
+            INNER JOIN files
+                ON files.hash=objects.file
+            WHERE owner=$1
+                AND ($2::integer IS NULL OR objects.folder=$2)
+                AND ($3::text IS NULL OR EXISTS (
+                    SELECT 1
+                    FROM object_tags
+                    WHERE object_id=objects.id AND tag=$3
+                ))
+                AND ($4::text IS NULL OR strpos(lower(objects.name), lower($4)) > 0);",
+            OBJECT_COLUMNS
+        );
+        let rows = self
+            .client()
+            .await?
+            .query(
+                statement.as_str(),
+                &[&user_id, &filter.folder, &filter.tag, &filter.search],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| object_from_row(self.storage(), row))
+            .collect())
+    }
+
+    async fn create_folder(&self, user_token: &str, name: &str) -> Result<i32, DbError> {
+        let (user_id, username) = self.get_account(user_token).await?;
+        let statement = "
+            INSERT INTO folders (owner, name)
+            VALUES ($1, $2)
+            RETURNING id;";
+
+        match self
+            .client()
+            .await?
+            .query_one(statement, &[&user_id, &name])
+            .await
+        {
+            Ok(row) => {
+                info!(
+                    "created folder \"{}\" for user #{} ({})",
+                    name, user_id, username
+                );
+                Ok(row.get(0))
+            }
+            Err(e) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => Err(DbError::AlreadyExists),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_folders(&self, user_token: &str) -> Result<Vec<Folder>, DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let statement = "
+            SELECT id, name
+            FROM folders
+            WHERE owner=$1
+            ORDER BY name;";
+        let rows = self.client().await?.query(statement, &[&user_id]).await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| Folder {
+                id: row.get(0),
+                name: row.get(1),
+            })
+            .collect())
+    }
+
+    async fn move_obj(
+        &self,
+        user_token: &str,
+        name: &str,
+        folder: Option<i32>,
+    ) -> Result<(), DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let statement = "
+            UPDATE objects
+            SET folder=$3
+            WHERE owner=$1 AND name=$2
+                AND ($3::integer IS NULL OR EXISTS (
+                    SELECT 1
+                    FROM folders
+                    WHERE id=$3 AND owner=$1
+                ))
+            RETURNING id;";
+        let rows = self
+            .client()
+            .await?
+            .query(statement, &[&user_id, &name, &folder])
+            .await?;
+        if rows.len() > 0 {
+            Ok(())
+        } else {
+            Err(DbError::Auth)
+        }
+    }
+
+    async fn set_tags(&self, user_token: &str, name: &str, tags: &[String]) -> Result<(), DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let statement = "
+            SELECT id
+            FROM objects
+            WHERE owner=$1 AND name=$2;";
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        let rows = tx.query(statement, &[&user_id, &name]).await?;
+        let obj_id: i32 = rows.get(0).ok_or(DbError::Auth)?.get(0);
+
+        let statement = "
+            DELETE FROM object_tags
+            WHERE object_id=$1;";
+        tx.execute(statement, &[&obj_id]).await?;
+
+        let statement = "
+            INSERT INTO object_tags (object_id, tag)
+            SELECT $1, unnest($2::text[])
+            ON CONFLICT DO NOTHING;";
+        tx.execute(statement, &[&obj_id, &tags]).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_obj(&self, user_token: &str, name: &str) -> Result<String, DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let statement = "
+            SELECT files.key
+            FROM objects
+            INNER JOIN files
+                ON files.hash=objects.file
+            WHERE owner=$1 AND name=$2;";
+        let rows = self
+            .client()
+            .await?
+            .query(statement, &[&user_id, &name])
+            .await?;
+        if rows.len() > 0 {
+            let data = rows.get(0).ok_or(DbError::Auth)?.get(0);
+            Ok(data)
+        } else {
+            Err(DbError::Auth)
+        }
+    }
+
+    async fn delete_obj(&self, user_token: &str, name: &str) -> Result<i32, DbError> {
+        let (user_id, username) = self.get_account(user_token).await?;
+
+        // Delete the object and drop its reference to the file in a single statement, so the
+        // reference count can't get out of sync
+        let statement = "
+            WITH deleted AS (
+                DELETE FROM objects
+                WHERE owner=$1 AND name=$2
+                RETURNING id, file
+            ), released AS (
+                UPDATE files
+                SET refs = files.refs - 1
+                FROM deleted
+                WHERE files.hash=deleted.file
+            )
+            SELECT id, file
+            FROM deleted;";
+        let rows = self
+            .client()
+            .await?
+            .query(statement, &[&user_id, &name])
+            .await?;
+        let row = rows.get(0).ok_or(DbError::Auth)?;
+        let obj_id: i32 = row.get(0);
+        let hash: String = row.get(1);
+        info!(
+            "deleted object \"{}\" from user #{} ({})",
+            name, user_id, username
+        );
+
+        // Delete from storage if this was the last reference
+        self.remove_unreferenced_file(&hash).await?;
+        Ok(obj_id)
+    }
+
+    async fn get_other_objs(
+        &self,
+        user_token: &str,
+        other_id: i32,
+    ) -> Result<Vec<Object>, DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        // Check if this user is in a game with the other ID
+        let statement = "
+            SELECT COUNT(1)
+            FROM games
+            INNER JOIN user_games
+                ON user_games.user_id=$1
+                AND user_games.game_id=games.id
+            WHERE host=$2;";
+        let row = self
+            .client()
+            .await?
+            .query_one(statement, &[&user_id, &other_id])
+            .await?;
+        let count: i64 = row.get(0);
+
+        if count > 0 {
+            let statement = format!(
+                "
+                SELECT {}
+                FROM objects
+                INNER JOIN files
+                    ON files.hash=objects.file
+                WHERE owner=$1;",
+                OBJECT_COLUMNS
+            );
+            let rows = self
+                .client()
+                .await?
+                .query(statement.as_str(), &[&other_id])
+                .await?;
+
+            Ok(rows
+                .iter()
+                .map(|row| object_from_row(self.storage(), row))
+                .collect())
+        } else {
+            Err(DbError::Auth)
+        }
+    }
+
+    async fn share(
+        &self,
+        user_token: &str,
+        item: &ShareItem,
+        with: &ShareWith,
+    ) -> Result<(), DbError> {
+        let (user_id, username) = self.get_account(user_token).await?;
+        let [object_id, folder_id, target_user, game_id] =
+            self.resolve_share(user_id, item, with).await?;
+
+        let statement = "
+            INSERT INTO shares (owner, object_id, folder_id, user_id, game_id)
+            SELECT $1, $2, $3, $4, $5
+            WHERE NOT EXISTS (
+                SELECT 1
+                FROM shares
+                WHERE owner=$1
+                    AND object_id IS NOT DISTINCT FROM $2
+                    AND folder_id IS NOT DISTINCT FROM $3
+                    AND user_id IS NOT DISTINCT FROM $4
+                    AND game_id IS NOT DISTINCT FROM $5
+            );";
+        self.client()
+            .await?
+            .execute(
+                statement,
+                &[&user_id, &object_id, &folder_id, &target_user, &game_id],
+            )
+            .await?;
+        info!(
+            "user #{} ({}) shared {:?} with {:?}",
+            user_id, username, item, with
+        );
+        Ok(())
+    }
+
+    async fn unshare(
+        &self,
+        user_token: &str,
+        item: &ShareItem,
+        with: &ShareWith,
+    ) -> Result<(), DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let [object_id, folder_id, target_user, game_id] =
+            self.resolve_share(user_id, item, with).await?;
+
+        let statement = "
+            DELETE FROM shares
+            WHERE owner=$1
+                AND object_id IS NOT DISTINCT FROM $2
+                AND folder_id IS NOT DISTINCT FROM $3
+                AND user_id IS NOT DISTINCT FROM $4
+                AND game_id IS NOT DISTINCT FROM $5
+            RETURNING id;";
+        let rows = self
+            .client()
+            .await?
+            .query(
+                statement,
+                &[&user_id, &object_id, &folder_id, &target_user, &game_id],
+            )
+            .await?;
+        if rows.len() > 0 {
+            Ok(())
+        } else {
+            Err(DbError::Auth)
+        }
+    }
+
+    async fn get_shared_objs(&self, user_token: &str) -> Result<Vec<Object>, DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let statement = format!(
+            "
+            SELECT {}
+            FROM objects
+            INNER JOIN files
+                ON files.hash=objects.file
+            WHERE objects.owner<>$1
+                AND EXISTS (
+                    SELECT 1
+                    FROM shares
+                    WHERE shares.owner=objects.owner
+                        AND (shares.object_id=objects.id OR shares.folder_id=objects.folder)
+                        AND (shares.user_id=$1 OR shares.game_id IN (
+                            SELECT game_id
+                            FROM user_games
+                            WHERE user_id=$1
+                        ))
+                );",
+            OBJECT_COLUMNS
+        );
+        let rows = self
+            .client()
+            .await?
+            .query(statement.as_str(), &[&user_id])
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| object_from_row(self.storage(), row))
+            .collect())
+    }
+
+    async fn check_game_permissions(
+        &self,
+        user_token: &str,
+        game_token: &str,
+    ) -> Result<GamePermission, DbError> {
+        let statement = "
+            SELECT id, host
+            FROM games
+            WHERE token=$1;";
+        let client = self.client().await?;
+        let statement = client.prepare_cached(statement).await?;
+        let row = client.query_one(&statement, &[&game_token]).await?;
+        let game_id: i32 = row.get(0);
+        let host: i32 = row.get(1);
+
+        let statement = "
+            SELECT COUNT(1), user_id
+            FROM user_games
+            INNER JOIN user_accounts
+                ON user_accounts.token=$2
+                AND user_games.user_id=user_accounts.id
+                AND user_games.game_id=$1
+            GROUP BY user_id;";
+        let statement = client.prepare_cached(statement).await?;
+        let row = client
+            .query_one(&statement, &[&game_id, &user_token])
+            .await?;
+        let count: i64 = row.get(0);
+        let user: i32 = row.get(1);
+
+        Ok(if user == host {
+            GamePermission::Host
+        } else if count > 0 {
+            GamePermission::Player
+        } else {
+            GamePermission::None
+        })
+    }
+
+    async fn save_game_state(&self, game_token: &str, state: &str) -> Result<(), DbError> {
+        let game_id = self.get_game(game_token).await?;
+        let statement = "
+            INSERT INTO game_states (game_id, state)
+            VALUES ($1, $2)
+            ON CONFLICT (game_id) DO UPDATE
+            SET state=EXCLUDED.state;";
+        self.client()
+            .await?
+            .execute(statement, &[&game_id, &state])
+            .await?;
+        Ok(())
+    }
+
+    async fn load_game_state(&self, game_token: &str) -> Result<Option<String>, DbError> {
+        let statement = "
+            SELECT state
+            FROM game_states
+            INNER JOIN games
+                ON games.id=game_states.game_id
+            WHERE games.token=$1;";
+        let rows = self
+            .client()
+            .await?
+            .query(statement, &[&game_token])
+            .await?;
+        Ok(rows.get(0).map(|row| row.get(0)))
+    }
+
+    async fn update_game_states(
+        &self,
+        containing: &str,
+        update: &(dyn Fn(&str) -> Option<String> + Send + Sync),
+    ) -> Result<usize, DbError> {
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        // Locked so a server saving one of these states at the same time waits for us
+        let statement = "
+            SELECT game_id, state
+            FROM game_states
+            WHERE strpos(state, $1) > 0
+            FOR UPDATE;";
+        let rows = tx.query(statement, &[&containing]).await?;
+
+        let mut updated = 0;
+        for row in rows {
+            let game_id: GameId = row.get(0);
+            let state: &str = row.get(1);
+            if let Some(state) = update(state) {
+                let statement = "
+                    UPDATE game_states
+                    SET state=$2
+                    WHERE game_id=$1;";
+                tx.execute(statement, &[&game_id, &state]).await?;
+                updated += 1;
+            }
+        }
+        tx.commit().await?;
+        Ok(updated)
+    }
+
+    async fn check_token(&self, user_token: &str) -> Result<bool, DbError> {
+        let statement = "
+            SELECT COUNT(1)
+            FROM user_accounts
+            WHERE token=$1 AND NOT disabled;";
+        let client = self.client().await?;
+        let statement = client.prepare_cached(statement).await?;
+        let row = client.query_one(&statement, &[&user_token]).await?;
+        let count: i64 = row.get(0);
+
+        Ok(count > 0)
+    }
+
+    async fn check_admin(&self, user_token: &str) -> Result<UserId, DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let statement = "
+            SELECT admin
+            FROM user_accounts
+            WHERE id=$1;";
+        let row = self
+            .client()
+            .await?
+            .query_one(statement, &[&user_id])
+            .await?;
+        let admin: bool = row.get(0);
+        if admin {
+            Ok(user_id)
+        } else {
+            Err(DbError::Forbidden)
+        }
+    }
+
+    async fn set_admin(&self, email: &str, admin: bool) -> Result<(), DbError> {
+        let statement = "
+            UPDATE user_accounts
+            SET admin=$2
+            WHERE email=$1;";
+        let updated = self
+            .client()
+            .await?
+            .execute(statement, &[&email, &admin])
+            .await?;
+        if updated == 0 {
+            return Err(DbError::Auth);
+        }
+        info!("set admin for {} to {}", email, admin);
+        Ok(())
+    }
+
+    async fn list_users(&self, search: Option<&str>) -> Result<Vec<Account>, DbError> {
+        let statement = "
+            SELECT id, email, nickname, tag, admin, disabled
+            FROM user_accounts
+            WHERE $1::text IS NULL
+                OR strpos(lower(email), lower($1)) > 0
+                OR strpos(lower(nickname || '#' || tag), lower($1)) > 0
+            ORDER BY id;";
+        let rows = self.client().await?.query(statement, &[&search]).await?;
+        Ok(rows.iter().map(account_from_row).collect())
+    }
+
+    async fn set_disabled(&self, user_id: UserId, disabled: bool) -> Result<(), DbError> {
+        let statement = "
+            UPDATE user_accounts
+            SET disabled=$2
+            WHERE id=$1;";
+        let updated = self
+            .client()
+            .await?
+            .execute(statement, &[&user_id, &disabled])
+            .await?;
+        if updated == 0 {
+            return Err(DbError::Auth);
+        }
+        info!("set disabled for user #{} to {}", user_id, disabled);
+        Ok(())
+    }
+
+    async fn list_games(&self) -> Result<Vec<GameDetails>, DbError> {
+        let statement = "
+            SELECT games.token, games.name, user_accounts.nickname, user_accounts.tag,
+                (SELECT COUNT(1) FROM user_games WHERE game_id=games.id)
+            FROM games
+            INNER JOIN user_accounts
+                ON user_accounts.id=games.host
+            ORDER BY games.id;";
+        let rows = self.client().await?.query(statement, &[]).await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                let nickname: String = row.get(2);
+                let tag: String = row.get(3);
+                GameDetails {
+                    token: row.get(0),
+                    name: row.get(1),
+                    host: format!("{}#{}", nickname, tag),
+                    players: row.get(4),
+                }
+            })
+            .collect())
+    }
+
+    async fn get_file_keys(&self) -> Result<Vec<String>, DbError> {
+        let statement = "
+            SELECT key
+            FROM files;";
+        let rows = self.client().await?.query(statement, &[]).await?;
+        Ok(rows.into_iter().map(|row| row.get(0)).collect())
+    }
+
+    async fn get_unsized_files(&self) -> Result<Vec<StoredFile>, DbError> {
+        let statement = "
+            SELECT hash, key, width, height
+            FROM files
+            WHERE size=0;";
+        let rows = self.client().await?.query(statement, &[]).await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| StoredFile {
+                hash: row.get(0),
+                key: row.get(1),
+                width: row.get(2),
+                height: row.get(3),
+                size: 0,
+                image: None,
+            })
+            .collect())
+    }
+
+    async fn set_file_details(&self, file: &StoredFile) -> Result<(), DbError> {
+        let statement = "
+            UPDATE files
+            SET width=$2, height=$3, size=$4
+            WHERE hash=$1;";
+        self.client()
+            .await?
+            .execute(
+                statement,
+                &[&file.hash, &file.width, &file.height, &file.size],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn set_password(&self, email: &str, password: &str) -> Result<(), DbError> {
+        let pw_hash = hash_password(password)?;
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        let statement = "
+            UPDATE identities
+            SET pw_hash=$2
+            WHERE email=$1
+            RETURNING user_id;";
+        let row = tx
+            .query_opt(statement, &[&email, &pw_hash])
+            .await?
+            .ok_or(DbError::Auth)?;
+        let user_id: UserId = row.get(0);
+
+        // Whoever had the old password shouldn't stay signed in
+        let statement = "
+            UPDATE user_accounts
+            SET timeout=0
+            WHERE id=$1;";
+        tx.execute(statement, &[&user_id]).await?;
+        tx.commit().await?;
+        info!("reset password for user #{}", user_id);
+        Ok(())
+    }
+
+    async fn get_game_members(&self, game_token: &str) -> Result<Vec<Account>, DbError> {
+        let game_id = self.get_game(game_token).await?;
+        let statement = "
+            SELECT user_accounts.id, email, nickname, tag, admin, disabled
+            FROM user_accounts
+            INNER JOIN user_games
+                ON user_games.user_id=user_accounts.id
+            WHERE user_games.game_id=$1
+            ORDER BY user_accounts.id;";
+        let rows = self.client().await?.query(statement, &[&game_id]).await?;
+        Ok(rows.iter().map(account_from_row).collect())
+    }
+
+    async fn export_game(&self, game_token: &str) -> Result<GameExport, DbError> {
+        let client = self.client().await?;
+        let statement = "
+            SELECT games.id, games.name, user_accounts.email
+            FROM games
+            INNER JOIN user_accounts
+                ON user_accounts.id=games.host
+            WHERE games.token=$1;";
+        let row = client
+            .query_opt(statement, &[&game_token])
+            .await?
+            .ok_or(DbError::Auth)?;
+        let game_id: GameId = row.get(0);
+        let name: String = row.get(1);
+        let host: String = row.get(2);
+
+        let statement = "
+            SELECT user_accounts.email
+            FROM user_games
+            INNER JOIN user_accounts
+                ON user_accounts.id=user_games.user_id
+            WHERE user_games.game_id=$1 AND user_accounts.email<>$2
+            ORDER BY user_accounts.id;";
+        let rows = client.query(statement, &[&game_id, &host]).await?;
+        let players: Vec<String> = rows.iter().map(|row| row.get(0)).collect();
+
+        let statement = "
+            SELECT state
+            FROM game_states
+            WHERE game_id=$1;";
+        let state: Option<String> = client
+            .query_opt(statement, &[&game_id])
+            .await?
+            .map(|row| row.get(0));
+
+        Ok(GameExport {
+            name,
+            host,
+            players,
+            state,
+        })
+    }
+
+    async fn import_game(&self, game: &GameExport) -> Result<String, DbError> {
+        let game_token = create_game_token()?;
+        let mut client = self.client().await?;
+        let tx = client.transaction().await?;
+        let statement = "
+            SELECT id
+            FROM user_accounts
+            WHERE email=$1;";
+        let row = tx
+            .query_opt(statement, &[&game.host])
+            .await?
+            .ok_or(DbError::Auth)?;
+        let host: UserId = row.get(0);
+
+        let statement = "
+            INSERT INTO games (host, token, name)
+            VALUES ($1, $2, $3)
+            RETURNING id;";
+        let row = tx
+            .query_one(statement, &[&host, &game_token, &game.name])
+            .await?;
+        let game_id: GameId = row.get(0);
+
+        let statement = "
+            INSERT INTO user_games (user_id, game_id)
+            SELECT id, $2
+            FROM user_accounts
+            WHERE email=$1
+            ON CONFLICT DO NOTHING;";
+        for email in std::iter::once(&game.host).chain(&game.players) {
+            if tx.execute(statement, &[email, &game_id]).await? == 0 {
+                warn!(
+                    "left {} out of game {}, as they have no account",
+                    email, game_token
+                );
+            }
+        }
+
+        if let Some(state) = &game.state {
+            let statement = "
+                INSERT INTO game_states (game_id, state)
+                VALUES ($1, $2);";
+            tx.execute(statement, &[&game_id, state]).await?;
+        }
+        tx.commit().await?;
+        info!(
+            "imported game {} ({}) for {}",
+            game_token, game.name, game.host
+        );
+        Ok(game_token)
+    }
+}
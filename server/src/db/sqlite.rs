@@ -0,0 +1,1599 @@
+use super::*;
+use rusqlite::{ffi, params, Connection, OptionalExtension};
+use std::sync::{Mutex, MutexGuard};
+use tokio::sync::Mutex as AsyncMutex;
+
+/// Columns to select for `object_from_row`. Tags come back as a JSON array.
+const OBJECT_COLUMNS: &str = "
+    objects.id, objects.name, files.key, files.width, files.height, objects.folder,
+    (SELECT json_group_array(tag) FROM (
+        SELECT tag FROM object_tags WHERE object_id=objects.id ORDER BY tag
+    ))";
+
+fn object_from_row(storage: &dyn Storage, row: &rusqlite::Row) -> rusqlite::Result<Object> {
+    let key: String = row.get(2)?;
+    let tags: String = row.get(6)?;
+    Ok(Object {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        url: storage.url(&key),
+        width: row.get(3)?,
+        height: row.get(4)?,
+        thumbnails: thumbnails(storage, &key),
+        folder: row.get(5)?,
+        tags: serde_json::from_str(&tags).unwrap_or_default(),
+    })
+}
+
+/// Reads columns `id, email, nickname, tag, admin, disabled` of `user_accounts`.
+fn account_from_row(row: &rusqlite::Row) -> rusqlite::Result<Account> {
+    let nickname: String = row.get(2)?;
+    let tag: String = row.get(3)?;
+    Ok(Account {
+        id: row.get(0)?,
+        email: row.get(1)?,
+        username: format!("{}#{}", nickname, tag),
+        admin: row.get(4)?,
+        disabled: row.get(5)?,
+    })
+}
+
+fn is_unique_violation(e: &rusqlite::Error) -> bool {
+    matches!(
+        e,
+        rusqlite::Error::SqliteFailure(e, _) if e.extended_code == ffi::SQLITE_CONSTRAINT_UNIQUE
+    )
+}
+
+/// Columns that databases created by older versions may be missing, in the order they were
+/// added, as a table and a column definition. A database at schema version `n` has the first
+/// `n`; new databases start at the latest version.
+const MIGRATIONS: [(&str, &str); 4] = [
+    ("files", "size bigint NOT NULL DEFAULT 0"),
+    (
+        "objects",
+        "folder integer REFERENCES folders(id) ON DELETE SET NULL",
+    ),
+    ("user_accounts", "admin boolean NOT NULL DEFAULT false"),
+    ("user_accounts", "disabled boolean NOT NULL DEFAULT false"),
+];
+
+fn table_exists(conn: &Connection, table: &str) -> Result<bool, DbError> {
+    let statement = "
+        SELECT 1
+        FROM sqlite_master
+        WHERE type='table' AND name=?1;";
+    let found = conn
+        .query_row(statement, params![table], |_| Ok(()))
+        .optional()?;
+    Ok(found.is_some())
+}
+
+fn column_exists(conn: &Connection, table: &str, column: &str) -> Result<bool, DbError> {
+    let statement = "
+        SELECT 1
+        FROM pragma_table_info(?1)
+        WHERE name=?2;";
+    let found = conn
+        .query_row(statement, params![table, column], |_| Ok(()))
+        .optional()?;
+    Ok(found.is_some())
+}
+
+/// Applies whichever of `MIGRATIONS` the database hasn't had yet. Databases without a recorded
+/// version are taken to be new if `fresh` is set, and otherwise to predate every migration.
+fn migrate(conn: &mut Connection, fresh: bool) -> Result<(), DbError> {
+    let tx = conn.transaction()?;
+    let recorded: Option<i64> = tx
+        .query_row("SELECT version FROM schema_version;", [], |row| row.get(0))
+        .optional()?;
+    let start = match recorded {
+        Some(version) => version as usize,
+        None if fresh => MIGRATIONS.len(),
+        None => 0,
+    };
+
+    for &(table, definition) in MIGRATIONS.iter().skip(start) {
+        let column = definition.split(' ').next().unwrap_or_default();
+        if !column_exists(&tx, table, column)? {
+            info!("adding column {}.{}", table, column);
+            tx.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {};", table, definition))?;
+        }
+    }
+
+    let version = MIGRATIONS.len().max(start);
+    tx.execute("DELETE FROM schema_version;", [])?;
+    tx.execute(
+        "INSERT INTO schema_version (version) VALUES (?1);",
+        params![version as i64],
+    )?;
+    tx.commit()?;
+    if start < version {
+        info!(
+            "migrated database schema from version {} to {}",
+            start, version
+        );
+    }
+    Ok(())
+}
+
+/// Keeps everything in a single SQLite file, for installs too small to need a database server.
+pub struct SqliteDb {
+    conn: Mutex<Connection>,
+    storage: Arc<dyn Storage>,
+    /// Held while writing or removing stored files, so one can't undo the other halfway.
+    files_lock: AsyncMutex<()>,
+}
+
+impl SqliteDb {
+    pub fn new(path: &str, storage: Arc<dyn Storage>) -> Result<Self, DbError> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        info!("opened SQLite database at {}", path);
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+            storage,
+            files_lock: AsyncMutex::new(()),
+        })
+    }
+
+    /// Locks the connection. Queries are local and quick, so they run inline rather than on a
+    /// blocking thread. The guard must be dropped before any `.await`.
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
+
+    fn allocate_tag(conn: &Connection, nickname: &str) -> Result<String, DbError> {
+        let statement = "
+            SELECT tag
+            FROM user_accounts
+            WHERE nickname=?1;";
+        let used = conn
+            .prepare(statement)?
+            .query_map(params![nickname], |row| row.get(0))?
+            .collect::<Result<HashSet<String>, _>>()?;
+        allocate_user_tag(&used)
+    }
+
+    fn get_game(conn: &Connection, game_token: &str) -> Result<GameId, DbError> {
+        let statement = "
+            SELECT id
+            FROM games
+            WHERE token=?1;";
+        conn.query_row(statement, params![game_token], |row| row.get(0))
+            .optional()?
+            .ok_or(DbError::Auth)
+    }
+
+    /// Bytes stored for a user's objects and avatar.
+    fn get_user_usage(conn: &Connection, user_id: UserId) -> Result<u64, DbError> {
+        // Identical files are only counted once per user
+        let statement = "
+            SELECT COALESCE(SUM(size), 0)
+            FROM files
+            WHERE hash IN (
+                SELECT file
+                FROM objects
+                WHERE owner=?1
+                UNION
+                SELECT avatar
+                FROM user_profiles
+                WHERE user_id=?1
+            );";
+        let used: i64 = conn.query_row(statement, params![user_id], |row| row.get(0))?;
+        Ok(used as u64)
+    }
+
+    /// Fails if the changes made in `conn` took the user over their quota. Changes that don't add
+    /// to their usage, like reusing a file they already have, are always allowed.
+    fn check_quota(conn: &Connection, user_id: UserId, used: u64) -> Result<(), DbError> {
+        let now_used = Self::get_user_usage(conn, user_id)?;
+        if now_used > used && now_used > CONFIG.user_quota_mb {
+            Err(DbError::QuotaExceeded)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Takes a reference to a stored file. Identical uploads share a file, so this just bumps
+    /// the count if it's already recorded.
+    fn acquire_file(conn: &Connection, file: &StoredFile) -> Result<(), DbError> {
+        let statement = "
+            INSERT INTO files (hash, key, width, height, size, refs)
+            VALUES (?1, ?2, ?3, ?4, ?5, 1)
+            ON CONFLICT (hash) DO UPDATE
+            SET refs = refs + 1;";
+        conn.execute(
+            statement,
+            params![file.hash, file.key, file.width, file.height, file.size],
+        )?;
+        Ok(())
+    }
+
+    /// Runs `update`, which takes a reference to `file` in a transaction, with the file's data
+    /// stored. The connection can't be held while writing, so the data goes first, and is removed
+    /// again if the update fails and nothing else refers to the file. `files_lock` is held
+    /// throughout, so `remove_unreferenced_file` can't get in between.
+    async fn with_file_data<T: Send>(
+        &self,
+        file: &StoredFile,
+        update: impl FnOnce(&mut Connection) -> Result<T, DbError> + Send,
+    ) -> Result<T, DbError> {
+        let _lock = self.files_lock.lock().await;
+        let written = write_file_data(self.storage(), file).await?;
+        let result = update(&mut self.conn());
+        if result.is_err() && written {
+            let statement = "
+                SELECT COUNT(1)
+                FROM files
+                WHERE hash=?1;";
+            let count: i64 = self
+                .conn()
+                .query_row(statement, params![file.hash], |row| row.get(0))?;
+            if count == 0 {
+                remove_file_data(self.storage(), &file.key).await;
+            }
+        }
+        result
+    }
+
+    /// Removes a stored file if nothing refers to it any more. The database row goes first, so
+    /// failing to remove the data only leaves an orphaned file behind.
+    async fn remove_unreferenced_file(&self, hash: &str) -> Result<(), DbError> {
+        let _lock = self.files_lock.lock().await;
+        let statement = "
+            DELETE FROM files
+            WHERE hash=?1 AND refs <= 0
+            RETURNING key;";
+        let key: Option<String> = self
+            .conn()
+            .query_row(statement, params![hash], |row| row.get(0))
+            .optional()?;
+        if let Some(key) = key {
+            remove_file_data(self.storage(), &key).await;
+        }
+        Ok(())
+    }
+
+    fn get_objs(
+        &self,
+        statement: &str,
+        params: impl rusqlite::Params,
+    ) -> Result<Vec<Object>, DbError> {
+        let conn = self.conn();
+        let mut statement = conn.prepare(statement)?;
+        let objs = statement
+            .query_map(params, |row| object_from_row(self.storage(), row))?
+            .collect::<Result<_, _>>()?;
+        Ok(objs)
+    }
+
+    /// Resolves a share to the ids stored in the `shares` table, checking the sharer owns the
+    /// item and is in the game they are sharing with.
+    fn resolve_share(
+        conn: &Connection,
+        user_id: UserId,
+        item: &ShareItem,
+        with: &ShareWith,
+    ) -> Result<[Option<i32>; 4], DbError> {
+        let (object_id, folder_id) = match item {
+            ShareItem::Object(name) => {
+                let statement = "
+                    SELECT id
+                    FROM objects
+                    WHERE owner=?1 AND name=?2;";
+                let id: i32 = conn
+                    .query_row(statement, params![user_id, name], |row| row.get(0))
+                    .optional()?
+                    .ok_or(DbError::Auth)?;
+                (Some(id), None)
+            }
+            ShareItem::Folder(id) => {
+                let statement = "
+                    SELECT id
+                    FROM folders
+                    WHERE owner=?1 AND id=?2;";
+                let id: i32 = conn
+                    .query_row(statement, params![user_id, id], |row| row.get(0))
+                    .optional()?
+                    .ok_or(DbError::Auth)?;
+                (None, Some(id))
+            }
+        };
+
+        let (target_user, game_id) = match with {
+            ShareWith::User(username) => {
+                let (nickname, tag) = username.rsplit_once('#').ok_or(DbError::InvalidUsername)?;
+                let statement = "
+                    SELECT id
+                    FROM user_accounts
+                    WHERE nickname=?1 AND tag=?2;";
+                let id: UserId = conn
+                    .query_row(statement, params![nickname, tag], |row| row.get(0))
+                    .optional()?
+                    .ok_or(DbError::Auth)?;
+                (Some(id), None)
+            }
+            ShareWith::Game(game_token) => {
+                let game_id = Self::get_game(conn, game_token)?;
+                let statement = "
+                    SELECT COUNT(1)
+                    FROM user_games
+                    WHERE user_id=?1 AND game_id=?2;";
+                let count: i64 =
+                    conn.query_row(statement, params![user_id, game_id], |row| row.get(0))?;
+                if count == 0 {
+                    return Err(DbError::Auth);
+                }
+                (None, Some(game_id))
+            }
+        };
+
+        Ok([object_id, folder_id, target_user, game_id])
+    }
+}
+
+#[rocket::async_trait]
+impl Database for SqliteDb {
+    fn storage(&self) -> &dyn Storage {
+        self.storage.as_ref()
+    }
+
+    async fn clear_tables(&self) -> Result<(), DbError> {
+        // Children first, so foreign keys don't get in the way
+        self.conn().execute_batch(
+            "
+            DROP TABLE IF EXISTS shares;
+            DROP TABLE IF EXISTS object_tags;
+            DROP TABLE IF EXISTS objects;
+            DROP TABLE IF EXISTS folders;
+            DROP TABLE IF EXISTS user_profiles;
+            DROP TABLE IF EXISTS files;
+            DROP TABLE IF EXISTS game_states;
+            DROP TABLE IF EXISTS user_games;
+            DROP TABLE IF EXISTS games;
+            DROP TABLE IF EXISTS unconfirmed_identities;
+            DROP TABLE IF EXISTS identities;
+            DROP TABLE IF EXISTS user_accounts;
+            DROP TABLE IF EXISTS schema_version;",
+        )?;
+        Ok(())
+    }
+
+    async fn create_tables(&self) -> Result<(), DbError> {
+        let mut conn = self.conn();
+        let fresh = !table_exists(&conn, "user_accounts")?;
+        conn.execute_batch(
+            "
+            CREATE TABLE IF NOT EXISTS schema_version(
+                version integer NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS user_accounts(
+                id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                email       text UNIQUE NOT NULL,
+                token       text NOT NULL,
+                nickname    text NOT NULL,
+                tag         text NOT NULL,
+                timeout     bigint NOT NULL,
+                admin       boolean NOT NULL DEFAULT false,
+                disabled    boolean NOT NULL DEFAULT false,
+                CONSTRAINT unique_user_name UNIQUE(nickname, tag)
+            );
+            CREATE TABLE IF NOT EXISTS identities(
+                id      INTEGER PRIMARY KEY AUTOINCREMENT,
+                email   text UNIQUE NOT NULL,
+                pw_hash text NOT NULL,
+                user_id integer NOT NULL,
+                FOREIGN KEY (user_id) REFERENCES user_accounts(id) ON DELETE CASCADE
+            );
+            CREATE TABLE IF NOT EXISTS unconfirmed_identities(
+                id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                email       text UNIQUE NOT NULL,
+                pw_hash     text NOT NULL,
+                nickname    text NOT NULL,
+                token       text NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS games(
+                id      INTEGER PRIMARY KEY AUTOINCREMENT,
+                host    integer NOT NULL,
+                token   text UNIQUE NOT NULL,
+                name    text NOT NULL,
+                FOREIGN KEY (host) REFERENCES user_accounts(id) ON DELETE CASCADE
+            );
+            CREATE TABLE IF NOT EXISTS user_games(
+                user_id integer NOT NULL,
+                game_id integer NOT NULL,
+                PRIMARY KEY (user_id, game_id),
+                FOREIGN KEY (user_id) REFERENCES user_accounts(id)   ON DELETE CASCADE,
+                FOREIGN KEY (game_id) REFERENCES games(id)           ON DELETE CASCADE
+            );
+            CREATE TABLE IF NOT EXISTS files(
+                hash    text PRIMARY KEY,
+                key     text NOT NULL,
+                width   integer NOT NULL,
+                height  integer NOT NULL,
+                size    bigint NOT NULL,
+                refs    integer NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS user_profiles(
+                user_id     integer PRIMARY KEY,
+                avatar      text,
+                colour      text,
+                pronouns    text,
+                timezone    text,
+                preferences text NOT NULL DEFAULT 'null',
+                FOREIGN KEY (user_id) REFERENCES user_accounts(id) ON DELETE CASCADE,
+                FOREIGN KEY (avatar)  REFERENCES files(hash)
+            );
+            CREATE TABLE IF NOT EXISTS game_states(
+                game_id integer PRIMARY KEY,
+                state   text NOT NULL,
+                FOREIGN KEY (game_id) REFERENCES games(id) ON DELETE CASCADE
+            );
+            CREATE TABLE IF NOT EXISTS folders(
+                id      INTEGER PRIMARY KEY AUTOINCREMENT,
+                owner   integer NOT NULL,
+                name    text NOT NULL,
+                UNIQUE (owner, name),
+                FOREIGN KEY (owner) REFERENCES user_accounts(id) ON DELETE CASCADE
+            );
+            CREATE TABLE IF NOT EXISTS objects(
+                id      INTEGER PRIMARY KEY AUTOINCREMENT,
+                owner   integer NOT NULL,
+                name    text NOT NULL,
+                file    text NOT NULL,
+                folder  integer,
+                UNIQUE (owner, name),
+                FOREIGN KEY (owner)  REFERENCES user_accounts(id) ON DELETE CASCADE,
+                FOREIGN KEY (file)   REFERENCES files(hash),
+                FOREIGN KEY (folder) REFERENCES folders(id)       ON DELETE SET NULL
+            );
+            CREATE TABLE IF NOT EXISTS object_tags(
+                object_id   integer NOT NULL,
+                tag         text NOT NULL,
+                PRIMARY KEY (object_id, tag),
+                FOREIGN KEY (object_id) REFERENCES objects(id) ON DELETE CASCADE
+            );
+            CREATE TABLE IF NOT EXISTS shares(
+                id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                owner       integer NOT NULL,
+                object_id   integer,
+                folder_id   integer,
+                user_id     integer,
+                game_id     integer,
+                CHECK ((object_id IS NULL) <> (folder_id IS NULL)),
+                CHECK ((user_id IS NULL) <> (game_id IS NULL)),
+                FOREIGN KEY (owner)     REFERENCES user_accounts(id) ON DELETE CASCADE,
+                FOREIGN KEY (object_id) REFERENCES objects(id)       ON DELETE CASCADE,
+                FOREIGN KEY (folder_id) REFERENCES folders(id)       ON DELETE CASCADE,
+                FOREIGN KEY (user_id)   REFERENCES user_accounts(id) ON DELETE CASCADE,
+                FOREIGN KEY (game_id)   REFERENCES games(id)         ON DELETE CASCADE
+            );",
+        )?;
+        migrate(&mut conn, fresh)?;
+        drop(conn);
+
+        complete_legacy_files(self).await?;
+        create_debug_users(self).await;
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), DbError> {
+        self.conn().query_row("SELECT 1;", [], |_| Ok(()))?;
+        Ok(())
+    }
+
+    async fn create_user(
+        &self,
+        email: &str,
+        password: &str,
+        nickname: &str,
+    ) -> Result<String, DbError> {
+        let (token, _) = create_user_token()?;
+        let pw_hash = hash_password(password)?;
+
+        // Create new unconfirmed user
+        let statement = "
+            INSERT INTO unconfirmed_identities(email, pw_hash, token, nickname)
+            VALUES(?1, ?2, ?3, ?4);";
+        self.conn()
+            .execute(statement, params![email, pw_hash, token, nickname])?;
+        info!("created new unverified user: {}", email);
+
+        Ok(token)
+    }
+
+    async fn confirm_user(&self, email: &str, token: &str) -> Result<String, DbError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+
+        let statement = "
+            DELETE FROM unconfirmed_identities
+            WHERE email=?1 AND token=?2
+            RETURNING pw_hash, nickname;";
+        let (pw_hash, nickname): (String, String) = tx
+            .query_row(statement, params![email, token], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .optional()?
+            .ok_or(DbError::Auth)?;
+        let tag = Self::allocate_tag(&tx, &nickname)?;
+
+        let (token, timeout) = create_user_token()?;
+        let statement = "
+            INSERT INTO user_accounts(email, token, timeout, nickname, tag, admin)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            RETURNING (id);";
+        let admin = CONFIG.admins.iter().any(|admin| admin == email);
+        let user_id: i32 = tx.query_row(
+            statement,
+            params![email, token, timeout, nickname, tag, admin],
+            |row| row.get(0),
+        )?;
+        info!("generated new token for user #{}", user_id);
+
+        let statement = "
+            INSERT INTO identities(email, pw_hash, user_id)
+            VALUES(?1, ?2, ?3);";
+        tx.execute(statement, params![email, pw_hash, user_id])?;
+        tx.commit()?;
+        info!("verified user id #{}: {}", user_id, email);
+        Ok(token)
+    }
+
+    async fn auth_user(&self, email: &str, password: &str) -> Result<(String, String), DbError> {
+        let statement = "
+            SELECT user_id, pw_hash
+            FROM identities
+            WHERE email=?1;";
+        let (user_id, pw_hash): (UserId, String) = self
+            .conn()
+            .query_row(statement, params![email], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .optional()?
+            .ok_or(DbError::Auth)?;
+
+        if verify_password(&pw_hash, password)? {
+            let (token, timeout) = create_user_token()?;
+            info!("generated new token for user #{}", user_id);
+
+            let statement = "
+                UPDATE user_accounts
+                SET token=?2, timeout=?3
+                WHERE id=?1 AND NOT disabled
+                RETURNING nickname, tag;";
+            let (nickname, tag): (String, String) = self
+                .conn()
+                .query_row(statement, params![user_id, token, timeout], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })
+                .optional()?
+                .ok_or(DbError::Auth)?;
+            let username = format!("{}#{}", nickname, tag);
+            Ok((token, username))
+        } else {
+            Err(DbError::Auth)
+        }
+    }
+
+    async fn get_account(&self, token: &str) -> Result<(UserId, String), DbError> {
+        let statement = "
+            SELECT id, timeout, nickname, tag
+            FROM user_accounts
+            WHERE token=?1 AND NOT disabled;";
+        let conn = self.conn();
+        let mut statement = conn.prepare_cached(statement)?;
+        let (user_id, timeout, nickname, tag): (UserId, Timestamp, String, String) = statement
+            .query_row(params![token], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .optional()?
+            .ok_or(DbError::Auth)?;
+
+        let username = format!("{}#{}", nickname, tag);
+        if timestamp()? < timeout {
+            Ok((user_id, username))
+        } else {
+            Err(DbError::Auth)
+        }
+    }
+
+    async fn check_token(&self, user_token: &str) -> Result<bool, DbError> {
+        let statement = "
+            SELECT COUNT(1)
+            FROM user_accounts
+            WHERE token=?1 AND NOT disabled;";
+        let conn = self.conn();
+        let mut statement = conn.prepare_cached(statement)?;
+        let count: i64 = statement.query_row(params![user_token], |row| row.get(0))?;
+
+        Ok(count > 0)
+    }
+
+    async fn get_profile(&self, user_token: &str) -> Result<Profile, DbError> {
+        let (user_id, username) = self.get_account(user_token).await?;
+        let statement = "
+            SELECT files.key, colour, pronouns, timezone, preferences
+            FROM user_profiles
+            LEFT JOIN files
+                ON files.hash=user_profiles.avatar
+            WHERE user_id=?1;";
+        let profile = self
+            .conn()
+            .query_row(statement, params![user_id], |row| {
+                let key: Option<String> = row.get(0)?;
+                let preferences: String = row.get(4)?;
+                Ok(Profile {
+                    username: username.clone(),
+                    avatar: key.map(|key| self.storage.url(&key)),
+                    settings: ProfileSettings {
+                        colour: row.get(1)?,
+                        pronouns: row.get(2)?,
+                        timezone: row.get(3)?,
+                        preferences: serde_json::from_str(&preferences).unwrap_or_default(),
+                    },
+                })
+            })
+            .optional()?;
+
+        // Users who haven't set anything yet don't have a row
+        Ok(profile.unwrap_or(Profile {
+            username,
+            avatar: None,
+            settings: ProfileSettings::default(),
+        }))
+    }
+
+    async fn set_profile(
+        &self,
+        user_token: &str,
+        settings: &ProfileSettings,
+    ) -> Result<(), DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let statement = "
+            INSERT INTO user_profiles (user_id, colour, pronouns, timezone, preferences)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT (user_id) DO UPDATE
+            SET colour=excluded.colour, pronouns=excluded.pronouns, timezone=excluded.timezone,
+                preferences=excluded.preferences;";
+        self.conn().execute(
+            statement,
+            params![
+                user_id,
+                settings.colour,
+                settings.pronouns,
+                settings.timezone,
+                settings.preferences.to_string()
+            ],
+        )?;
+        Ok(())
+    }
+
+    async fn set_avatar(&self, user_token: &str, file: &StoredFile) -> Result<(), DbError> {
+        let (user_id, username) = self.get_account(user_token).await?;
+        let old_hash = self
+            .with_file_data(file, |conn| {
+                let tx = conn.transaction()?;
+                let used = Self::get_user_usage(&tx, user_id)?;
+                Self::acquire_file(&tx, file)?;
+
+                let statement = "
+                    SELECT avatar
+                    FROM user_profiles
+                    WHERE user_id=?1;";
+                let old_hash: Option<String> = tx
+                    .query_row(statement, params![user_id], |row| row.get(0))
+                    .optional()?
+                    .flatten();
+
+                let statement = "
+                    INSERT INTO user_profiles (user_id, avatar)
+                    VALUES (?1, ?2)
+                    ON CONFLICT (user_id) DO UPDATE
+                    SET avatar=excluded.avatar;";
+                tx.execute(statement, params![user_id, file.hash])?;
+
+                if let Some(old_hash) = &old_hash {
+                    let statement = "
+                        UPDATE files
+                        SET refs = refs - 1
+                        WHERE hash=?1;";
+                    tx.execute(statement, params![old_hash])?;
+                }
+                Self::check_quota(&tx, user_id, used)?;
+                tx.commit()?;
+                Ok(old_hash)
+            })
+            .await?;
+        info!(
+            "set avatar for user #{} ({}) to {}",
+            user_id, username, file.key
+        );
+
+        match old_hash {
+            Some(old_hash) => self.remove_unreferenced_file(&old_hash).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn set_nickname(
+        &self,
+        user_token: &str,
+        nickname: &str,
+    ) -> Result<(UserId, String), DbError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let tag = Self::allocate_tag(&tx, nickname)?;
+        let statement = "
+            UPDATE user_accounts
+            SET nickname=?2, tag=?3
+            WHERE token=?1 AND timeout>?4 AND NOT disabled
+            RETURNING id;";
+        let user_id: UserId = tx
+            .query_row(
+                statement,
+                params![user_token, nickname, tag, timestamp()?],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or(DbError::Auth)?;
+        tx.commit()?;
+        info!("user #{} changed nickname", user_id);
+        Ok((user_id, format!("{}#{}", nickname, tag)))
+    }
+
+    async fn create_game(&self, user_token: &str, name: &str) -> Result<String, DbError> {
+        let (user_id, username) = self.get_account(user_token).await?;
+        let game_token = create_game_token()?;
+
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let statement = "
+            INSERT INTO games (host, token, name)
+            VALUES (?1, ?2, ?3)
+            RETURNING id;";
+        let game_id: i32 = tx.query_row(statement, params![user_id, game_token, name], |row| {
+            row.get(0)
+        })?;
+
+        let statement = "
+            INSERT INTO user_games (user_id, game_id)
+            VALUES (?1, ?2);";
+        tx.execute(statement, params![user_id, game_id])?;
+        tx.commit()?;
+
+        info!(
+            "created game {} ({}) for user #{} ({})",
+            game_token, name, user_id, username
+        );
+        Ok(game_token)
+    }
+
+    async fn join_game(&self, user_token: &str, game_token: &str) -> Result<(), DbError> {
+        let (user_id, username) = self.get_account(user_token).await?;
+        let conn = self.conn();
+        let game_id = Self::get_game(&conn, game_token)?;
+        let statement = "
+            INSERT INTO user_games(user_id, game_id)
+            VALUES (?1, ?2)";
+        conn.execute(statement, params![user_id, game_id])?;
+        info!(
+            "user #{} ({}) joined game {}",
+            user_id, username, game_token
+        );
+        Ok(())
+    }
+
+    async fn get_hosted_games(&self, user_token: &str) -> Result<Vec<Game>, DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let statement = "
+            SELECT token, name
+            FROM games
+            WHERE host=?1;";
+        let conn = self.conn();
+        let mut statement = conn.prepare(statement)?;
+        let games = statement
+            .query_map(params![user_id], |row| {
+                Ok(Game {
+                    token: row.get(0)?,
+                    name: row.get(1)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(games)
+    }
+
+    async fn get_joined_games(&self, user_token: &str) -> Result<Vec<Game>, DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let statement = "
+            SELECT games.token, games.name
+            FROM games
+            INNER JOIN user_games
+                ON user_games.user_id=?1
+                    AND games.id=user_games.game_id;";
+        let conn = self.conn();
+        let mut statement = conn.prepare(statement)?;
+        let games = statement
+            .query_map(params![user_id], |row| {
+                Ok(Game {
+                    token: row.get(0)?,
+                    name: row.get(1)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(games)
+    }
+
+    async fn check_game_permissions(
+        &self,
+        user_token: &str,
+        game_token: &str,
+    ) -> Result<GamePermission, DbError> {
+        let statement = "
+            SELECT id, host
+            FROM games
+            WHERE token=?1;";
+        let conn = self.conn();
+        let (game_id, host): (i32, i32) = conn
+            .prepare_cached(statement)?
+            .query_row(params![game_token], |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        let statement = "
+            SELECT COUNT(1), user_id
+            FROM user_games
+            INNER JOIN user_accounts
+                ON user_accounts.token=?2
+                AND user_games.user_id=user_accounts.id
+                AND user_games.game_id=?1
+            GROUP BY user_id;";
+        let (count, user): (i64, i32) = conn
+            .prepare_cached(statement)?
+            .query_row(params![game_id, user_token], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?;
+
+        Ok(if user == host {
+            GamePermission::Host
+        } else if count > 0 {
+            GamePermission::Player
+        } else {
+            GamePermission::None
+        })
+    }
+
+    async fn save_game_state(&self, game_token: &str, state: &str) -> Result<(), DbError> {
+        let conn = self.conn();
+        let game_id = Self::get_game(&conn, game_token)?;
+        let statement = "
+            INSERT INTO game_states (game_id, state)
+            VALUES (?1, ?2)
+            ON CONFLICT (game_id) DO UPDATE
+            SET state=excluded.state;";
+        conn.execute(statement, params![game_id, state])?;
+        Ok(())
+    }
+
+    async fn load_game_state(&self, game_token: &str) -> Result<Option<String>, DbError> {
+        let statement = "
+            SELECT state
+            FROM game_states
+            INNER JOIN games
+                ON games.id=game_states.game_id
+            WHERE games.token=?1;";
+        Ok(self
+            .conn()
+            .query_row(statement, params![game_token], |row| row.get(0))
+            .optional()?)
+    }
+
+    async fn update_game_states(
+        &self,
+        containing: &str,
+        update: &(dyn Fn(&str) -> Option<String> + Send + Sync),
+    ) -> Result<usize, DbError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let statement = "
+            SELECT game_id, state
+            FROM game_states
+            WHERE instr(state, ?1) > 0;";
+        let mut statement = tx.prepare(statement)?;
+        let states: Vec<(GameId, String)> = statement
+            .query_map(params![containing], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        drop(statement);
+
+        let mut updated = 0;
+        for (game_id, state) in states {
+            if let Some(state) = update(&state) {
+                let statement = "
+                    UPDATE game_states
+                    SET state=?2
+                    WHERE game_id=?1;";
+                tx.execute(statement, params![game_id, state])?;
+                updated += 1;
+            }
+        }
+        tx.commit()?;
+        Ok(updated)
+    }
+
+    async fn get_usage(&self, user_token: &str) -> Result<(u64, u64), DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let used = Self::get_user_usage(&self.conn(), user_id)?;
+        Ok((used, CONFIG.user_quota_mb))
+    }
+
+    async fn create_obj(
+        &self,
+        user_token: &str,
+        name: &str,
+        file: &StoredFile,
+    ) -> Result<(), DbError> {
+        let (user_id, username) = self.get_account(user_token).await?;
+        self.with_file_data(file, |conn| {
+            let tx = conn.transaction()?;
+            let used = Self::get_user_usage(&tx, user_id)?;
+            Self::acquire_file(&tx, file)?;
+
+            let statement = "
+                INSERT INTO objects (owner, name, file)
+                VALUES (?1, ?2, ?3);";
+
+            // Dropping the transaction without committing releases the file again
+            match tx.execute(statement, params![user_id, name, file.hash]) {
+                Ok(_) => {
+                    Self::check_quota(&tx, user_id, used)?;
+                    Ok(tx.commit()?)
+                }
+                Err(e) if is_unique_violation(&e) => Err(DbError::AlreadyExists),
+                Err(e) => Err(e.into()),
+            }
+        })
+        .await?;
+        info!(
+            "added object \"{}\" to user #{} ({}) at {}",
+            name, user_id, username, file.key
+        );
+        Ok(())
+    }
+
+    async fn rename_obj(
+        &self,
+        user_token: &str,
+        name: &str,
+        new_name: &str,
+    ) -> Result<(), DbError> {
+        let (user_id, username) = self.get_account(user_token).await?;
+        let statement = "
+            UPDATE objects
+            SET name=?3
+            WHERE owner=?1 AND name=?2;";
+
+        match self
+            .conn()
+            .execute(statement, params![user_id, name, new_name])
+        {
+            Ok(count) if count > 0 => {
+                info!(
+                    "renamed object \"{}\" to \"{}\" for user #{} ({})",
+                    name, new_name, user_id, username
+                );
+                Ok(())
+            }
+            Ok(_) => Err(DbError::Auth),
+            Err(e) if is_unique_violation(&e) => Err(DbError::AlreadyExists),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn replace_obj(
+        &self,
+        user_token: &str,
+        name: &str,
+        file: &StoredFile,
+    ) -> Result<(), DbError> {
+        let (user_id, username) = self.get_account(user_token).await?;
+        let old_hash = self
+            .with_file_data(file, |conn| {
+                let tx = conn.transaction()?;
+                let used = Self::get_user_usage(&tx, user_id)?;
+                Self::acquire_file(&tx, file)?;
+
+                let statement = "
+                    SELECT file
+                    FROM objects
+                    WHERE owner=?1 AND name=?2;";
+                let old_hash: String = tx
+                    .query_row(statement, params![user_id, name], |row| row.get(0))
+                    .optional()?
+                    .ok_or(DbError::Auth)?;
+
+                let statement = "
+                    UPDATE objects
+                    SET file=?3
+                    WHERE owner=?1 AND name=?2;";
+                tx.execute(statement, params![user_id, name, file.hash])?;
+
+                let statement = "
+                    UPDATE files
+                    SET refs = refs - 1
+                    WHERE hash=?1;";
+                tx.execute(statement, params![old_hash])?;
+                Self::check_quota(&tx, user_id, used)?;
+                tx.commit()?;
+                Ok(old_hash)
+            })
+            .await?;
+        info!(
+            "replaced image of object \"{}\" for user #{} ({}) with {}",
+            name, user_id, username, file.key
+        );
+
+        self.remove_unreferenced_file(&old_hash).await
+    }
+
+    async fn get_owned_objs(
+        &self,
+        user_token: &str,
+        filter: &ObjFilter,
+    ) -> Result<Vec<Object>, DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let statement = format!(
+            "
+            SELECT {}
+            FROM objects
+            INNER JOIN files
+                ON files.hash=objects.file
+            WHERE owner=?1
+                AND (?2 IS NULL OR objects.folder=?2)
+                AND (?3 IS NULL OR EXISTS (
+                    SELECT 1
+                    FROM object_tags
+                    WHERE object_id=objects.id AND tag=?3
+                ))
+                AND (?4 IS NULL OR instr(lower(objects.name), lower(?4)) > 0);",
+            OBJECT_COLUMNS
+        );
+        self.get_objs(
+            &statement,
+            params![user_id, filter.folder, filter.tag, filter.search],
+        )
+    }
+
+    async fn get_obj(&self, user_token: &str, name: &str) -> Result<String, DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let statement = "
+            SELECT files.key
+            FROM objects
+            INNER JOIN files
+                ON files.hash=objects.file
+            WHERE owner=?1 AND name=?2;";
+        self.conn()
+            .query_row(statement, params![user_id, name], |row| row.get(0))
+            .optional()?
+            .ok_or(DbError::Auth)
+    }
+
+    async fn delete_obj(&self, user_token: &str, name: &str) -> Result<i32, DbError> {
+        let (user_id, username) = self.get_account(user_token).await?;
+
+        // Delete the object and drop its reference to the file together, so the reference
+        // count can't get out of sync
+        let (obj_id, hash) = {
+            let mut conn = self.conn();
+            let tx = conn.transaction()?;
+            let statement = "
+                DELETE FROM objects
+                WHERE owner=?1 AND name=?2
+                RETURNING id, file;";
+            let (obj_id, hash): (i32, String) = tx
+                .query_row(statement, params![user_id, name], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })
+                .optional()?
+                .ok_or(DbError::Auth)?;
+
+            let statement = "
+                UPDATE files
+                SET refs = refs - 1
+                WHERE hash=?1;";
+            tx.execute(statement, params![hash])?;
+            tx.commit()?;
+            (obj_id, hash)
+        };
+        info!(
+            "deleted object \"{}\" from user #{} ({})",
+            name, user_id, username
+        );
+
+        // Delete from storage if this was the last reference
+        self.remove_unreferenced_file(&hash).await?;
+        Ok(obj_id)
+    }
+
+    async fn get_other_objs(
+        &self,
+        user_token: &str,
+        other_id: i32,
+    ) -> Result<Vec<Object>, DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        // Check if this user is in a game with the other ID
+        let statement = "
+            SELECT COUNT(1)
+            FROM games
+            INNER JOIN user_games
+                ON user_games.user_id=?1
+                AND user_games.game_id=games.id
+            WHERE host=?2;";
+        let count: i64 = self
+            .conn()
+            .query_row(statement, params![user_id, other_id], |row| row.get(0))?;
+
+        if count > 0 {
+            let statement = format!(
+                "
+                SELECT {}
+                FROM objects
+                INNER JOIN files
+                    ON files.hash=objects.file
+                WHERE owner=?1;",
+                OBJECT_COLUMNS
+            );
+            self.get_objs(&statement, params![other_id])
+        } else {
+            Err(DbError::Auth)
+        }
+    }
+
+    async fn create_folder(&self, user_token: &str, name: &str) -> Result<i32, DbError> {
+        let (user_id, username) = self.get_account(user_token).await?;
+        let statement = "
+            INSERT INTO folders (owner, name)
+            VALUES (?1, ?2)
+            RETURNING id;";
+
+        match self
+            .conn()
+            .query_row(statement, params![user_id, name], |row| row.get(0))
+        {
+            Ok(id) => {
+                info!(
+                    "created folder \"{}\" for user #{} ({})",
+                    name, user_id, username
+                );
+                Ok(id)
+            }
+            Err(e) if is_unique_violation(&e) => Err(DbError::AlreadyExists),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_folders(&self, user_token: &str) -> Result<Vec<Folder>, DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let statement = "
+            SELECT id, name
+            FROM folders
+            WHERE owner=?1
+            ORDER BY name;";
+        let conn = self.conn();
+        let mut statement = conn.prepare(statement)?;
+        let folders = statement
+            .query_map(params![user_id], |row| {
+                Ok(Folder {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(folders)
+    }
+
+    async fn move_obj(
+        &self,
+        user_token: &str,
+        name: &str,
+        folder: Option<i32>,
+    ) -> Result<(), DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let statement = "
+            UPDATE objects
+            SET folder=?3
+            WHERE owner=?1 AND name=?2
+                AND (?3 IS NULL OR EXISTS (
+                    SELECT 1
+                    FROM folders
+                    WHERE id=?3 AND owner=?1
+                ));";
+        let count = self
+            .conn()
+            .execute(statement, params![user_id, name, folder])?;
+        if count > 0 {
+            Ok(())
+        } else {
+            Err(DbError::Auth)
+        }
+    }
+
+    async fn set_tags(&self, user_token: &str, name: &str, tags: &[String]) -> Result<(), DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let statement = "
+            SELECT id
+            FROM objects
+            WHERE owner=?1 AND name=?2;";
+        let obj_id: i32 = tx
+            .query_row(statement, params![user_id, name], |row| row.get(0))
+            .optional()?
+            .ok_or(DbError::Auth)?;
+
+        let statement = "
+            DELETE FROM object_tags
+            WHERE object_id=?1;";
+        tx.execute(statement, params![obj_id])?;
+
+        let statement = "
+            INSERT INTO object_tags (object_id, tag)
+            VALUES (?1, ?2)
+            ON CONFLICT DO NOTHING;";
+        for tag in tags {
+            tx.execute(statement, params![obj_id, tag])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn share(
+        &self,
+        user_token: &str,
+        item: &ShareItem,
+        with: &ShareWith,
+    ) -> Result<(), DbError> {
+        let (user_id, username) = self.get_account(user_token).await?;
+        let conn = self.conn();
+        let [object_id, folder_id, target_user, game_id] =
+            Self::resolve_share(&conn, user_id, item, with)?;
+
+        let statement = "
+            INSERT INTO shares (owner, object_id, folder_id, user_id, game_id)
+            SELECT ?1, ?2, ?3, ?4, ?5
+            WHERE NOT EXISTS (
+                SELECT 1
+                FROM shares
+                WHERE owner=?1
+                    AND object_id IS ?2
+                    AND folder_id IS ?3
+                    AND user_id IS ?4
+                    AND game_id IS ?5
+            );";
+        conn.execute(
+            statement,
+            params![user_id, object_id, folder_id, target_user, game_id],
+        )?;
+        info!(
+            "user #{} ({}) shared {:?} with {:?}",
+            user_id, username, item, with
+        );
+        Ok(())
+    }
+
+    async fn unshare(
+        &self,
+        user_token: &str,
+        item: &ShareItem,
+        with: &ShareWith,
+    ) -> Result<(), DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let conn = self.conn();
+        let [object_id, folder_id, target_user, game_id] =
+            Self::resolve_share(&conn, user_id, item, with)?;
+
+        let statement = "
+            DELETE FROM shares
+            WHERE owner=?1
+                AND object_id IS ?2
+                AND folder_id IS ?3
+                AND user_id IS ?4
+                AND game_id IS ?5;";
+        let count = conn.execute(
+            statement,
+            params![user_id, object_id, folder_id, target_user, game_id],
+        )?;
+        if count > 0 {
+            Ok(())
+        } else {
+            Err(DbError::Auth)
+        }
+    }
+
+    async fn get_shared_objs(&self, user_token: &str) -> Result<Vec<Object>, DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let statement = format!(
+            "
+            SELECT {}
+            FROM objects
+            INNER JOIN files
+                ON files.hash=objects.file
+            WHERE objects.owner<>?1
+                AND EXISTS (
+                    SELECT 1
+                    FROM shares
+                    WHERE shares.owner=objects.owner
+                        AND (shares.object_id=objects.id OR shares.folder_id=objects.folder)
+                        AND (shares.user_id=?1 OR shares.game_id IN (
+                            SELECT game_id
+                            FROM user_games
+                            WHERE user_id=?1
+                        ))
+                );",
+            OBJECT_COLUMNS
+        );
+        self.get_objs(&statement, params![user_id])
+    }
+
+    async fn check_admin(&self, user_token: &str) -> Result<UserId, DbError> {
+        let (user_id, _) = self.get_account(user_token).await?;
+        let statement = "
+            SELECT admin
+            FROM user_accounts
+            WHERE id=?1;";
+        let admin: bool = self
+            .conn()
+            .query_row(statement, params![user_id], |row| row.get(0))?;
+        if admin {
+            Ok(user_id)
+        } else {
+            Err(DbError::Forbidden)
+        }
+    }
+
+    async fn set_admin(&self, email: &str, admin: bool) -> Result<(), DbError> {
+        let statement = "
+            UPDATE user_accounts
+            SET admin=?2
+            WHERE email=?1;";
+        if self.conn().execute(statement, params![email, admin])? == 0 {
+            return Err(DbError::Auth);
+        }
+        info!("set admin for {} to {}", email, admin);
+        Ok(())
+    }
+
+    async fn list_users(&self, search: Option<&str>) -> Result<Vec<Account>, DbError> {
+        let statement = "
+            SELECT id, email, nickname, tag, admin, disabled
+            FROM user_accounts
+            WHERE ?1 IS NULL
+                OR instr(lower(email), lower(?1)) > 0
+                OR instr(lower(nickname || '#' || tag), lower(?1)) > 0
+            ORDER BY id;";
+        let conn = self.conn();
+        let mut statement = conn.prepare(statement)?;
+        let users = statement
+            .query_map(params![search], account_from_row)?
+            .collect::<Result<_, _>>()?;
+        Ok(users)
+    }
+
+    async fn set_disabled(&self, user_id: UserId, disabled: bool) -> Result<(), DbError> {
+        let statement = "
+            UPDATE user_accounts
+            SET disabled=?2
+            WHERE id=?1;";
+        if self.conn().execute(statement, params![user_id, disabled])? == 0 {
+            return Err(DbError::Auth);
+        }
+        info!("set disabled for user #{} to {}", user_id, disabled);
+        Ok(())
+    }
+
+    async fn list_games(&self) -> Result<Vec<GameDetails>, DbError> {
+        let statement = "
+            SELECT games.token, games.name, user_accounts.nickname, user_accounts.tag,
+                (SELECT COUNT(1) FROM user_games WHERE game_id=games.id)
+            FROM games
+            INNER JOIN user_accounts
+                ON user_accounts.id=games.host
+            ORDER BY games.id;";
+        let conn = self.conn();
+        let mut statement = conn.prepare(statement)?;
+        let games = statement
+            .query_map([], |row| {
+                let nickname: String = row.get(2)?;
+                let tag: String = row.get(3)?;
+                Ok(GameDetails {
+                    token: row.get(0)?,
+                    name: row.get(1)?,
+                    host: format!("{}#{}", nickname, tag),
+                    players: row.get(4)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(games)
+    }
+
+    async fn get_file_keys(&self) -> Result<Vec<String>, DbError> {
+        let statement = "
+            SELECT key
+            FROM files;";
+        let conn = self.conn();
+        let mut statement = conn.prepare(statement)?;
+        let keys = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(keys)
+    }
+
+    async fn get_unsized_files(&self) -> Result<Vec<StoredFile>, DbError> {
+        let statement = "
+            SELECT hash, key, width, height
+            FROM files
+            WHERE size=0;";
+        let conn = self.conn();
+        let mut statement = conn.prepare(statement)?;
+        let files = statement
+            .query_map([], |row| {
+                Ok(StoredFile {
+                    hash: row.get(0)?,
+                    key: row.get(1)?,
+                    width: row.get(2)?,
+                    height: row.get(3)?,
+                    size: 0,
+                    image: None,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(files)
+    }
+
+    async fn set_file_details(&self, file: &StoredFile) -> Result<(), DbError> {
+        let statement = "
+            UPDATE files
+            SET width=?2, height=?3, size=?4
+            WHERE hash=?1;";
+        self.conn().execute(
+            statement,
+            params![file.hash, file.width, file.height, file.size],
+        )?;
+        Ok(())
+    }
+
+    async fn set_password(&self, email: &str, password: &str) -> Result<(), DbError> {
+        let pw_hash = hash_password(password)?;
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let statement = "
+            UPDATE identities
+            SET pw_hash=?2
+            WHERE email=?1
+            RETURNING user_id;";
+        let user_id: UserId = tx
+            .query_row(statement, params![email, pw_hash], |row| row.get(0))
+            .optional()?
+            .ok_or(DbError::Auth)?;
+
+        // Whoever had the old password shouldn't stay signed in
+        let statement = "
+            UPDATE user_accounts
+            SET timeout=0
+            WHERE id=?1;";
+        tx.execute(statement, params![user_id])?;
+        tx.commit()?;
+        info!("reset password for user #{}", user_id);
+        Ok(())
+    }
+
+    async fn get_game_members(&self, game_token: &str) -> Result<Vec<Account>, DbError> {
+        let conn = self.conn();
+        let game_id = Self::get_game(&conn, game_token)?;
+        let statement = "
+            SELECT user_accounts.id, email, nickname, tag, admin, disabled
+            FROM user_accounts
+            INNER JOIN user_games
+                ON user_games.user_id=user_accounts.id
+            WHERE user_games.game_id=?1
+            ORDER BY user_accounts.id;";
+        let mut statement = conn.prepare(statement)?;
+        let members = statement
+            .query_map(params![game_id], account_from_row)?
+            .collect::<Result<_, _>>()?;
+        Ok(members)
+    }
+
+    async fn export_game(&self, game_token: &str) -> Result<GameExport, DbError> {
+        let conn = self.conn();
+        let statement = "
+            SELECT games.id, games.name, user_accounts.email
+            FROM games
+            INNER JOIN user_accounts
+                ON user_accounts.id=games.host
+            WHERE games.token=?1;";
+        let (game_id, name, host): (GameId, String, String) = conn
+            .query_row(statement, params![game_token], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .optional()?
+            .ok_or(DbError::Auth)?;
+
+        let statement = "
+            SELECT user_accounts.email
+            FROM user_games
+            INNER JOIN user_accounts
+                ON user_accounts.id=user_games.user_id
+            WHERE user_games.game_id=?1 AND user_accounts.email<>?2
+            ORDER BY user_accounts.id;";
+        let players: Vec<String> = conn
+            .prepare(statement)?
+            .query_map(params![game_id, host], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+
+        let statement = "
+            SELECT state
+            FROM game_states
+            WHERE game_id=?1;";
+        let state: Option<String> = conn
+            .query_row(statement, params![game_id], |row| row.get(0))
+            .optional()?;
+
+        Ok(GameExport {
+            name,
+            host,
+            players,
+            state,
+        })
+    }
+
+    async fn import_game(&self, game: &GameExport) -> Result<String, DbError> {
+        let game_token = create_game_token()?;
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let statement = "
+            SELECT id
+            FROM user_accounts
+            WHERE email=?1;";
+        let host: UserId = tx
+            .query_row(statement, params![game.host], |row| row.get(0))
+            .optional()?
+            .ok_or(DbError::Auth)?;
+
+        let statement = "
+            INSERT INTO games (host, token, name)
+            VALUES (?1, ?2, ?3)
+            RETURNING id;";
+        let game_id: GameId =
+            tx.query_row(statement, params![host, game_token, game.name], |row| {
+                row.get(0)
+            })?;
+
+        let statement = "
+            INSERT INTO user_games (user_id, game_id)
+            SELECT id, ?2
+            FROM user_accounts
+            WHERE email=?1
+            ON CONFLICT DO NOTHING;";
+        for email in std::iter::once(&game.host).chain(&game.players) {
+            if tx.execute(statement, params![email, game_id])? == 0 {
+                warn!(
+                    "left {} out of game {}, as they have no account",
+                    email, game_token
+                );
+            }
+        }
+
+        if let Some(state) = &game.state {
+            let statement = "
+                INSERT INTO game_states (game_id, state)
+                VALUES (?1, ?2);";
+            tx.execute(statement, params![game_id, state])?;
+        }
+        tx.commit()?;
+        info!(
+            "imported game {} ({}) for {}",
+            game_token, game.name, game.host
+        );
+        Ok(game_token)
+    }
+}
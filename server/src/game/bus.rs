@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+use crate::config::{BusConfig, CONFIG};
+
+#[derive(Debug)]
+pub enum BusError {
+    Io(std::io::Error),
+    Protocol(String),
+}
+
+impl From<std::io::Error> for BusError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl Display for BusError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for BusError {}
+
+/// Messages published to a channel after subscribing. Dropping it unsubscribes.
+pub type Subscription = mpsc::UnboundedReceiver<String>;
+
+/// Passes messages between RoleCall instances, so that they can host the same games.
+#[rocket::async_trait]
+pub trait Bus: Send + Sync {
+    /// Sends a message to everyone subscribed to a channel, this instance included, returning
+    /// how many subscribers there were. Every subscriber sees a channel's messages in the same
+    /// order.
+    async fn publish(&self, channel: &str, msg: &str) -> Result<usize, BusError>;
+    async fn subscribe(&self, channel: &str) -> Result<Subscription, BusError>;
+}
+
+/// Creates the bus selected in the config.
+pub fn create_bus() -> Arc<dyn Bus> {
+    match &CONFIG.bus {
+        BusConfig::Local => Arc::new(LoopbackBus::new()),
+        BusConfig::Redis { addr } => Arc::new(RedisBus::new(addr)),
+    }
+}
+
+/// Passes messages around within a single process.
+#[derive(Default)]
+pub struct LoopbackBus {
+    // Held while publishing, so that everyone sees the same order
+    channels: Mutex<HashMap<String, Vec<mpsc::UnboundedSender<String>>>>,
+}
+
+impl LoopbackBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[rocket::async_trait]
+impl Bus for LoopbackBus {
+    async fn publish(&self, channel: &str, msg: &str) -> Result<usize, BusError> {
+        let mut channels = self.channels.lock().unwrap();
+        let subscribers = channels.entry(channel.to_string()).or_default();
+        subscribers.retain(|tx| tx.send(msg.to_string()).is_ok());
+        let count = subscribers.len();
+        if count == 0 {
+            channels.remove(channel);
+        }
+        Ok(count)
+    }
+
+    async fn subscribe(&self, channel: &str) -> Result<Subscription, BusError> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.channels
+            .lock()
+            .unwrap()
+            .entry(channel.to_string())
+            .or_default()
+            .push(tx);
+        Ok(rx)
+    }
+}
+
+/// Passes messages through a Redis server, or anything else that speaks its protocol.
+pub struct RedisBus {
+    addr: String,
+    // Opened when first needed, and again after any error
+    conn: tokio::sync::Mutex<Option<BufReader<TcpStream>>>,
+}
+
+impl RedisBus {
+    pub fn new(addr: &str) -> Self {
+        Self {
+            addr: addr.to_string(),
+            conn: tokio::sync::Mutex::new(None),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Bus for RedisBus {
+    async fn publish(&self, channel: &str, msg: &str) -> Result<usize, BusError> {
+        let mut conn = self.conn.lock().await;
+        if conn.is_none() {
+            *conn = Some(BufReader::new(TcpStream::connect(&self.addr).await?));
+        }
+        match command(conn.as_mut().unwrap(), &["PUBLISH", channel, msg]).await {
+            Ok(Resp::Int(count)) => Ok(count.max(0) as usize),
+            Ok(reply) => Err(unexpected(&reply)),
+            Err(e) => {
+                // Who knows what state the connection is in, so start again next time
+                *conn = None;
+                Err(e)
+            }
+        }
+    }
+
+    async fn subscribe(&self, channel: &str) -> Result<Subscription, BusError> {
+        // A subscribed connection can't be used for anything else, so each gets its own
+        let mut stream = BufReader::new(TcpStream::connect(&self.addr).await?);
+        match command(&mut stream, &["SUBSCRIBE", channel]).await? {
+            Resp::Array(reply) if reply.first() == Some(&bulk("subscribe")) => {}
+            reply => return Err(unexpected(&reply)),
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let channel = channel.to_string();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    result = read_resp(&mut stream) => match result {
+                        Ok(Resp::Array(mut reply))
+                            if reply.len() == 3 && reply[0] == bulk("message") =>
+                        {
+                            if let Resp::Bulk(Some(msg)) = reply.pop().unwrap() {
+                                match String::from_utf8(msg) {
+                                    Ok(msg) => {
+                                        if tx.send(msg).is_err() {
+                                            break;
+                                        }
+                                    }
+                                    Err(_) => warn!("non-text message on {}", channel),
+                                }
+                            }
+                        }
+                        Ok(reply) => warn!("unexpected reply on {}: {:?}", channel, reply),
+                        Err(e) => {
+                            warn!("lost subscription to {}: {}", channel, e);
+                            break;
+                        }
+                    },
+                    // Closing the connection unsubscribes
+                    _ = tx.closed() => break,
+                }
+            }
+        });
+        Ok(rx)
+    }
+}
+
+/// A value in the Redis protocol. Simple strings are read as bulk strings, since nothing here
+/// needs to tell them apart.
+#[derive(Debug, PartialEq)]
+enum Resp {
+    Error(String),
+    Int(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Resp>),
+}
+
+fn bulk(s: &str) -> Resp {
+    Resp::Bulk(Some(s.as_bytes().to_vec()))
+}
+
+fn unexpected(reply: &Resp) -> BusError {
+    BusError::Protocol(format!("unexpected reply: {:?}", reply))
+}
+
+/// Encodes a command, which is sent as an array of bulk strings.
+fn encode(args: &[&str]) -> Vec<u8> {
+    let mut data = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        data.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        data.extend_from_slice(arg.as_bytes());
+        data.extend_from_slice(b"\r\n");
+    }
+    data
+}
+
+fn read_resp<R>(reader: &mut R) -> BoxFuture<'_, Result<Resp, BusError>>
+where
+    R: AsyncBufRead + Unpin + Send,
+{
+    async move {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(BusError::Protocol("connection closed".to_string()));
+        }
+        let line = line.trim_end_matches("\r\n");
+        let kind = line
+            .chars()
+            .next()
+            .ok_or_else(|| BusError::Protocol("empty reply".to_string()))?;
+        let rest = &line[kind.len_utf8()..];
+        let parse_len = || {
+            rest.parse::<i64>()
+                .map_err(|_| BusError::Protocol(format!("bad length: {}", rest)))
+        };
+        match kind {
+            '+' => Ok(bulk(rest)),
+            '-' => Ok(Resp::Error(rest.to_string())),
+            ':' => Ok(Resp::Int(parse_len()?)),
+            '$' => {
+                let len = parse_len()?;
+                if len < 0 {
+                    return Ok(Resp::Bulk(None));
+                }
+                // Bulk strings are followed by a line break too
+                let mut data = vec![0; len as usize + 2];
+                reader.read_exact(&mut data).await?;
+                data.truncate(len as usize);
+                Ok(Resp::Bulk(Some(data)))
+            }
+            '*' => {
+                let mut items = Vec::new();
+                for _ in 0..parse_len()? {
+                    items.push(read_resp(reader).await?);
+                }
+                Ok(Resp::Array(items))
+            }
+            _ => Err(BusError::Protocol(format!("bad reply: {}", line))),
+        }
+    }
+    .boxed()
+}
+
+/// Sends a command and waits for its reply.
+async fn command<S>(stream: &mut S, args: &[&str]) -> Result<Resp, BusError>
+where
+    S: AsyncBufRead + AsyncWrite + Unpin + Send,
+{
+    stream.write_all(&encode(args)).await?;
+    stream.flush().await?;
+    match read_resp(stream).await? {
+        Resp::Error(e) => Err(BusError::Protocol(e)),
+        reply => Ok(reply),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{command, encode, read_resp, Bus, LoopbackBus, RedisBus, Resp};
+    use std::collections::HashMap;
+    use std::env;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::io::{AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use tokio::time::timeout;
+
+    /// Checks a pair of buses that share their messages, e.g. two connections to one server.
+    async fn check_bus(bus: &dyn Bus, other: &dyn Bus) {
+        let mut first = bus.subscribe("bus_test").await.unwrap();
+        let mut second = other.subscribe("bus_test").await.unwrap();
+
+        // Everyone sees every message, in order
+        assert_eq!(bus.publish("bus_test", "one").await.unwrap(), 2);
+        assert_eq!(other.publish("bus_test", "two").await.unwrap(), 2);
+        for sub in [&mut first, &mut second].iter_mut() {
+            for &expected in ["one", "two"].iter() {
+                let msg = timeout(Duration::from_secs(5), sub.recv()).await.unwrap();
+                assert_eq!(msg.as_deref(), Some(expected));
+            }
+        }
+
+        // Other channels are separate
+        assert_eq!(bus.publish("bus_test_other", "three").await.unwrap(), 0);
+        assert!(first.try_recv().is_err());
+    }
+
+    /// Just enough of a Redis server to test against, supporting SUBSCRIBE and PUBLISH.
+    async fn redis_stand_in() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let channels: Arc<Mutex<HashMap<String, Vec<mpsc::UnboundedSender<Vec<u8>>>>>> =
+            Default::default();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let channels = channels.clone();
+                let (reader, mut writer) = stream.into_split();
+                let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+                tokio::spawn(async move {
+                    while let Some(data) = rx.recv().await {
+                        if writer.write_all(&data).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+                tokio::spawn(async move {
+                    let mut reader = BufReader::new(reader);
+                    while let Ok(Resp::Array(args)) = read_resp(&mut reader).await {
+                        let args: Vec<_> = args
+                            .into_iter()
+                            .filter_map(|arg| match arg {
+                                Resp::Bulk(Some(data)) => String::from_utf8(data).ok(),
+                                _ => None,
+                            })
+                            .collect();
+                        let args: Vec<_> = args.iter().map(String::as_str).collect();
+                        let reply = match args[..] {
+                            ["SUBSCRIBE", channel] => {
+                                let mut channels = channels.lock().unwrap();
+                                let subscribers = channels.entry(channel.to_string()).or_default();
+                                subscribers.push(tx.clone());
+                                format!(
+                                    "*3\r\n$9\r\nsubscribe\r\n${}\r\n{}\r\n:{}\r\n",
+                                    channel.len(),
+                                    channel,
+                                    subscribers.len()
+                                )
+                                .into_bytes()
+                            }
+                            ["PUBLISH", channel, msg] => {
+                                let mut channels = channels.lock().unwrap();
+                                let subscribers = channels.entry(channel.to_string()).or_default();
+                                let msg = encode(&["message", channel, msg]);
+                                subscribers.retain(|tx| tx.send(msg.clone()).is_ok());
+                                format!(":{}\r\n", subscribers.len()).into_bytes()
+                            }
+                            _ => b"-ERR unknown command\r\n".to_vec(),
+                        };
+                        if tx.send(reply).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_loopback_bus() {
+        let bus = LoopbackBus::new();
+        check_bus(&bus, &bus).await;
+
+        // Dropping a subscription unsubscribes
+        let sub = bus.subscribe("bus_test").await.unwrap();
+        drop(sub);
+        assert_eq!(bus.publish("bus_test", "four").await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_redis_bus() {
+        let addr = redis_stand_in().await;
+        check_bus(&RedisBus::new(&addr), &RedisBus::new(&addr)).await;
+
+        // Errors come back from the server
+        let mut conn = BufReader::new(tokio::net::TcpStream::connect(&addr).await.unwrap());
+        assert!(command(&mut conn, &["NOPE"]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_real_redis_bus() {
+        // Runs against a real server if one is configured, e.g. RC_TEST_REDIS_ADDR=localhost:6379
+        let addr = match env::var("RC_TEST_REDIS_ADDR") {
+            Ok(addr) => addr,
+            Err(_) => return,
+        };
+        check_bus(&RedisBus::new(&addr), &RedisBus::new(&addr)).await;
+    }
+}
@@ -1,19 +1,25 @@
 use std::fmt::{Display, Formatter};
 use std::hash::{Hash, Hasher};
-use std::sync::mpsc::Receiver;
 use std::sync::Arc;
 
 use futures::stream::SplitSink;
 use futures::{SinkExt, StreamExt};
 
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::watch;
 use tokio_tungstenite::{
     accept_async, tungstenite::Error as WsError, tungstenite::Message, WebSocketStream,
 };
+use tracing::{field, Instrument, Span};
 
-use crate::db::{DbManager, GamePermission};
+use crate::config::CONFIG;
+use crate::db::{Database, GamePermission};
+use crate::game::outbox::{coalesce, Outbound};
 use crate::game::protocol::ProtocolMessage;
-use crate::game::server::{connect_to_server, UserInfo};
+use crate::game::server::{Games, UserInfo};
+use crate::logging::redact;
+use crate::metrics;
 
 #[derive(Debug)]
 pub enum GameError {
@@ -21,6 +27,8 @@ pub enum GameError {
     WebSocket(WsError),
     Malformed,
     AlreadyConnected,
+    /// The game's server couldn't reach the bus.
+    Unavailable,
 }
 
 impl From<tokio::io::Error> for GameError {
@@ -56,10 +64,11 @@ impl GameConnection {
         // Check that the first two messages received are the user and game token
         if let Some(Ok(user_token)) = ws.next().await {
             let user_token = user_token.into_text()?.trim().to_string();
-            info!("received user token: {}", user_token);
+            info!("received user token: {}", redact(&user_token));
             if let Some(Ok(game_token)) = ws.next().await {
                 let game_token = game_token.into_text()?.trim().to_string();
-                info!("received game token: {}", game_token);
+                Span::current().record("game", &game_token.as_str());
+                info!("received game token");
                 Ok(Self {
                     ws,
                     user_token,
@@ -75,7 +84,7 @@ impl GameConnection {
         }
     }
 
-    async fn start(mut self, db: Arc<DbManager>) {
+    async fn start(mut self, db: Arc<dyn Database>, games: Arc<Games>) {
         match db
             .check_game_permissions(&self.user_token, &self.game_token)
             .await
@@ -102,40 +111,67 @@ impl GameConnection {
                 // Load user information
                 match db.get_account(&self.user_token).await {
                     Ok((id, username)) => {
+                        Span::current().record("user", &username.as_str());
+                        // The profile is only for display, so connect without it if need be
+                        let (avatar, colour) = match db.get_profile(&self.user_token).await {
+                            Ok(profile) => (profile.avatar, profile.settings.colour),
+                            Err(e) => {
+                                warn!("error retrieving profile: {}", e);
+                                (None, None)
+                            }
+                        };
                         let user = UserInfo {
                             token: self.user_token,
                             username,
                             id,
                             is_host: perm == GamePermission::Host,
+                            avatar,
+                            colour,
                         };
-                        let (tx, rx) = std::sync::mpsc::sync_channel(100);
-                        if let Ok(server) = connect_to_server(user.clone(), self.game_token, tx) {
-                            // Create communication channels and spawn handlers
-                            let (writer, mut reader) = self.ws.split();
-                            std::thread::spawn(move || Self::forward_messages(writer, rx));
-
-                            // Forward received data to the server
-                            info!("verified connection");
-                            while let Some(result) = reader.next().await {
-                                match result {
-                                    Ok(result) => server.recv(result, user.clone()).await,
-                                    Err(e) => warn!("error running connection: {}", e),
+                        let user_id = user.id;
+                        match games.connect(user, self.game_token).await {
+                            Ok((server, rx)) => {
+                                metrics::GAME_CLIENTS.inc();
+                                // Send the server's messages on from a separate task
+                                let (writer, mut reader) = self.ws.split();
+                                let mut writer = tokio::spawn(Self::forward_messages(writer, rx));
+
+                                // Forward received data to the server
+                                info!("verified connection");
+                                loop {
+                                    tokio::select! {
+                                        result = reader.next() => match result {
+                                            Some(Ok(msg)) => server.recv(msg, user_id).await,
+                                            Some(Err(e)) => {
+                                                warn!("error running connection: {}", e)
+                                            }
+                                            None => break,
+                                        },
+                                        // The server dropped us, e.g. for falling behind
+                                        _ = &mut writer => break,
+                                    }
                                 }
+                                server.close_client(user_id).await;
+                                metrics::GAME_CLIENTS.dec();
                             }
-                            server.close_client(user);
-                        } else {
-                            warn!("user already connected");
-                            if let Err(e) = self
-                                .ws
-                                .send(
-                                    ProtocolMessage::FailedConnection {
-                                        reason: "user already connected".to_string(),
-                                    }
-                                    .into_msg(),
-                                )
-                                .await
-                            {
-                                warn!("failed to send error to client: {}", e);
+                            Err(e) => {
+                                let reason = match e {
+                                    GameError::AlreadyConnected => "user already connected",
+                                    _ => "game unavailable",
+                                };
+                                warn!("failed connecting to game: {}", reason);
+                                if let Err(e) = self
+                                    .ws
+                                    .send(
+                                        ProtocolMessage::FailedConnection {
+                                            reason: reason.to_string(),
+                                        }
+                                        .into_msg(),
+                                    )
+                                    .await
+                                {
+                                    warn!("failed to send error to client: {}", e);
+                                }
                             }
                         }
                     }
@@ -147,17 +183,49 @@ impl GameConnection {
         }
     }
 
-    fn forward_messages(
+    async fn forward_messages(
         mut writer: SplitSink<WebSocketStream<TcpStream>, Message>,
-        rx: Receiver<String>,
+        mut rx: Receiver<Outbound>,
     ) {
         // Listen to the receiver and forward any received messages to the websocket
-        while let Ok(msg) = rx.recv() {
-            if let Err(e) = futures::executor::block_on(writer.send(Message::Text(msg))) {
-                warn!("failed writing: {}", e);
+        while let Some(first) = rx.recv().await {
+            // Take whatever else is already queued, so a client that's behind catches up at once
+            let mut msgs = Vec::new();
+            let mut next = Some(first);
+            while let Some(outbound) = next {
+                match outbound {
+                    Outbound::Msg(msg) => msgs.push(msg),
+                    Outbound::Batch(batch) => msgs.extend(batch.into_iter().map(Arc::new)),
+                }
+                next = rx.try_recv().ok();
+            }
+            if CONFIG.outbound.coalesce {
+                msgs = coalesce(msgs);
+            }
+
+            // Give up on clients that stop reading, rather than waiting on them forever
+            let write = async {
+                for msg in msgs {
+                    writer.feed(Message::Text(msg.to_string())).await?;
+                }
+                writer.flush().await
+            };
+            match tokio::time::timeout(CONFIG.outbound.stall_timeout, write).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    warn!("failed writing: {}", e);
+                    return;
+                }
+                Err(_) => {
+                    warn!("client stalled, disconnecting");
+                    return;
+                }
             }
         }
-        warn!("receiver closed");
+        info!("receiver closed");
+        if let Err(e) = writer.close().await {
+            warn!("failed closing connection: {}", e);
+        }
     }
 
     pub fn get_user(&self) -> String {
@@ -176,20 +244,70 @@ impl Hash for GameConnection {
     }
 }
 
-pub async fn ws_listen(db: Arc<DbManager>, addr: &str) -> Result<(), GameError> {
+pub async fn ws_listen(
+    db: Arc<dyn Database>,
+    games: Arc<Games>,
+    addr: &str,
+) -> Result<(), GameError> {
     // info!("Websocket server starting up...");
     let listener = TcpListener::bind(addr).await?;
     info!("Listening at: {}", addr);
-    while let Ok((stream, _)) = listener.accept().await {
-        info!("Received connection from {}", stream.peer_addr()?);
-        tokio::spawn(run_server(db.clone(), stream));
+    ws_serve(db, games, listener).await
+}
+
+/// Accepts game connections on an already-bound listener, e.g. one on an ephemeral port.
+pub async fn ws_serve(
+    db: Arc<dyn Database>,
+    games: Arc<Games>,
+    listener: TcpListener,
+) -> Result<(), GameError> {
+    // Readiness checks look at this, so it has to be cleared however we stop
+    games.set_listening(true);
+    let result = accept_connections(db, games.clone(), listener).await;
+    games.set_listening(false);
+    result
+}
+
+async fn accept_connections(
+    db: Arc<dyn Database>,
+    games: Arc<Games>,
+    listener: TcpListener,
+) -> Result<(), GameError> {
+    let mut closing = games.closing();
+    while !*closing.borrow() {
+        tokio::select! {
+            result = listener.accept() => match result {
+                Ok((stream, addr)) => {
+                    // Filled in as the connection tells us who it is
+                    let span = info_span!(
+                        "conn",
+                        peer = %addr,
+                        game = field::Empty,
+                        user = field::Empty
+                    );
+                    let running = games.closing();
+                    let conn = run_server(db.clone(), games.clone(), stream, running);
+                    tokio::spawn(conn.instrument(span));
+                }
+                Err(_) => break,
+            },
+            _ = closing.changed() => {}
+        }
     }
+    info!("Stopped accepting game connections");
     Ok(())
 }
 
-async fn run_server(db: Arc<DbManager>, stream: TcpStream) {
+/// Runs a connection, holding on to `_running` so that shutting down waits for it.
+async fn run_server(
+    db: Arc<dyn Database>,
+    games: Arc<Games>,
+    stream: TcpStream,
+    _running: watch::Receiver<bool>,
+) {
+    info!("Received connection");
     match GameConnection::new(stream).await {
-        Ok(conn) => conn.start(db).await,
+        Ok(conn) => conn.start(db, games).await,
         Err(e) => warn!("failed to receive client: {}", e),
     }
 }
@@ -1,4 +1,6 @@
+pub mod bus;
 pub mod conn;
-mod server;
-mod protocol;
+pub mod server;
+pub mod protocol;
+mod outbox;
 mod state;
\ No newline at end of file
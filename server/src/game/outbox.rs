@@ -0,0 +1,186 @@
+use std::sync::Arc;
+
+use crate::game::protocol::ProtocolMessage;
+
+/// Something queued for a game client.
+#[derive(Debug)]
+pub enum Outbound {
+    /// A message shared by every client it was broadcast to.
+    Msg(Arc<ProtocolMessage>),
+    /// Messages that must go out together, e.g. the state a client has to catch up on. Only
+    /// takes up one slot in the queue, however long it is.
+    Batch(Vec<ProtocolMessage>),
+}
+
+/// What a message is about, so updates to the same thing can be merged.
+#[derive(PartialEq)]
+enum Target<'a> {
+    Token(&'a str),
+    Obj(&'a str),
+}
+
+fn target(msg: &ProtocolMessage) -> Option<Target> {
+    match msg {
+        ProtocolMessage::PlaceToken(token) => token.id.as_deref().map(Target::Token),
+        ProtocolMessage::DeleteToken { token_id }
+        | ProtocolMessage::MoveToken { token_id, .. }
+        | ProtocolMessage::RenameToken { token_id, .. }
+        | ProtocolMessage::SetController { token_id, .. } => Some(Target::Token(token_id)),
+        ProtocolMessage::PlaceObj(obj) => obj.id.as_deref().map(Target::Obj),
+        ProtocolMessage::DeleteObj { obj_id } | ProtocolMessage::MoveObj { obj_id, .. } => {
+            Some(Target::Obj(obj_id))
+        }
+        _ => None,
+    }
+}
+
+/// Folds `later` into `earlier` if the pair can be sent as a single message.
+fn merge(earlier: &mut ProtocolMessage, later: &ProtocolMessage) -> bool {
+    match later {
+        // Token moves are relative, so they add up. Clients wait for their own moves to come back,
+        // so the merged move carries all of their ids.
+        ProtocolMessage::MoveToken {
+            id: later_id,
+            dx: later_dx,
+            dy: later_dy,
+            merged_ids: later_merged_ids,
+            ..
+        } => {
+            if let ProtocolMessage::MoveToken {
+                id,
+                dx,
+                dy,
+                merged_ids,
+                ..
+            } = earlier
+            {
+                merged_ids.push(std::mem::replace(id, later_id.clone()));
+                merged_ids.extend(later_merged_ids.iter().cloned());
+                *dx = dx.saturating_add(*later_dx);
+                *dy = dy.saturating_add(*later_dy);
+                true
+            } else {
+                false
+            }
+        }
+        // Object moves are absolute, so the last one wins
+        ProtocolMessage::MoveObj { .. } if matches!(earlier, ProtocolMessage::MoveObj { .. }) => {
+            *earlier = later.clone();
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Merges queued updates that later ones make redundant, e.g. a run of moves of the same token
+/// while a client was falling behind. Updates are only merged back as far as the last other
+/// message about the same token or object, so clients still see everything in order.
+pub fn coalesce(msgs: Vec<Arc<ProtocolMessage>>) -> Vec<Arc<ProtocolMessage>> {
+    let mut out: Vec<Arc<ProtocolMessage>> = Vec::with_capacity(msgs.len());
+    'next: for msg in msgs {
+        if let Some(msg_target) = target(&msg) {
+            for earlier in out.iter_mut().rev() {
+                if target(earlier).as_ref() == Some(&msg_target) {
+                    if merge(Arc::make_mut(earlier), &msg) {
+                        continue 'next;
+                    }
+                    break;
+                }
+            }
+        }
+        out.push(msg);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::coalesce;
+    use crate::game::protocol::ProtocolMessage;
+    use std::sync::Arc;
+
+    fn move_token(token_id: &str, dx: i16, dy: i16) -> Arc<ProtocolMessage> {
+        Arc::new(ProtocolMessage::MoveToken {
+            id: format!("{}{}", dx, dy),
+            token_id: token_id.to_string(),
+            dx,
+            dy,
+            merged_ids: Vec::new(),
+        })
+    }
+
+    fn move_obj(obj_id: &str, x: i16) -> Arc<ProtocolMessage> {
+        Arc::new(ProtocolMessage::MoveObj {
+            obj_id: obj_id.to_string(),
+            x,
+            y: 0,
+            w: 1,
+            h: 1,
+        })
+    }
+
+    #[test]
+    fn test_coalesce_moves() {
+        // Moves of the same token add up, and moves of other things are kept separate
+        let msgs = vec![
+            move_token("0", 1, 0),
+            move_obj("0", 5),
+            move_token("1", 1, 1),
+            move_token("0", 0, 2),
+            move_obj("0", 7),
+        ];
+        let expected = vec![
+            Arc::new(ProtocolMessage::MoveToken {
+                id: "02".to_string(),
+                token_id: "0".to_string(),
+                dx: 1,
+                dy: 2,
+                merged_ids: vec!["10".to_string()],
+            }),
+            move_obj("0", 7),
+            move_token("1", 1, 1),
+        ];
+        assert_eq!(coalesce(msgs), expected);
+    }
+
+    #[test]
+    fn test_coalesce_keeps_move_ids() {
+        // Every merged move's id comes back, so clients can clear their pending moves
+        let msgs = vec![
+            move_token("0", 1, 0),
+            move_token("0", 0, 1),
+            move_token("0", 1, 1),
+        ];
+        let merged = coalesce(msgs);
+        assert_eq!(merged.len(), 1);
+        match merged[0].as_ref() {
+            ProtocolMessage::MoveToken { id, merged_ids, .. } => {
+                assert_eq!(id, "11");
+                assert_eq!(merged_ids, &vec!["10".to_string(), "01".to_string()]);
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+
+        // Moves that weren't merged are sent as before
+        let json = serde_json::to_value(move_token("0", 1, 0).as_ref()).unwrap();
+        assert!(json["MoveToken"].get("merged_ids").is_none());
+    }
+
+    #[test]
+    fn test_coalesce_keeps_order() {
+        // A move after a rename can't be merged with one before it
+        let rename = Arc::new(ProtocolMessage::RenameToken {
+            token_id: "0".to_string(),
+            name: Some("goblin".to_string()),
+        });
+        let msgs = vec![move_token("0", 1, 0), rename.clone(), move_token("0", 0, 1)];
+        assert_eq!(coalesce(msgs.clone()), msgs);
+
+        // Neither can moves of a token that was deleted and replaced with the same id
+        let delete = Arc::new(ProtocolMessage::DeleteToken {
+            token_id: "0".to_string(),
+        });
+        let msgs = vec![move_token("0", 1, 0), delete, move_token("0", 0, 1)];
+        assert_eq!(coalesce(msgs.clone()), msgs);
+    }
+}
@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum ProtocolMessage {
     PlaceToken(Token),
     DeleteToken {
@@ -11,6 +11,9 @@ pub enum ProtocolMessage {
         token_id: String,
         dx: i16,
         dy: i16,
+        /// Ids of earlier moves that were merged into this one before sending.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        merged_ids: Vec<String>,
     },
     RenameToken {
         token_id: String,
@@ -35,16 +38,48 @@ pub enum ProtocolMessage {
         username: String,
         host: bool,
         host_id: i32,
+        avatar: Option<String>,
+        colour: Option<String>,
     },
     Disconnect {
         username: String,
     },
+    RenameUser {
+        username: String,
+        new_username: String,
+    },
     FailedConnection {
         reason: String,
     },
+    /// Sent to a client that missed messages, before the full game state is sent again.
+    Resync {},
+    /// Sent to every client before the server goes away.
+    ServerShutdown {
+        reason: String,
+    },
 }
 
 impl ProtocolMessage {
+    /// The message's type, as it appears in the JSON.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProtocolMessage::PlaceToken(_) => "PlaceToken",
+            ProtocolMessage::DeleteToken { .. } => "DeleteToken",
+            ProtocolMessage::MoveToken { .. } => "MoveToken",
+            ProtocolMessage::RenameToken { .. } => "RenameToken",
+            ProtocolMessage::PlaceObj(_) => "PlaceObj",
+            ProtocolMessage::DeleteObj { .. } => "DeleteObj",
+            ProtocolMessage::MoveObj { .. } => "MoveObj",
+            ProtocolMessage::SetController { .. } => "SetController",
+            ProtocolMessage::Connect { .. } => "Connect",
+            ProtocolMessage::Disconnect { .. } => "Disconnect",
+            ProtocolMessage::RenameUser { .. } => "RenameUser",
+            ProtocolMessage::FailedConnection { .. } => "FailedConnection",
+            ProtocolMessage::Resync {} => "Resync",
+            ProtocolMessage::ServerShutdown { .. } => "ServerShutdown",
+        }
+    }
+
     pub(crate) fn to_string(&self) -> String {
         serde_json::to_string(&self).unwrap()
     }
@@ -61,7 +96,7 @@ impl Into<RawMessage> for ProtocolMessage {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Token {
     pub id: Option<String>,
     pub name: Option<String>,
@@ -78,7 +113,7 @@ impl Token {
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct PlacedObj {
     pub id: Option<String>,
     pub obj_id: i32,
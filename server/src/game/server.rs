@@ -1,46 +1,39 @@
 use std::collections::HashMap;
-use std::sync::Arc;
-use std::sync::Mutex;
-
-use crate::game::protocol::ProtocolMessage;
 use std::hash::{Hash, Hasher};
-use std::sync::mpsc::SyncSender;
-use tokio_tungstenite::tungstenite::Message;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use tokio::time::Instant;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot, watch, OnceCell};
+use tokio::time::{timeout, Instant};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::Instrument;
+use uuid::Uuid;
 
-use crate::config::CONFIG;
+use crate::config::{OverflowPolicy, CONFIG};
+use crate::db::Database;
+use crate::game::bus::{Bus, BusError, Subscription};
 use crate::game::conn::GameError;
+use crate::game::outbox::Outbound;
+use crate::game::protocol::ProtocolMessage;
 use crate::game::state::GameState;
+use crate::metrics;
 
-lazy_static! {
-    static ref SERVERS: Mutex<HashMap<String, Arc<Server>>> = {
-        tokio::spawn(server_monitor());
-        Mutex::new(HashMap::new())
-    };
-}
-
-async fn server_monitor() {
-    loop {
-        tokio::time::sleep(CONFIG.monitor_interval).await;
-        let mut servers = SERVERS.lock().unwrap();
-        let mut to_kill = Vec::new();
+/// Commands that can queue up for a game server before senders have to wait.
+const COMMAND_BUFFER: usize = 1024;
+/// How often to check on clients that have fallen behind.
+const LAG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a new server waits for servers on other instances to send it the game's state.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long shutting down waits for connections to pass on the news and close.
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Bus channel for updates that concern every game.
+const UPDATES_CHANNEL: &str = "rolecall:updates";
 
-        for (game_token, server) in servers.iter() {
-            // Option is stored to allow easy replace; should never be empty
-            let keepalive = server.keepalive.lock().unwrap().unwrap();
-            let num_clients = server.clients.pin().len();
-
-            if num_clients == 0 && keepalive.elapsed() > CONFIG.game_timeout {
-                info!("killing game server {}", game_token);
-                to_kill.push(game_token.clone());
-            }
-        }
-
-        for token in to_kill.into_iter() {
-            servers.remove(&token);
-        }
-    }
+fn game_channel(game_token: &str) -> String {
+    format!("rolecall:game:{}", game_token)
 }
 
 #[derive(Clone, Debug, PartialOrd, PartialEq, Ord, Eq)]
@@ -49,6 +42,20 @@ pub struct UserInfo {
     pub username: String,
     pub is_host: bool,
     pub id: i32,
+    pub avatar: Option<String>,
+    pub colour: Option<String>,
+}
+
+impl UserInfo {
+    fn connect_msg(&self, host_id: i32) -> ProtocolMessage {
+        ProtocolMessage::Connect {
+            username: self.username.clone(),
+            host: self.is_host,
+            host_id,
+            avatar: self.avatar.clone(),
+            colour: self.colour.clone(),
+        }
+    }
 }
 
 impl Hash for UserInfo {
@@ -57,91 +64,841 @@ impl Hash for UserInfo {
     }
 }
 
-pub struct Server {
+type ConnectReply = oneshot::Sender<Result<mpsc::Receiver<Outbound>, GameError>>;
+
+enum Command {
+    Connect {
+        user: UserInfo,
+        reply: ConnectReply,
+    },
+    Recv {
+        user_id: i32,
+        msg: ProtocolMessage,
+    },
+    Close {
+        user_id: i32,
+    },
+    RenameUser {
+        user_id: i32,
+        username: String,
+        new_username: String,
+    },
+    RemovePlacedObj {
+        obj_id: i32,
+    },
+    Shutdown {
+        reason: String,
+        done: oneshot::Sender<()>,
+    },
+    Status {
+        reply: oneshot::Sender<ServerStatus>,
+    },
+}
+
+/// What a running game server is up to, for admins.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerStatus {
+    pub game_token: String,
+    /// Clients connected to this instance.
+    pub clients: usize,
+    /// Clients connected to the game's servers on other instances.
+    pub remote_clients: usize,
+}
+
+/// Updates that every running game on every instance needs to hear about. Each server applies
+/// them as they arrive rather than in order with the game's own events, which is fine as
+/// clients can't do anything that conflicts with them.
+#[derive(Debug, Serialize, Deserialize)]
+enum Update {
+    RenameUser {
+        user_id: i32,
+        username: String,
+        new_username: String,
+    },
+    RemovePlacedObj {
+        obj_id: i32,
+    },
+    /// Stops a game's servers. Clients are told why, and can reconnect to fresh servers.
+    CloseGame {
+        game_token: String,
+        reason: String,
+    },
+}
+
+impl Update {
+    /// Whether a game's servers need to hear about the update.
+    fn concerns(&self, game_token: &str) -> bool {
+        match self {
+            Update::CloseGame {
+                game_token: token, ..
+            } => token == game_token,
+            _ => true,
+        }
+    }
+
+    fn command(&self) -> Command {
+        match self {
+            Update::RenameUser {
+                user_id,
+                username,
+                new_username,
+            } => Command::RenameUser {
+                user_id: *user_id,
+                username: username.clone(),
+                new_username: new_username.clone(),
+            },
+            Update::RemovePlacedObj { obj_id } => Command::RemovePlacedObj { obj_id: *obj_id },
+            Update::CloseGame { reason, .. } => Command::Shutdown {
+                reason: reason.clone(),
+                // Nobody here waits for the server to stop
+                done: oneshot::channel().0,
+            },
+        }
+    }
+}
+
+/// A client connected to one of a game's servers.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Member {
+    node: String,
+    user_id: i32,
+    connect: ProtocolMessage,
+}
+
+/// What the servers for a game on different instances tell each other.
+#[derive(Debug, Serialize, Deserialize)]
+enum Event {
+    /// A message from a client. Every server applies these in the order the bus delivers them,
+    /// so they all end up with the same state.
+    Msg(ProtocolMessage),
+    Joined(Member),
+    Left {
+        node: String,
+        user_id: i32,
+    },
+    /// Sent by a server when it starts, asking for the game's state.
+    SyncRequest {
+        node: String,
+    },
+    /// The game's state and everyone connected to it, for the server that asked.
+    Sync {
+        node: String,
+        state: GameState,
+        members: Vec<Member>,
+    },
+}
+
+/// A running game server. The server owns the game's state on its own task, and everything
+/// else talks to it through one of these.
+#[derive(Clone)]
+pub struct ServerHandle {
+    tx: mpsc::Sender<Command>,
+}
+
+impl ServerHandle {
+    /// Passes a message from a client on to the server.
+    pub async fn recv(&self, msg: Message, user_id: i32) {
+        if let Message::Close(_) = msg {
+            info!("Client closed");
+            return;
+        }
+        match msg.to_text() {
+            Ok(text) => match serde_json::from_str(text) {
+                Ok(msg) => self.send(Command::Recv { user_id, msg }).await,
+                Err(e) => {
+                    metrics::MALFORMED_MESSAGES.inc();
+                    // The error's message can quote the payload, so only say where it went wrong
+                    warn!(
+                        "malformed message of {} bytes: {:?} error at line {} column {}",
+                        text.len(),
+                        e.classify(),
+                        e.line(),
+                        e.column()
+                    );
+                }
+            },
+            Err(_) => warn!("invalid message type"),
+        }
+    }
+
+    pub async fn close_client(&self, user_id: i32) {
+        self.send(Command::Close { user_id }).await;
+    }
+
+    async fn send(&self, cmd: Command) {
+        // Servers only stop once nobody is connected, so there's nobody to tell
+        if self.tx.send(cmd).await.is_err() {
+            warn!("game server has stopped");
+        }
+    }
+
+    fn is(&self, other: &ServerHandle) -> bool {
+        self.tx.same_channel(&other.tx)
+    }
+}
+
+/// The games hosted on this instance. Other instances on the same bus can host the same games,
+/// with each game's servers keeping each other up to date.
+pub struct Games {
+    node: String,
+    bus: Arc<dyn Bus>,
+    db: Arc<dyn Database>,
+    // Only locked to look up, add or remove a handle, never across an await
+    servers: Mutex<HashMap<String, ServerHandle>>,
+    /// Set once shutting down. Connections hold a receiver, so shutting down can wait for them.
+    closing: watch::Sender<bool>,
+    stopped: OnceCell<()>,
+    /// Whether game connections are being accepted.
+    listening: AtomicBool,
+    /// Messages that can queue up for each client, and what happens when they're too slow.
+    client_buffer: usize,
+    overflow: OverflowPolicy,
+}
+
+impl Games {
+    /// Starts hosting games, saving their state in the database when they stop.
+    pub async fn start(bus: Arc<dyn Bus>, db: Arc<dyn Database>) -> Result<Arc<Self>, BusError> {
+        let outbound = &CONFIG.outbound;
+        Self::start_with_queue(bus, db, outbound.buffer, outbound.overflow).await
+    }
+
+    /// Like `start`, but with clients' queues set up independently of the config.
+    pub async fn start_with_queue(
+        bus: Arc<dyn Bus>,
+        db: Arc<dyn Database>,
+        client_buffer: usize,
+        overflow: OverflowPolicy,
+    ) -> Result<Arc<Self>, BusError> {
+        let mut updates = bus.subscribe(UPDATES_CHANNEL).await?;
+        let games = Arc::new(Self {
+            node: Uuid::new_v4().to_string(),
+            bus,
+            db,
+            servers: Mutex::new(HashMap::new()),
+            closing: watch::channel(false).0,
+            stopped: OnceCell::new(),
+            listening: AtomicBool::new(false),
+            client_buffer,
+            overflow,
+        });
+
+        // Pass updates on to the servers running here, for as long as there can be any
+        let weak = Arc::downgrade(&games);
+        tokio::spawn(async move {
+            while let Some(update) = updates.recv().await {
+                let games = match weak.upgrade() {
+                    Some(games) => games,
+                    None => break,
+                };
+                match serde_json::from_str::<Update>(&update) {
+                    Ok(update) => {
+                        for (game_token, server) in games.running_servers() {
+                            if update.concerns(&game_token) {
+                                server.send(update.command()).await;
+                            }
+                        }
+                    }
+                    Err(e) => warn!("malformed update: {}", e),
+                }
+            }
+        });
+        Ok(games)
+    }
+
+    /// Joins a user to a game's server, starting one if needed. Returns the server, and the
+    /// messages it sends to the user.
+    pub async fn connect(
+        self: &Arc<Self>,
+        user: UserInfo,
+        game_token: String,
+    ) -> Result<(ServerHandle, mpsc::Receiver<Outbound>), GameError> {
+        loop {
+            let server = {
+                let mut servers = self.servers.lock().unwrap();
+                // Checked under the lock, so shutting down can't miss a new server
+                if *self.closing.borrow() {
+                    return Err(GameError::Unavailable);
+                }
+                servers
+                    .entry(game_token.clone())
+                    .or_insert_with(|| {
+                        info!("Create server for game {}", game_token);
+                        Server::start(self.clone(), user.clone(), game_token.clone())
+                    })
+                    .clone()
+            };
+
+            let (reply, response) = oneshot::channel();
+            let cmd = Command::Connect {
+                user: user.clone(),
+                reply,
+            };
+            if server.tx.send(cmd).await.is_ok() {
+                if let Ok(result) = response.await {
+                    return result.map(|rx| (server, rx));
+                }
+            }
+
+            // The server stopped just as we reached it, so make sure it's gone and start another
+            self.remove_server(&game_token, &server);
+        }
+    }
+
+    /// Tells games that a user has a new username, so they keep control of their tokens.
+    pub async fn rename_user(&self, user_id: i32, username: &str, new_username: &str) {
+        // Usernames appear in saved states as JSON strings
+        let containing = serde_json::to_string(username).unwrap();
+        self.update_saved_states(&containing, |state| {
+            state.rename_user(user_id, username, new_username)
+        })
+        .await;
+        self.publish_update(&Update::RenameUser {
+            user_id,
+            username: username.to_string(),
+            new_username: new_username.to_string(),
+        })
+        .await;
+    }
+
+    /// Removes every placed copy of an object from games, e.g. after it is deleted.
+    pub async fn remove_placed_obj(&self, obj_id: i32) {
+        let containing = format!("\"obj_id\":{},", obj_id);
+        self.update_saved_states(&containing, |state| {
+            !state.remove_placed_obj(obj_id).is_empty()
+        })
+        .await;
+        self.publish_update(&Update::RemovePlacedObj { obj_id })
+            .await;
+    }
+
+    /// Applies a change to the saved state of every game, for games that aren't running to
+    /// pick up when they start. Running games are updated separately, and save their own state
+    /// over this when they stop. `containing` narrows down which states need looking at.
+    async fn update_saved_states<F>(&self, containing: &str, update: F)
+    where
+        F: Fn(&mut GameState) -> bool + Send + Sync,
+    {
+        let rewrite = |saved: &str| {
+            let mut state: GameState = serde_json::from_str(saved).ok()?;
+            if update(&mut state) {
+                Some(serde_json::to_string(&state).unwrap())
+            } else {
+                None
+            }
+        };
+        match self.db.update_game_states(containing, &rewrite).await {
+            Ok(0) => {}
+            Ok(updated) => info!("updated {} saved game states", updated),
+            Err(e) => warn!("failed updating saved game states: {}", e),
+        }
+    }
+
+    /// Stops a game's servers on every instance.
+    pub async fn close_game(&self, game_token: &str, reason: &str) {
+        self.publish_update(&Update::CloseGame {
+            game_token: game_token.to_string(),
+            reason: reason.to_string(),
+        })
+        .await;
+    }
+
+    /// Describes the game servers running on this instance.
+    pub async fn status(&self) -> Vec<ServerStatus> {
+        let mut status = Vec::new();
+        for (_, server) in self.running_servers() {
+            let (reply, response) = oneshot::channel();
+            server.send(Command::Status { reply }).await;
+            // Servers that have stopped since drop the reply
+            if let Ok(server_status) = response.await {
+                status.push(server_status);
+            }
+        }
+        status.sort_by(|a, b| a.game_token.cmp(&b.game_token));
+        status
+    }
+
+    /// Stops hosting games: stops taking connections, tells everyone connected why, and saves
+    /// every game. Returns once that's done, and connections have closed or had their chance.
+    pub async fn shutdown(&self, reason: &str) {
+        self.stopped.get_or_init(|| self.stop(reason)).await;
+    }
+
+    /// Watches for shutting down. Connections keep hold of one until they finish.
+    pub fn closing(&self) -> watch::Receiver<bool> {
+        self.closing.subscribe()
+    }
+
+    pub fn is_listening(&self) -> bool {
+        self.listening.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_listening(&self, listening: bool) {
+        self.listening.store(listening, Ordering::SeqCst);
+    }
+
+    async fn stop(&self, reason: &str) {
+        info!("Shutting down games: {}", reason);
+        let servers = {
+            let servers = self.servers.lock().unwrap();
+            self.closing.send_replace(true);
+            servers.values().cloned().collect::<Vec<_>>()
+        };
+
+        let mut stopping = Vec::new();
+        for server in servers {
+            let (done, stopped) = oneshot::channel();
+            let reason = reason.to_string();
+            server.send(Command::Shutdown { reason, done }).await;
+            stopping.push(stopped);
+        }
+        // Servers that had already stopped drop the reply, which is just as good
+        for stopped in stopping {
+            let _ = stopped.await;
+        }
+
+        if timeout(SHUTDOWN_DRAIN_TIMEOUT, self.closing.closed())
+            .await
+            .is_err()
+        {
+            warn!("game connections still open after shutting down");
+        }
+    }
+
+    async fn publish_update(&self, update: &Update) {
+        let msg = serde_json::to_string(update).unwrap();
+        if let Err(e) = self.bus.publish(UPDATES_CHANNEL, &msg).await {
+            warn!("failed publishing update: {}", e);
+        }
+    }
+
+    fn running_servers(&self) -> Vec<(String, ServerHandle)> {
+        let servers = self.servers.lock().unwrap();
+        servers
+            .iter()
+            .map(|(game_token, server)| (game_token.clone(), server.clone()))
+            .collect()
+    }
+
+    fn remove_server(&self, game_token: &str, handle: &ServerHandle) {
+        let mut servers = self.servers.lock().unwrap();
+        if servers
+            .get(game_token)
+            .map_or(false, |server| server.is(handle))
+        {
+            servers.remove(game_token);
+        }
+    }
+}
+
+struct Client {
+    user: UserInfo,
+    tx: mpsc::Sender<Outbound>,
+    /// When the client's queue overflowed, if it's waiting to be resynced.
+    lagging_since: Option<Instant>,
+}
+
+/// Whether a new server has the game's state yet.
+enum Syncing {
+    /// Waiting for another server to send it. Clients have to wait too, and events after our
+    /// request are kept to apply on top of the state we're sent.
+    Waiting {
+        requested: bool,
+        events: Vec<Event>,
+        connects: Vec<(UserInfo, ConnectReply)>,
+    },
+    Done,
+}
+
+struct Server {
+    games: Arc<Games>,
     game_token: String,
-    clients: flurry::HashMap<UserInfo, SyncSender<String>>,
-    keepalive: Mutex<Option<Instant>>,
-    state: Mutex<GameState>,
-    host_id: i32,
+    clients: HashMap<i32, Client>,
+    /// Connection messages for clients of servers on other instances.
+    members: HashMap<(String, i32), ProtocolMessage>,
+    keepalive: Instant,
+    state: GameState,
+    sync: Syncing,
+    /// Events for the bus, which are published in order from a separate task.
+    events: mpsc::UnboundedSender<String>,
 }
 
 impl Server {
-    fn new(host: UserInfo, game_token: String) -> Self {
-        let host_id = host.id;
-        let clients = flurry::HashMap::new();
-        let state = Mutex::new(GameState::new(host));
-        let keepalive = Mutex::new(Some(Instant::now()));
+    fn start(games: Arc<Games>, host: UserInfo, game_token: String) -> ServerHandle {
+        let span = info_span!("game", game = %game_token);
+        let (events, mut outgoing) = mpsc::unbounded_channel::<String>();
+        let bus = games.bus.clone();
+        let channel = game_channel(&game_token);
+        let publisher = async move {
+            while let Some(event) = outgoing.recv().await {
+                if let Err(e) = bus.publish(&channel, &event).await {
+                    warn!("failed publishing game event: {}", e);
+                }
+            }
+        };
+        tokio::spawn(publisher.instrument(span.clone()));
 
-        Self {
+        let (tx, rx) = mpsc::channel(COMMAND_BUFFER);
+        let handle = ServerHandle { tx };
+        let server = Self {
+            games,
             game_token,
-            clients,
-            state,
-            keepalive,
-            host_id,
-        }
+            clients: HashMap::new(),
+            members: HashMap::new(),
+            keepalive: Instant::now(),
+            state: GameState::new(&host),
+            sync: Syncing::Waiting {
+                requested: false,
+                events: Vec::new(),
+                connects: Vec::new(),
+            },
+            events,
+        };
+        tokio::spawn(server.run(rx, handle.clone()).instrument(span));
+        handle
     }
 
-    fn has_client(&self, user: &UserInfo) -> bool {
-        self.clients.pin().contains_key(user)
-    }
+    async fn run(mut self, mut rx: mpsc::Receiver<Command>, handle: ServerHandle) {
+        metrics::GAME_SERVERS.inc();
+        self.load().await;
+        let mut events = match self.join_bus().await {
+            Ok(events) => events,
+            Err(e) => {
+                warn!("game server {} can't reach the bus: {}", self.game_token, e);
+                self.games.remove_server(&self.game_token, &handle);
+                rx.close();
+                while let Some(cmd) = rx.recv().await {
+                    if let Command::Connect { reply, .. } = cmd {
+                        let _ = reply.send(Err(GameError::Unavailable));
+                    }
+                }
+                metrics::GAME_SERVERS.dec();
+                return;
+            }
+        };
 
-    async fn add_client(&self, user: UserInfo, tx: SyncSender<String>) {
-        // Send existing client info
-        let clients = self.clients.pin();
-        for user in clients.keys() {
-            if let Err(e) = tx.send(
-                ProtocolMessage::Connect {
-                    username: user.username.clone(),
-                    host: user.is_host,
-                    host_id: self.host_id,
+        let mut monitor = tokio::time::interval(CONFIG.monitor_interval);
+        let mut lag_check = tokio::time::interval(LAG_CHECK_INTERVAL);
+        let sync_timeout = tokio::time::sleep(SYNC_TIMEOUT);
+        tokio::pin!(sync_timeout);
+        let mut shutdown = None;
+        loop {
+            tokio::select! {
+                Some(cmd) = rx.recv() => match cmd {
+                    Command::Shutdown { reason, done } => {
+                        self.shutdown(reason);
+                        shutdown = Some(done);
+                        break;
+                    }
+                    cmd => self.handle(cmd),
+                },
+                event = events.recv() => match event {
+                    Some(event) => self.handle_event(&event),
+                    None => {
+                        // Clients are dropped, and can reconnect to a fresh server
+                        warn!("game server {} lost the bus", self.game_token);
+                        break;
+                    }
+                },
+                _ = &mut sync_timeout, if self.is_syncing() => {
+                    warn!("no state received for game {}, starting afresh", self.game_token);
+                    self.finish_sync(None);
+                }
+                _ = lag_check.tick() => self.check_lagging(),
+                _ = monitor.tick() => {
+                    if self.clients.is_empty() && self.keepalive.elapsed() > CONFIG.game_timeout {
+                        info!("killing game server {}", self.game_token);
+                        break;
+                    }
                 }
-                .to_string(),
-            ) {
-                warn!("failed sending users: {}", e);
             }
         }
-        // Send existing state info
+
+        self.save().await;
+        // Anyone who grabbed this handle in the meantime will see it has stopped and retry
+        self.games.remove_server(&self.game_token, &handle);
+        metrics::GAME_SERVERS.dec();
+        if let Some(done) = shutdown {
+            let _ = done.send(());
+        }
+    }
+
+    /// Picks up where the game was left when a server for it last stopped.
+    async fn load(&mut self) {
+        match self.games.db.load_game_state(&self.game_token).await {
+            Ok(Some(state)) => match serde_json::from_str(&state) {
+                Ok(state) => self.state = state,
+                Err(e) => warn!("malformed saved state for game {}: {}", self.game_token, e),
+            },
+            Ok(None) => {}
+            Err(e) => warn!("failed loading state for game {}: {}", self.game_token, e),
+        }
+    }
+
+    async fn save(&self) {
+        // A server that never got the state has nothing worth saving
+        if self.is_syncing() {
+            return;
+        }
+        let state = serde_json::to_string(&self.state).unwrap();
+        if let Err(e) = self
+            .games
+            .db
+            .save_game_state(&self.game_token, &state)
+            .await
         {
-            let state = self.state.lock().unwrap();
-            state.replay(tx.clone());
+            warn!("failed saving state for game {}: {}", self.game_token, e);
+        }
+    }
+
+    /// Tells clients why they're about to be dropped. Dropping their queues closes their
+    /// connections once they've been sent everything.
+    fn shutdown(&mut self, reason: String) {
+        self.broadcast(&ProtocolMessage::ServerShutdown { reason });
+        for user_id in self.clients.keys() {
+            self.publish(&Event::Left {
+                node: self.games.node.clone(),
+                user_id: *user_id,
+            });
+        }
+        self.clients.clear();
+    }
+
+    /// Subscribes to the game's channel, and asks any other servers for the game's state.
+    async fn join_bus(&mut self) -> Result<Subscription, BusError> {
+        let bus = &self.games.bus;
+        let channel = game_channel(&self.game_token);
+        let events = bus.subscribe(&channel).await?;
+        let request = serde_json::to_string(&Event::SyncRequest {
+            node: self.games.node.clone(),
+        })
+        .unwrap();
+        // If we're the only subscriber, there's nobody to ask
+        if bus.publish(&channel, &request).await? == 1 {
+            self.finish_sync(None);
         }
+        Ok(events)
+    }
+
+    fn is_syncing(&self) -> bool {
+        matches!(self.sync, Syncing::Waiting { .. })
+    }
 
-        clients.insert(user.clone(), tx);
-        info!("New client for game {}: {}", self.game_token, user.token);
-        let username = user.username.clone();
-        self.recv(
-            ProtocolMessage::Connect {
+    /// Starts running the game, from the state another server sent if there is one, then lets
+    /// in everyone who was waiting.
+    fn finish_sync(&mut self, synced: Option<(GameState, Vec<Member>)>) {
+        let (events, connects) = match std::mem::replace(&mut self.sync, Syncing::Done) {
+            Syncing::Waiting {
+                events, connects, ..
+            } => (events, connects),
+            Syncing::Done => return,
+        };
+        if let Some((state, members)) = synced {
+            let node = self.games.node.clone();
+            self.state = state;
+            self.members = members
+                .into_iter()
+                .filter(|member| member.node != node)
+                .map(|member| ((member.node, member.user_id), member.connect))
+                .collect();
+        }
+        for event in events {
+            self.apply(event);
+        }
+        for (user, reply) in connects {
+            self.connect(user, reply);
+        }
+    }
+
+    fn publish(&self, event: &Event) {
+        // The publishing task only stops once we have
+        let _ = self.events.send(serde_json::to_string(event).unwrap());
+    }
+
+    fn handle(&mut self, cmd: Command) {
+        match cmd {
+            Command::Connect { user, reply } => match &mut self.sync {
+                Syncing::Waiting { connects, .. } => connects.push((user, reply)),
+                Syncing::Done => self.connect(user, reply),
+            },
+            Command::Recv { user_id, msg } => self.recv(user_id, msg),
+            Command::Close { user_id } => self.close_client(user_id),
+            Command::RenameUser {
+                user_id,
                 username,
-                host: user.is_host,
-                host_id: self.host_id,
+                new_username,
+            } => self.rename_user(user_id, &username, &new_username),
+            Command::RemovePlacedObj { obj_id } => self.remove_placed_obj(obj_id),
+            Command::Status { reply } => {
+                let _ = reply.send(ServerStatus {
+                    game_token: self.game_token.clone(),
+                    clients: self.clients.len(),
+                    remote_clients: self.members.len(),
+                });
             }
-            .into(),
-            user,
-        )
-        .await;
+            // Handled by the run loop, since the server stops
+            Command::Shutdown { .. } => {}
+        }
     }
 
-    pub fn close_client(&self, user: UserInfo) {
-        let clients = self.clients.pin();
-        clients.remove(&user);
-        for client in clients.values() {
-            info!("Sending disconnect update for {}", user.username);
-            if let Err(e) = client.send(
-                ProtocolMessage::Disconnect {
-                    username: user.username.clone(),
+    fn handle_event(&mut self, event: &str) {
+        let event = match serde_json::from_str(event) {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("malformed game event: {}", e);
+                return;
+            }
+        };
+        match event {
+            Event::SyncRequest { node } => {
+                if node == self.games.node {
+                    if let Syncing::Waiting { requested, .. } = &mut self.sync {
+                        *requested = true;
+                    }
+                } else if !self.is_syncing() {
+                    self.publish(&Event::Sync {
+                        node,
+                        state: self.state.clone(),
+                        members: self.members(),
+                    });
                 }
-                .to_string(),
-            ) {
-                warn!("Failed sending disconnect update: {}", e);
             }
+            Event::Sync {
+                node,
+                state,
+                members,
+            } => {
+                if node == self.games.node {
+                    self.finish_sync(Some((state, members)));
+                }
+            }
+            event => match &mut self.sync {
+                // Anything before our request is already in the state we'll be sent
+                Syncing::Waiting {
+                    requested: true,
+                    events,
+                    ..
+                } => events.push(event),
+                Syncing::Waiting { .. } => {}
+                Syncing::Done => self.apply(event),
+            },
+        }
+    }
+
+    /// Applies something that happened in the game, here or on another instance.
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::Msg(mut msg) => {
+                if self.state.process(&mut msg) {
+                    self.broadcast(&msg);
+                }
+            }
+            Event::Joined(member) if member.node != self.games.node => {
+                self.broadcast(&member.connect);
+                self.members
+                    .insert((member.node, member.user_id), member.connect);
+            }
+            Event::Left { node, user_id } if node != self.games.node => {
+                if let Some(ProtocolMessage::Connect { username, .. }) =
+                    self.members.remove(&(node, user_id))
+                {
+                    self.broadcast(&ProtocolMessage::Disconnect { username });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Everyone connected to the game, on any server.
+    fn members(&self) -> Vec<Member> {
+        let host_id = self.state.host_id();
+        self.clients
+            .values()
+            .map(|client| Member {
+                node: self.games.node.clone(),
+                user_id: client.user.id,
+                connect: client.user.connect_msg(host_id),
+            })
+            .chain(
+                self.members
+                    .iter()
+                    .map(|((node, user_id), connect)| Member {
+                        node: node.clone(),
+                        user_id: *user_id,
+                        connect: connect.clone(),
+                    }),
+            )
+            .collect()
+    }
+
+    /// Messages that bring a client up to date: who else is here, then the game state.
+    fn backlog(&self) -> Vec<ProtocolMessage> {
+        let host_id = self.state.host_id();
+        self.clients
+            .values()
+            .map(|client| client.user.connect_msg(host_id))
+            .chain(self.members.values().cloned())
+            .chain(self.state.replay())
+            .collect()
+    }
+
+    fn connect(&mut self, user: UserInfo, reply: ConnectReply) {
+        let user_id = user.id;
+        let result = self.add_client(user);
+        let added = result.is_ok();
+        if reply.send(result).is_err() && added {
+            // The connection went away while we were setting it up
+            self.close_client(user_id);
+        }
+    }
+
+    fn add_client(&mut self, user: UserInfo) -> Result<mpsc::Receiver<Outbound>, GameError> {
+        if self.clients.contains_key(&user.id) {
+            return Err(GameError::AlreadyConnected);
+        }
+
+        // Send existing client info and state, in an extra slot so it doesn't use up the buffer
+        let (tx, rx) = mpsc::channel(self.games.client_buffer + 1);
+        let backlog = self.backlog();
+        if !backlog.is_empty() {
+            if let Err(e) = tx.try_send(Outbound::Batch(backlog)) {
+                warn!("failed sending state: {}", e);
+            }
+        }
+
+        info!("New client for game {}: {}", self.game_token, user.username);
+        let msg = user.connect_msg(self.state.host_id());
+        self.publish(&Event::Joined(Member {
+            node: self.games.node.clone(),
+            user_id: user.id,
+            connect: msg.clone(),
+        }));
+        let client = Client {
+            user,
+            tx,
+            lagging_since: None,
+        };
+        self.clients.insert(client.user.id, client);
+        self.broadcast(&msg);
+        Ok(rx)
+    }
+
+    fn close_client(&mut self, user_id: i32) {
+        if let Some(client) = self.clients.remove(&user_id) {
+            info!("Sending disconnect update for {}", client.user.username);
+            self.publish(&Event::Left {
+                node: self.games.node.clone(),
+                user_id,
+            });
+            self.broadcast(&ProtocolMessage::Disconnect {
+                username: client.user.username,
+            });
         }
-        if clients.len() == 0 {
-            let mut keepalive = self.keepalive.lock().unwrap();
-            keepalive.replace(Instant::now());
+        if self.clients.is_empty() {
+            self.keepalive = Instant::now();
         }
     }
 
-    fn authorised(&self, msg: &ProtocolMessage, user: UserInfo) -> bool {
+    fn authorised(&self, msg: &ProtocolMessage, user: &UserInfo) -> bool {
         match msg {
             ProtocolMessage::PlaceToken(_)
             | ProtocolMessage::DeleteToken { .. }
@@ -151,65 +908,125 @@ impl Server {
             | ProtocolMessage::MoveObj { .. } => user.is_host,
             ProtocolMessage::MoveToken { token_id, .. }
             | ProtocolMessage::RenameToken { token_id, .. } => {
-                user.is_host || {
-                    let state = self.state.lock().unwrap();
-                    Some(user.username) == state.get_owner(token_id) || user.is_host
-                }
+                user.is_host || Some(&user.username) == self.state.get_owner(token_id).as_ref()
             }
             ProtocolMessage::Connect { .. }
             | ProtocolMessage::Disconnect { .. }
             | ProtocolMessage::FailedConnection { .. } => true,
+            ProtocolMessage::RenameUser { .. }
+            | ProtocolMessage::Resync {}
+            | ProtocolMessage::ServerShutdown { .. } => false,
         }
     }
 
-    pub async fn recv(&self, msg: Message, user: UserInfo) {
-        if let Ok(text) = msg.to_text() {
-            if let Ok(mut parsed) = serde_json::from_str::<ProtocolMessage>(&text) {
-                if self.authorised(&parsed, user) {
-                    let proceed = {
-                        let mut state = self.state.lock().unwrap();
-                        state.process(&mut parsed)
-                    };
-
-                    if proceed {
-                        let text = parsed.to_string();
-                        info!("sending: {}", text);
-                        let clients = self.clients.pin();
-                        for tx in clients.values() {
-                            if let Err(e) = tx.send(text.to_string()) {
-                                warn!("failed writing: {}", e);
-                            }
-                        }
+    /// Queues a message for every client. Rather than hold up everyone else, clients whose
+    /// queue is full miss out, and are resynced or disconnected depending on the policy.
+    fn broadcast(&mut self, msg: &ProtocolMessage) {
+        debug!(kind = msg.kind(), "sending message");
+        metrics::MESSAGES_BROADCAST
+            .with_label_values(&[msg.kind()])
+            .inc();
+        let msg = Arc::new(msg.clone());
+        let mut too_slow = Vec::new();
+        for (&user_id, client) in self.clients.iter_mut() {
+            if client.lagging_since.is_some() {
+                continue;
+            }
+            match client.tx.try_send(Outbound::Msg(msg.clone())) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => match self.games.overflow {
+                    OverflowPolicy::Resync => {
+                        warn!("client #{} fell behind, resyncing", user_id);
+                        client.lagging_since = Some(Instant::now());
                     }
-                } else {
-                    warn!("unauthorised message from non-host");
+                    OverflowPolicy::Disconnect => too_slow.push(user_id),
+                },
+                // The connection is closing, and will tell us when it's done
+                Err(TrySendError::Closed(_)) => {}
+            }
+        }
+        for user_id in too_slow {
+            warn!("client #{} fell behind, disconnecting", user_id);
+            self.close_client(user_id);
+        }
+    }
+
+    /// Resyncs clients that fell behind once they have room, and disconnects those that have
+    /// been stuck for too long.
+    fn check_lagging(&mut self) {
+        let lagging: Vec<_> = self
+            .clients
+            .iter()
+            .filter_map(|(&user_id, client)| client.lagging_since.map(|since| (user_id, since)))
+            .collect();
+        if lagging.is_empty() {
+            return;
+        }
+
+        let mut resync = vec![ProtocolMessage::Resync {}];
+        resync.extend(self.backlog());
+        for (user_id, since) in lagging {
+            let client = self.clients.get_mut(&user_id).unwrap();
+            match client.tx.try_send(Outbound::Batch(resync.clone())) {
+                Ok(()) => {
+                    info!("resynced client #{}", user_id);
+                    client.lagging_since = None;
+                }
+                Err(TrySendError::Full(_)) if since.elapsed() < CONFIG.outbound.stall_timeout => {}
+                Err(_) => {
+                    warn!("client #{} stalled, disconnecting", user_id);
+                    self.close_client(user_id);
                 }
-            } else {
-                warn!("malformed message: {}", text);
             }
+        }
+    }
+
+    /// Checks a client's message, and passes it on to every server for the game to apply.
+    fn recv(&mut self, user_id: i32, msg: ProtocolMessage) {
+        // Ignore anything still arriving from clients that have been dropped
+        let user = match self.clients.get(&user_id) {
+            Some(client) => &client.user,
+            None => return,
+        };
+        metrics::MESSAGES_RECEIVED
+            .with_label_values(&[msg.kind()])
+            .inc();
+        if self.authorised(&msg, user) {
+            self.publish(&Event::Msg(msg));
         } else {
-            match msg {
-                Message::Close(_) => info!("Client closed"),
-                _ => warn!("invalid message type"),
+            metrics::UNAUTHORISED_MESSAGES.inc();
+            warn!("unauthorised message from non-host");
+        }
+    }
+
+    fn rename_user(&mut self, user_id: i32, username: &str, new_username: &str) {
+        let mut connected = match self.clients.get_mut(&user_id) {
+            Some(client) if client.user.username == username => {
+                client.user.username = new_username.to_string();
+                true
             }
+            _ => false,
+        };
+        for ((_, id), connect) in self.members.iter_mut() {
+            if let ProtocolMessage::Connect { username: name, .. } = connect {
+                if *id == user_id && name == username {
+                    *name = new_username.to_string();
+                    connected = true;
+                }
+            }
+        }
+        let changed = self.state.rename_user(user_id, username, new_username);
+        if connected || changed {
+            self.broadcast(&ProtocolMessage::RenameUser {
+                username: username.to_string(),
+                new_username: new_username.to_string(),
+            });
         }
     }
-}
 
-pub fn connect_to_server(
-    user: UserInfo,
-    game_token: String,
-    tx: SyncSender<String>,
-) -> Result<Arc<Server>, GameError> {
-    let mut servers = SERVERS.lock().unwrap();
-    let server = servers.entry(game_token.clone()).or_insert_with(|| {
-        info!("Create server for game {}", game_token);
-        Arc::new(Server::new(user.clone(), game_token))
-    });
-    if !server.has_client(&user) {
-        futures::executor::block_on(server.add_client(user, tx));
-        Ok(Arc::clone(server))
-    } else {
-        Err(GameError::AlreadyConnected)
+    fn remove_placed_obj(&mut self, obj_id: i32) {
+        for id in self.state.remove_placed_obj(obj_id) {
+            self.broadcast(&ProtocolMessage::DeleteObj { obj_id: id });
+        }
     }
 }
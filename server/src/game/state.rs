@@ -1,11 +1,14 @@
 use crate::game::protocol::{PlacedObj, ProtocolMessage, Token};
 use crate::game::server::UserInfo;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::sync::mpsc::SyncSender;
 
-// Can assume it is thread safe since it is stored in a mutex
+// Owned by the game server task, so it never needs locking. Servers on other instances are
+// sent a copy when they start.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GameState {
-    host: UserInfo,
+    host_id: i32,
+    host_username: String,
     tokens: HashMap<String, Token>,
     token_count: usize,
     placed_objs: HashMap<String, PlacedObj>,
@@ -13,9 +16,10 @@ pub struct GameState {
 }
 
 impl GameState {
-    pub fn new(host: UserInfo) -> Self {
+    pub fn new(host: &UserInfo) -> Self {
         Self {
-            host,
+            host_id: host.id,
+            host_username: host.username.clone(),
             tokens: HashMap::new(),
             token_count: 0,
             placed_objs: HashMap::new(),
@@ -30,7 +34,7 @@ impl GameState {
                 token.id = Some(token_id.clone());
                 self.token_count += 1;
                 // controller is automatically the host
-                token.controller = Some(self.host.username.clone());
+                token.controller = Some(self.host_username.clone());
 
                 if !self
                     .tokens
@@ -104,22 +108,106 @@ impl GameState {
         }
     }
 
+    /// Removes all placed copies of an object, returning the ids of the removed copies.
+    pub fn remove_placed_obj(&mut self, obj_id: i32) -> Vec<String> {
+        let removed: Vec<_> = self
+            .placed_objs
+            .iter()
+            .filter(|(_, obj)| obj.obj_id == obj_id)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in removed.iter() {
+            self.placed_objs.remove(id);
+        }
+        removed
+    }
+
+    /// Updates references to a user whose username changed, returning whether there were any.
+    pub fn rename_user(&mut self, user_id: i32, username: &str, new_username: &str) -> bool {
+        let mut changed = false;
+        if self.host_id == user_id && self.host_username == username {
+            self.host_username = new_username.to_string();
+            changed = true;
+        }
+        for token in self.tokens.values_mut() {
+            if token.controller.as_deref() == Some(username) {
+                token.controller = Some(new_username.to_string());
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    pub fn host_id(&self) -> i32 {
+        self.host_id
+    }
+
     pub fn get_owner(&self, token_id: &str) -> Option<String> {
         self.tokens
             .get(token_id)
             .and_then(|token| token.controller.clone())
     }
 
-    pub fn replay(&self, tx: SyncSender<String>) {
-        self.tokens.values().for_each(|token| {
-            if let Err(e) = tx.send(token.to_msg().to_string()) {
-                warn!("STATE: error forwarding token: {}", e)
-            }
-        });
-        self.placed_objs.values().for_each(|obj| {
-            if let Err(e) = tx.send(obj.to_msg().to_string()) {
-                warn!("STATE: error forwarding object: {}", e)
-            }
+    /// Messages that recreate the current state, for clients that join late.
+    pub fn replay(&self) -> Vec<ProtocolMessage> {
+        self.tokens
+            .values()
+            .map(Token::to_msg)
+            .chain(self.placed_objs.values().map(PlacedObj::to_msg))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GameState;
+    use crate::game::protocol::{PlacedObj, ProtocolMessage};
+    use crate::game::server::UserInfo;
+
+    fn place_obj(state: &mut GameState, obj_id: i32) -> String {
+        let mut msg = ProtocolMessage::PlaceObj(PlacedObj {
+            id: None,
+            obj_id,
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
         });
+        assert!(state.process(&mut msg));
+        match msg {
+            ProtocolMessage::PlaceObj(obj) => obj.id.unwrap(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_remove_placed_obj() {
+        let host = UserInfo {
+            token: "token".to_string(),
+            username: "host#0001".to_string(),
+            is_host: true,
+            id: 1,
+            avatar: None,
+            colour: None,
+        };
+        let mut state = GameState::new(&host);
+        let first = place_obj(&mut state, 7);
+        let other = place_obj(&mut state, 8);
+        let second = place_obj(&mut state, 7);
+
+        // Every copy of the object goes, and nothing else
+        let mut removed = state.remove_placed_obj(7);
+        removed.sort();
+        assert_eq!(removed, vec![first, second]);
+        let replay = state.replay();
+        assert_eq!(replay.len(), 1);
+        assert!(matches!(
+            &replay[0],
+            ProtocolMessage::PlaceObj(obj) if obj.id.as_ref() == Some(&other)
+        ));
+
+        // Objects that aren't placed are ignored
+        assert!(state.remove_placed_obj(7).is_empty());
+        assert!(state.remove_placed_obj(100).is_empty());
     }
 }
@@ -1,9 +1,13 @@
 #![feature(proc_macro_hygiene, decl_macro, async_closure)]
 #[macro_use] extern crate rocket;
 #[macro_use] extern crate lazy_static;
-#[macro_use] extern crate log;
+#[macro_use] extern crate tracing;
 
 pub mod db;
 pub mod web;
 pub mod game;
-pub mod config;
\ No newline at end of file
+pub mod config;
+pub mod upload;
+pub mod storage;
+pub mod metrics;
+pub mod logging;
\ No newline at end of file
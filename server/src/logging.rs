@@ -0,0 +1,130 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::route::{Handler, Outcome};
+use rocket::{Data, Request, Response, Route};
+use tracing::Instrument;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::MakeWriter;
+use uuid::Uuid;
+
+use crate::config::LogFormat;
+
+/// Response header carrying the ID a request's log lines are tagged with.
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+/// How much of a secret `redact` leaves in.
+const REDACTED_PREFIX: usize = 4;
+
+/// Whether logging has been set up, which can only happen once.
+static LOGGING_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Sets up logging to stdout, as text or one JSON object per line. Lines logged through `log`,
+/// e.g. by Rocket, come out the same way.
+pub fn init(level: LevelFilter, format: LogFormat) {
+    init_to(level, format, io::stdout);
+}
+
+/// Like `init`, but logs to stderr, for tools whose output goes to stdout. Has to be called
+/// before anything reads `CONFIG`, which would set up logging to stdout otherwise.
+pub fn init_stderr(level: LevelFilter, format: LogFormat) {
+    init_to(level, format, io::stderr);
+}
+
+fn init_to<W>(level: LevelFilter, format: LogFormat, writer: W)
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    if LOGGING_STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(writer);
+    let result = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .try_init(),
+    };
+    result.expect("CONFIG: failed to initialise logger");
+}
+
+/// Shortens a secret, e.g. a session token, to something that can be logged but is still enough
+/// to tell it apart from others.
+pub fn redact(secret: &str) -> String {
+    let shown: String = secret.chars().take(REDACTED_PREFIX).collect();
+    format!("{}...", shown)
+}
+
+/// Identifies an HTTP request in logs, and in its response's headers.
+struct RequestId(String);
+
+fn request_id<'r>(request: &'r Request<'_>) -> &'r str {
+    &request
+        .local_cache(|| RequestId(Uuid::new_v4().to_string()))
+        .0
+}
+
+/// Runs a route's handler in a span for the request, so everything logged while handling it
+/// carries the request's ID.
+#[derive(Clone)]
+struct Traced(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for Traced {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        // Only the path, so query parameters stay out of the logs
+        let span = info_span!(
+            "request",
+            id = request_id(request),
+            method = request.method().as_str(),
+            path = %request.uri().path()
+        );
+        self.0.handle(request, data).instrument(span).await
+    }
+}
+
+/// Wraps routes' handlers so their logs are tagged with the request.
+pub fn traced(routes: Vec<Route>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(Traced(route.handler));
+            route
+        })
+        .collect()
+}
+
+/// Tells clients each request's ID, so problems they report can be found in the logs.
+pub struct RequestIds;
+
+#[rocket::async_trait]
+impl Fairing for RequestIds {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request IDs",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        response.set_raw_header(REQUEST_ID_HEADER, request_id(request).to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logging::redact;
+
+    #[test]
+    fn test_redact() {
+        let token = "c2VjcmV0LXNlc3Npb24tdG9rZW4";
+        let redacted = redact(token);
+        assert_eq!(redacted, "c2Vj...");
+        assert!(!redacted.contains(&token[..8]));
+        assert_eq!(redact("ab"), "ab...");
+    }
+}
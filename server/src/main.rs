@@ -1,35 +1,99 @@
 use std::error::Error;
 use std::sync::Arc;
-use std::{env, fs};
+use std::{env, fs, process};
 
-use dotenv::dotenv;
-
-use rolecall::config::CONFIG;
-use rolecall::db::DbManager;
+use rolecall::config::{self, Args, Config, ConfigError, Settings, StorageConfig, CONFIG};
+use rolecall::db::{self, Database};
 use rolecall::game;
+use rolecall::game::bus;
+use rolecall::game::server::Games;
 use rolecall::web::Api;
 
+/// What game clients are told when the server stops.
+const SHUTDOWN_REASON: &str = "server shutting down";
+
 #[rocket::main]
 async fn main() {
-    dotenv().expect("MAIN: failed loading config");
+    let args = Args::parse(env::args().skip(1)).unwrap_or_else(|e| exit_with(e));
+    if args.help {
+        print!("{}", config::usage());
+        return;
+    }
+    let settings = Settings::gather(&args).unwrap_or_else(|e| exit_with(e));
+    let loaded = Config::from_settings(&settings);
+    if args.print_config {
+        print!("{}", settings);
+    }
+    let loaded = loaded.unwrap_or_else(|e| exit_with(e));
+    if args.print_config {
+        return;
+    }
+    config::install(loaded);
+
     create_upload_dir().unwrap();
 
     let db = create_db().await.expect("MAIN: failed loading database");
-    let api = Api::new(db.clone()).expect("MAIN: failed starting web server");
+    let games = Games::start(bus::create_bus(), db.clone())
+        .await
+        .expect("MAIN: failed connecting to the bus");
+    let api = Api::new(db.clone(), games.clone()).expect("MAIN: failed starting web server");
+
+    tokio::spawn(game::conn::ws_listen(
+        db,
+        games.clone(),
+        &CONFIG.listen_addr,
+    ));
 
-    tokio::spawn(game::conn::ws_listen(db, &CONFIG.listen_addr));
+    // Rocket stops by itself on the same signals, but games need telling straight away
+    let stopping = games.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        stopping.shutdown(SHUTDOWN_REASON).await;
+    });
+
+    // Returns once Rocket has finished with any requests in progress
     api.start().await.expect("MAIN: failed during execution");
+    games.shutdown(SHUTDOWN_REASON).await;
 }
 
-async fn create_db() -> Result<Arc<DbManager>, Box<dyn Error>> {
-    let db = DbManager::new().await?;
-    db.clear_tables().await?;
+/// Config problems are the user's to fix, so they get a message rather than a panic.
+fn exit_with(e: ConfigError) -> ! {
+    eprintln!("rolecall: {}", e);
+    process::exit(2);
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate =
+            signal(SignalKind::terminate()).expect("MAIN: failed listening for SIGTERM");
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+async fn create_db() -> Result<Arc<dyn Database>, Box<dyn Error>> {
+    let db = db::create_database()?;
+    if CONFIG.db_reset {
+        tracing::warn!("resetting the database, as db_reset is set");
+        db.clear_tables().await?;
+    }
     db.create_tables().await?;
 
-    Ok(Arc::new(db))
+    Ok(db)
 }
 
 fn create_upload_dir() -> Result<(), Box<dyn Error>> {
+    // only needed when storing uploads on local disk
+    if let StorageConfig::S3 { .. } = CONFIG.storage {
+        return Ok(());
+    }
+
     // check the directory doesn't exist
     let mut path = env::current_dir()?;
     path.push(&CONFIG.upload_dir);
@@ -42,6 +106,6 @@ fn create_upload_dir() -> Result<(), Box<dyn Error>> {
     }
 
     fs::create_dir(&path)?;
-    log::info!("creating upload directory: {:?}", path.to_str());
+    tracing::info!("creating upload directory: {:?}", path.to_str());
     Ok(())
 }
@@ -0,0 +1,114 @@
+use std::future::Future;
+use std::time::Instant;
+
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, Response};
+
+lazy_static! {
+    pub static ref GAME_SERVERS: IntGauge = register_int_gauge!(
+        "rolecall_game_servers",
+        "Game servers running on this instance"
+    )
+    .unwrap();
+    pub static ref GAME_CLIENTS: IntGauge = register_int_gauge!(
+        "rolecall_game_clients",
+        "Clients connected to games on this instance"
+    )
+    .unwrap();
+    pub static ref MESSAGES_RECEIVED: IntCounterVec = register_int_counter_vec!(
+        "rolecall_messages_received_total",
+        "Messages received from game clients",
+        &["type"]
+    )
+    .unwrap();
+    pub static ref MESSAGES_BROADCAST: IntCounterVec = register_int_counter_vec!(
+        "rolecall_messages_broadcast_total",
+        "Messages sent to everyone in a game",
+        &["type"]
+    )
+    .unwrap();
+    pub static ref UNAUTHORISED_MESSAGES: IntCounter = register_int_counter!(
+        "rolecall_unauthorised_messages_total",
+        "Messages from game clients that weren't allowed to send them"
+    )
+    .unwrap();
+    pub static ref MALFORMED_MESSAGES: IntCounter = register_int_counter!(
+        "rolecall_malformed_messages_total",
+        "Messages from game clients that couldn't be parsed"
+    )
+    .unwrap();
+    pub static ref DB_QUERY_SECONDS: HistogramVec = register_histogram_vec!(
+        "rolecall_db_query_seconds",
+        "Time taken by database operations",
+        &["query"]
+    )
+    .unwrap();
+    pub static ref UPLOAD_BYTES: IntCounter = register_int_counter!(
+        "rolecall_upload_bytes_total",
+        "Bytes of files uploaded, including rejected ones"
+    )
+    .unwrap();
+    pub static ref HTTP_REQUEST_SECONDS: HistogramVec = register_histogram_vec!(
+        "rolecall_http_request_seconds",
+        "Time taken to answer HTTP requests",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+}
+
+/// Renders every metric in the Prometheus text format.
+pub fn encode() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+/// Runs a database operation, recording how long it took.
+pub async fn time_query<T>(query: &str, operation: impl Future<Output = T>) -> T {
+    let timer = DB_QUERY_SECONDS.with_label_values(&[query]).start_timer();
+    let result = operation.await;
+    timer.observe_duration();
+    result
+}
+
+/// Records how long each HTTP request takes, by the route that handled it.
+pub struct HttpMetrics;
+
+/// When Rocket started on a request.
+struct RequestStart(Instant);
+
+#[rocket::async_trait]
+impl Fairing for HttpMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "HTTP metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let start = request.local_cache(|| RequestStart(Instant::now()));
+        // Label by route rather than path, so there's a bounded number of series
+        let route = request
+            .route()
+            .and_then(|route| route.name.as_deref())
+            .unwrap_or("unmatched");
+        HTTP_REQUEST_SECONDS
+            .with_label_values(&[
+                request.method().as_str(),
+                route,
+                &response.status().code.to_string(),
+            ])
+            .observe(start.0.elapsed().as_secs_f64());
+    }
+}
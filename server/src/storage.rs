@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use s3::creds::Credentials;
+use s3::error::S3Error;
+use s3::{Bucket, Region};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::config::{StorageConfig, CONFIG};
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(std::io::Error),
+    S3(String),
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<S3Error> for StorageError {
+    fn from(e: S3Error) -> Self {
+        Self::S3(e.to_string())
+    }
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+/// A file found by [`Storage::list`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageEntry {
+    pub key: String,
+    /// When the file was last written.
+    pub modified: SystemTime,
+}
+
+/// Somewhere to keep uploaded files. Files are identified by a key, which is a plain file name.
+#[rocket::async_trait]
+pub trait Storage: Send + Sync {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<(), StorageError>;
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError>;
+    async fn exists(&self, key: &str) -> Result<bool, StorageError>;
+    async fn delete(&self, key: &str) -> Result<(), StorageError>;
+    /// Lists every stored file.
+    async fn list(&self) -> Result<Vec<StorageEntry>, StorageError>;
+
+    /// The URL clients should use to fetch the file with the given key.
+    fn url(&self, key: &str) -> String;
+
+    /// The directory files are stored in, if they are on local disk and can be served directly.
+    fn local_dir(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// Creates the storage backend selected in the config.
+pub fn create_storage() -> Result<Arc<dyn Storage>, StorageError> {
+    Ok(match &CONFIG.storage {
+        StorageConfig::Local => Arc::new(LocalStorage::new(&CONFIG.upload_dir)),
+        StorageConfig::S3 {
+            endpoint,
+            region,
+            bucket,
+            access_key,
+            secret_key,
+            presign_secs,
+        } => Arc::new(S3Storage::new(
+            endpoint,
+            region,
+            bucket,
+            access_key,
+            secret_key,
+            *presign_secs,
+        )?),
+    })
+}
+
+/// Stores files in a directory on local disk, served by Rocket under `/images`.
+pub struct LocalStorage {
+    dir: String,
+}
+
+impl LocalStorage {
+    pub fn new(dir: &str) -> Self {
+        Self {
+            dir: dir.to_string(),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        let mut path = PathBuf::from(&self.dir);
+        path.push(key);
+        path
+    }
+}
+
+#[rocket::async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<(), StorageError> {
+        Ok(tokio::fs::write(self.path(key), data).await?)
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        Ok(tokio::fs::read(self.path(key)).await?)
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, StorageError> {
+        match tokio::fs::metadata(self.path(key)).await {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        Ok(tokio::fs::remove_file(self.path(key)).await?)
+    }
+
+    async fn list(&self) -> Result<Vec<StorageEntry>, StorageError> {
+        let mut files = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if metadata.is_file() {
+                files.push(StorageEntry {
+                    key: entry.file_name().to_string_lossy().into_owned(),
+                    modified: metadata.modified()?,
+                });
+            }
+        }
+        Ok(files)
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("/images/{}", key)
+    }
+
+    fn local_dir(&self) -> Option<&str> {
+        Some(&self.dir)
+    }
+}
+
+/// Keeps files in memory, served through the `/images` route. Nothing survives a restart, so this
+/// is only meant for tests.
+#[derive(Default)]
+pub struct MemoryStorage {
+    files: Mutex<HashMap<String, (Vec<u8>, SystemTime)>>,
+}
+
+fn not_found(key: &str) -> StorageError {
+    StorageError::Io(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("no such file: {}", key),
+    ))
+}
+
+#[rocket::async_trait]
+impl Storage for MemoryStorage {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<(), StorageError> {
+        let mut files = self.files.lock().unwrap();
+        files.insert(key.to_string(), (data.to_vec(), SystemTime::now()));
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        let files = self.files.lock().unwrap();
+        let (data, _) = files.get(key).ok_or_else(|| not_found(key))?;
+        Ok(data.clone())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, StorageError> {
+        Ok(self.files.lock().unwrap().contains_key(key))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let mut files = self.files.lock().unwrap();
+        files.remove(key).map(|_| ()).ok_or_else(|| not_found(key))
+    }
+
+    async fn list(&self) -> Result<Vec<StorageEntry>, StorageError> {
+        let files = self.files.lock().unwrap();
+        Ok(files
+            .iter()
+            .map(|(key, (_, modified))| StorageEntry {
+                key: key.clone(),
+                modified: *modified,
+            })
+            .collect())
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("/images/{}", key)
+    }
+}
+
+/// Stores files in a bucket on an S3-compatible service. Clients either get a presigned URL
+/// pointing straight at the bucket, or (if `presign_secs` is zero) fetch files through the
+/// `/images` route, which proxies them.
+pub struct S3Storage {
+    bucket: Bucket,
+    presign_secs: u32,
+}
+
+impl S3Storage {
+    pub fn new(
+        endpoint: &str,
+        region: &str,
+        bucket: &str,
+        access_key: &str,
+        secret_key: &str,
+        presign_secs: u32,
+    ) -> Result<Self, StorageError> {
+        let region = Region::Custom {
+            region: region.to_string(),
+            endpoint: endpoint.to_string(),
+        };
+        let credentials = Credentials::new(Some(access_key), Some(secret_key), None, None, None)
+            .map_err(|e| StorageError::S3(e.to_string()))?;
+        // Path-style addressing is what MinIO and most self-hosted services expect
+        let bucket = Bucket::new(bucket, region, credentials)?.with_path_style();
+
+        Ok(Self {
+            bucket,
+            presign_secs,
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl Storage for S3Storage {
+    async fn put(&self, key: &str, data: &[u8]) -> Result<(), StorageError> {
+        self.bucket.put_object(key, data).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>, StorageError> {
+        let response = self.bucket.get_object(key).await?;
+        Ok(response.bytes().to_vec())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, StorageError> {
+        match self.bucket.head_object(key).await {
+            Ok(_) => Ok(true),
+            Err(S3Error::Http(404, _)) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), StorageError> {
+        self.bucket.delete_object(key).await?;
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<StorageEntry>, StorageError> {
+        let pages = self.bucket.list(String::new(), None).await?;
+        Ok(pages
+            .into_iter()
+            .flat_map(|page| page.contents)
+            .map(|object| {
+                // If the timestamp can't be read, treat the file as new so nothing is purged early
+                let modified = OffsetDateTime::parse(&object.last_modified, &Rfc3339)
+                    .map(SystemTime::from)
+                    .unwrap_or_else(|_| SystemTime::now());
+                StorageEntry {
+                    key: object.key,
+                    modified,
+                }
+            })
+            .collect())
+    }
+
+    fn url(&self, key: &str) -> String {
+        if self.presign_secs > 0 {
+            match self.bucket.presign_get(key, self.presign_secs, None) {
+                Ok(url) => return url,
+                Err(e) => warn!("failed presigning URL for {}: {}", key, e),
+            }
+        }
+        format!("/images/{}", key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{LocalStorage, MemoryStorage, S3Storage, Storage};
+    use std::env;
+    use std::time::Duration;
+
+    async fn check_storage(storage: &dyn Storage) {
+        let key = "storage_test.png";
+        let data = vec![0xde, 0xad, 0xbe, 0xef];
+
+        storage.put(key, &data).await.unwrap();
+        assert!(storage.exists(key).await.unwrap());
+        assert_eq!(storage.get(key).await.unwrap(), data);
+        let listed = storage.list().await.unwrap();
+        let entry = listed.iter().find(|entry| entry.key == key).unwrap();
+        assert!(entry.modified.elapsed().unwrap_or_default() < Duration::from_secs(60));
+
+        storage.delete(key).await.unwrap();
+        assert!(!storage.exists(key).await.unwrap());
+        let listed = storage.list().await.unwrap();
+        assert!(listed.iter().all(|entry| entry.key != key));
+    }
+
+    #[tokio::test]
+    async fn test_local_storage() {
+        let dir = env::temp_dir();
+        let storage = LocalStorage::new(dir.to_str().unwrap());
+        check_storage(&storage).await;
+        assert_eq!(storage.url("foo.png"), "/images/foo.png");
+    }
+
+    #[tokio::test]
+    async fn test_memory_storage() {
+        let storage = MemoryStorage::default();
+        check_storage(&storage).await;
+        assert!(storage.get("missing.png").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_s3_storage() {
+        // Runs against a local MinIO-style server if one is configured, e.g.
+        //   RC_TEST_S3_ENDPOINT=http://localhost:9100 RC_TEST_S3_BUCKET=rolecall
+        let endpoint = match env::var("RC_TEST_S3_ENDPOINT") {
+            Ok(endpoint) => endpoint,
+            Err(_) => {
+                eprintln!("SKIPPED test_s3_storage: set RC_TEST_S3_ENDPOINT to run against S3");
+                return;
+            }
+        };
+        let bucket = env::var("RC_TEST_S3_BUCKET").unwrap_or("rolecall".to_string());
+        let access_key = env::var("RC_TEST_S3_ACCESS_KEY").unwrap_or("minioadmin".to_string());
+        let secret_key = env::var("RC_TEST_S3_SECRET_KEY").unwrap_or("minioadmin".to_string());
+
+        let storage =
+            S3Storage::new(&endpoint, "us-east-1", &bucket, &access_key, &secret_key, 60)
+                .unwrap();
+        check_storage(&storage).await;
+        assert!(storage.url("foo.png").starts_with(&endpoint));
+    }
+}
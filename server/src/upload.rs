@@ -0,0 +1,216 @@
+use std::fmt::{Display, Formatter};
+use std::io::Cursor;
+
+use image::io::Reader;
+use image::{DynamicImage, GenericImageView, ImageOutputFormat};
+use sha2::{Digest, Sha256};
+
+use crate::config::CONFIG;
+use crate::storage::{Storage, StorageError};
+
+#[derive(Debug)]
+pub enum UploadError {
+    NotAnImage,
+    TooLarge { width: u32, height: u32 },
+    Image(image::ImageError),
+}
+
+impl From<image::ImageError> for UploadError {
+    fn from(e: image::ImageError) -> Self {
+        Self::Image(e)
+    }
+}
+
+impl Display for UploadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+/// Sizes (in pixels, along the longest side) of the thumbnails generated for each upload.
+pub const THUMBNAIL_SIZES: [u32; 2] = [128, 512];
+
+/// An uploaded image, re-encoded as PNG.
+pub struct ProcessedImage {
+    pub data: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    /// One PNG per entry of `THUMBNAIL_SIZES`, in the same order.
+    pub thumbnails: Vec<Vec<u8>>,
+}
+
+impl ProcessedImage {
+    /// Total bytes taken up by the image and its thumbnails.
+    pub fn size(&self) -> usize {
+        self.data.len() + self.thumbnails.iter().map(Vec::len).sum::<usize>()
+    }
+}
+
+/// Details of a processed image needed to record it in the database.
+pub struct StoredFile {
+    pub hash: String,
+    pub key: String,
+    pub width: i32,
+    pub height: i32,
+    pub size: i64,
+    /// The image itself, which the database stores once it holds a reference to the file. Left
+    /// out if the data is stored some other way.
+    pub image: Option<ProcessedImage>,
+}
+
+impl StoredFile {
+    pub fn new(image: ProcessedImage) -> Self {
+        let hash = content_hash(&image.data);
+        let key = image_key(&hash);
+        Self {
+            hash,
+            key,
+            width: image.width as i32,
+            height: image.height as i32,
+            size: image.size() as i64,
+            image: Some(image),
+        }
+    }
+}
+
+/// Returns the hex-encoded SHA-256 hash that identifies a stored file.
+pub fn content_hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Returns the storage key for an image with the given content hash.
+pub fn image_key(hash: &str) -> String {
+    format!("{}.png", hash)
+}
+
+/// Returns the key a thumbnail of the given size is stored at, next to the full-size image.
+pub fn thumbnail_key(key: &str, size: u32) -> String {
+    let stem = key.strip_suffix(".png").unwrap_or(key);
+    format!("{}_{}.png", stem, size)
+}
+
+/// Stores an image and its thumbnails.
+pub async fn save_image(
+    storage: &dyn Storage,
+    key: &str,
+    image: &ProcessedImage,
+) -> Result<(), StorageError> {
+    storage.put(key, &image.data).await?;
+    for (&size, data) in THUMBNAIL_SIZES.iter().zip(image.thumbnails.iter()) {
+        storage.put(&thumbnail_key(key, size), data).await?;
+    }
+    Ok(())
+}
+
+/// Removes an image and its thumbnails. Missing thumbnails are ignored.
+pub async fn remove_image(storage: &dyn Storage, key: &str) -> Result<(), StorageError> {
+    storage.delete(key).await?;
+    for &size in THUMBNAIL_SIZES.iter() {
+        if let Err(e) = storage.delete(&thumbnail_key(key, size)).await {
+            warn!("failed removing thumbnail for {}: {}", key, e);
+        }
+    }
+    Ok(())
+}
+
+fn encode_png(image: &DynamicImage) -> Result<Vec<u8>, UploadError> {
+    let mut buf = Cursor::new(Vec::new());
+    image.write_to(&mut buf, ImageOutputFormat::Png)?;
+    Ok(buf.into_inner())
+}
+
+fn create_thumbnail(image: &DynamicImage, size: u32) -> Result<Vec<u8>, UploadError> {
+    let (width, height) = image.dimensions();
+    if width <= size && height <= size {
+        // Don't scale up small images
+        encode_png(image)
+    } else {
+        encode_png(&image.thumbnail(size, size))
+    }
+}
+
+/// Decodes an uploaded file and re-encodes it as a PNG. Re-encoding discards any metadata
+/// (EXIF etc.) that came with the original file.
+pub fn process_image(data: &[u8]) -> Result<ProcessedImage, UploadError> {
+    let reader = Reader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|_| UploadError::NotAnImage)?;
+    let format = reader.format().ok_or(UploadError::NotAnImage)?;
+
+    // Check dimensions from the header before decoding, so we don't allocate a huge buffer
+    let (width, height) = reader
+        .into_dimensions()
+        .map_err(|_| UploadError::NotAnImage)?;
+    if width == 0 || height == 0 {
+        return Err(UploadError::NotAnImage);
+    }
+    if width > CONFIG.max_image_dim || height > CONFIG.max_image_dim {
+        return Err(UploadError::TooLarge { width, height });
+    }
+
+    // Reading the dimensions used up the reader, so start again with the format we found
+    let reader = Reader::with_format(Cursor::new(data), format);
+    let image = reader.decode().map_err(|_| UploadError::NotAnImage)?;
+    let (width, height) = image.dimensions();
+    let thumbnails = THUMBNAIL_SIZES
+        .iter()
+        .map(|&size| create_thumbnail(&image, size))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(ProcessedImage {
+        data: encode_png(&image)?,
+        width,
+        height,
+        thumbnails,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::upload::{
+        content_hash, process_image, thumbnail_key, UploadError, THUMBNAIL_SIZES,
+    };
+    use image::{GenericImageView, ImageOutputFormat, RgbImage};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_process_image() {
+        // Non-images are rejected
+        let result = process_image(&[0xde, 0xad, 0xbe, 0xef]);
+        assert!(matches!(result, Err(UploadError::NotAnImage)));
+
+        // Other formats are converted to PNG with the same dimensions
+        let mut jpeg = Cursor::new(Vec::new());
+        image::DynamicImage::ImageRgb8(RgbImage::new(30, 20))
+            .write_to(&mut jpeg, ImageOutputFormat::Jpeg(90))
+            .unwrap();
+        let processed = process_image(&jpeg.into_inner()).unwrap();
+        assert_eq!((processed.width, processed.height), (30, 20));
+        assert_eq!(
+            image::guess_format(&processed.data).unwrap(),
+            image::ImageFormat::Png
+        );
+
+        // Thumbnails fit within their size
+        let image = image::DynamicImage::ImageRgb8(RgbImage::new(1000, 250));
+        let mut png = Cursor::new(Vec::new());
+        image.write_to(&mut png, ImageOutputFormat::Png).unwrap();
+        let png_data = png.into_inner();
+        let processed = process_image(&png_data).unwrap();
+        assert_eq!(processed.thumbnails.len(), THUMBNAIL_SIZES.len());
+        let dimensions: Vec<_> = processed
+            .thumbnails
+            .iter()
+            .map(|data| image::load_from_memory(data).unwrap().dimensions())
+            .collect();
+        assert_eq!(dimensions, vec![(128, 32), (512, 128)]);
+
+        assert_eq!(thumbnail_key("abc.png", 128), "abc_128.png");
+
+        // Re-encoding is deterministic, so identical uploads hash the same
+        let again = process_image(&png_data).unwrap();
+        assert_eq!(content_hash(&processed.data), content_hash(&again.data));
+    }
+}
@@ -1,5 +1,9 @@
 use rocket::{http::ContentType, fs::FileServer, response::content::{RawHtml, self}};
-use rocket::{Data, State};
+use rocket::figment::Figment;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::log::LogLevel;
+use rocket::{Build, Data, Rocket, State};
 use rocket::serde::json::{Json};
 use rocket_multipart_form_data::{
     MultipartFormData, MultipartFormDataField, MultipartFormDataOptions,
@@ -7,40 +11,66 @@ use rocket_multipart_form_data::{
 use serde::{Deserialize, Serialize};
 
 use std::error::Error;
+use std::fmt::Display;
+use std::future::Future;
 use std::sync::Arc;
+use std::time::Duration;
+
+use uuid::Uuid;
 
 use crate::config::CONFIG;
-use crate::db::{DbError, DbManager, Game, Object};
+use crate::game::server::{Games, ServerStatus};
+use crate::db::{
+    self, Account, Database, DbError, Folder, Game, GameDetails, ObjFilter, Object, Profile,
+    ProfileSettings, ShareItem, ShareWith,
+};
+use crate::logging::{self, RequestIds};
+use crate::metrics::{self, HttpMetrics};
+use crate::upload::{self, StoredFile, UploadError};
 
 use rocket_cors::CorsOptions;
-use std::fs::File;
-use std::io::Write;
-use uuid::Uuid;
+
+/// How long each readiness check gets before it counts as failed.
+const READY_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
 
 pub struct Api {
-    db: Arc<DbManager>,
+    db: Arc<dyn Database>,
+    games: Arc<Games>,
     index: String,
     game: String,
 }
 
 impl Api {
-    pub fn new(db: Arc<DbManager>) -> Result<Self, Box<dyn Error>> {
+    pub fn new(db: Arc<dyn Database>, games: Arc<Games>) -> Result<Self, Box<dyn Error>> {
         let index = process_html(std::fs::read_to_string("../client/index.html")?);
         let game = process_html(std::fs::read_to_string("../client/game.html")?);
 
-        Ok(Self { db, index, game })
+        Ok(Self {
+            db,
+            games,
+            index,
+            game,
+        })
     }
 
-    pub async fn start(self) -> Result<(), DbError> {
-        let _rocket = rocket::build()
+    /// Builds the API without the client's static files, which may not have been built yet.
+    pub fn build(self) -> Rocket<Build> {
+        let rocket = rocket::custom(Self::rocket_config())
             .mount(
                 "/",
-                routes![
+                logging::traced(routes![
                     index,
                     game,
+                    get_metrics,
+                    healthz,
+                    readyz,
                     new_user,
                     check_user,
                     auth_user,
+                    set_nickname,
+                    get_profile,
+                    set_profile,
+                    set_avatar,
                     new_game,
                     join_game,
                     hosted_games,
@@ -48,11 +78,51 @@ impl Api {
                     create_obj,
                     get_owned_objs,
                     get_other_objs,
+                    get_usage,
+                    rename_obj,
+                    replace_obj,
+                    move_obj,
+                    tag_obj,
+                    create_folder,
+                    get_folders,
+                    share,
+                    unshare,
+                    get_shared_objs,
                     delete_obj,
-                ],
+                    admin_users,
+                    admin_disable_user,
+                    admin_games,
+                    admin_servers,
+                    admin_close_server,
+                    admin_purge_files,
+                ]),
             )
             .attach(CorsOptions::default().to_cors().unwrap())
-            .mount("/images", FileServer::from(&CONFIG.upload_dir))
+            .attach(HttpMetrics)
+            .attach(RequestIds);
+
+        // Serve uploads straight from disk if we can, otherwise proxy them from storage
+        let rocket = match self.db.storage().local_dir() {
+            Some(dir) => rocket.mount("/images", FileServer::from(dir)),
+            None => rocket.mount("/images", routes![get_image]),
+        };
+
+        rocket.manage(self)
+    }
+
+    /// Rocket's settings, taken from our config rather than `Rocket.toml`.
+    fn rocket_config() -> Figment {
+        rocket::Config::figment()
+            .merge(("address", CONFIG.http.address))
+            .merge(("port", CONFIG.http.port))
+            .merge(("workers", CONFIG.http.workers))
+            .merge(("keep_alive", CONFIG.http.keep_alive))
+            .merge(("log_level", LogLevel::Critical))
+    }
+
+    pub async fn start(self) -> Result<(), DbError> {
+        let _rocket = self
+            .build()
             .mount("/static", FileServer::from("../client/public/"))
             .mount("/dist", FileServer::from("../client/dist"))
             .mount(
@@ -63,7 +133,6 @@ impl Api {
                 "/react-dom",
                 FileServer::from("../client/node_modules/react-dom/umd/"),
             )
-            .manage(self)
             .launch()
             .await?;
         Ok(())
@@ -76,6 +145,24 @@ struct Request {
     token: String,
 }
 
+/// The session token from an `Authorization: Bearer` header, for routes without a body. A
+/// missing header gives an empty token, which fails authentication like any other bad token.
+struct BearerToken(String);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BearerToken {
+    type Error = ();
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, ()> {
+        let token = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        Outcome::Success(BearerToken(token.to_string()))
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
 struct UserCreateRequest {
@@ -91,6 +178,21 @@ struct UserAuthRequest {
     password: String,
 }
 
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct NicknameRequest {
+    token: String,
+    nickname: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ProfileRequest {
+    token: String,
+    #[serde(flatten)]
+    settings: ProfileSettings,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
 struct GameCreateRequest {
@@ -106,6 +208,85 @@ struct ObjCreateRequest {
     data: String,
 }
 
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ObjRenameRequest {
+    token: String,
+    name: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ObjListRequest {
+    token: String,
+    folder: Option<i32>,
+    tag: Option<String>,
+    search: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ObjMoveRequest {
+    token: String,
+    folder: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ObjTagRequest {
+    token: String,
+    tags: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct FolderCreateRequest {
+    token: String,
+    name: String,
+}
+
+/// Shares either an object (by name) or a folder, with either a user (by `nickname#tag`) or the
+/// members of a game.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ShareRequest {
+    token: String,
+    object: Option<String>,
+    folder: Option<i32>,
+    username: Option<String>,
+    game: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct AdminUsersRequest {
+    token: String,
+    search: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct AdminDisableRequest {
+    token: String,
+    disabled: bool,
+}
+
+impl ShareRequest {
+    fn parse(&self) -> Option<(ShareItem, ShareWith)> {
+        let item = match (&self.object, self.folder) {
+            (Some(name), None) => ShareItem::Object(name.clone()),
+            (None, Some(id)) => ShareItem::Folder(id),
+            _ => return None,
+        };
+        let with = match (&self.username, &self.game) {
+            (Some(username), None) => ShareWith::User(username.clone()),
+            (None, Some(game_token)) => ShareWith::Game(game_token.clone()),
+            _ => return None,
+        };
+        Some((item, with))
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
 pub struct UserResponse {
@@ -140,6 +321,92 @@ pub struct ListObjsResponse {
     pub objs: Option<Vec<Object>>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct FolderResponse {
+    pub status: bool,
+    pub msg: Option<String>,
+    pub id: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ListFoldersResponse {
+    pub status: bool,
+    pub msg: Option<String>,
+    pub folders: Option<Vec<Folder>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ProfileResponse {
+    pub status: bool,
+    pub msg: Option<String>,
+    pub profile: Option<Profile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct AdminUsersResponse {
+    pub status: bool,
+    pub msg: Option<String>,
+    pub users: Option<Vec<Account>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct AdminGamesResponse {
+    pub status: bool,
+    pub msg: Option<String>,
+    pub games: Option<Vec<GameDetails>>,
+}
+
+/// Game servers running on the instance that answered.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct AdminServersResponse {
+    pub status: bool,
+    pub msg: Option<String>,
+    pub servers: Option<Vec<ServerStatus>>,
+}
+
+/// Keys of the files that were purged.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct AdminPurgeResponse {
+    pub status: bool,
+    pub msg: Option<String>,
+    pub purged: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct CheckResult {
+    pub ok: bool,
+    pub msg: Option<String>,
+}
+
+/// Whether the server can take traffic, and if not, what's wrong.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ReadyResponse {
+    pub status: bool,
+    pub database: CheckResult,
+    pub storage: CheckResult,
+    pub websocket: CheckResult,
+}
+
+/// Storage used by a user's objects, in bytes.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct UsageResponse {
+    pub status: bool,
+    pub msg: Option<String>,
+    pub used: Option<u64>,
+    pub quota: Option<u64>,
+    pub remaining: Option<u64>,
+}
+
 #[post("/api/users", format = "json", data = "<user>")]
 async fn new_user(state: &State<Api>, user: Json<UserCreateRequest>) -> Json<UserResponse> {
     let result = state
@@ -211,26 +478,57 @@ async fn auth_user(state: &State<Api>, user: Json<UserAuthRequest>) -> Json<User
     }
 }
 
-#[post("/api/games", format = "json", data = "<game>")]
-async fn new_game(state: &State<Api>, game: Json<GameCreateRequest>) -> Json<GameResponse> {
-    let result = state.db.create_game(&game.user_token, &game.name).await;
+/// Changes the user's nickname. They get a new tag with it, so the full username is sent back.
+#[post("/api/users/nickname", format = "json", data = "<req>")]
+async fn set_nickname(state: &State<Api>, req: Json<NicknameRequest>) -> Json<UserResponse> {
+    let nickname = req.nickname.trim();
+    if nickname.is_empty() || nickname.contains('#') {
+        return Json(UserResponse {
+            status: false,
+            msg: Some("invalid nickname".to_string()),
+            token: None,
+            username: None,
+        });
+    }
+
+    let result = match state.db.get_account(&req.token).await {
+        Ok((_, old_username)) => state
+            .db
+            .set_nickname(&req.token, nickname)
+            .await
+            .map(|(user_id, username)| (user_id, old_username, username)),
+        Err(e) => Err(e),
+    };
 
     match result {
-        Ok(token) => Json(GameResponse {
-            status: true,
-            msg: None,
-            token: Some(token),
+        Ok((user_id, old_username, username)) => {
+            // Let anyone in a running game know who this is now
+            state
+                .games
+                .rename_user(user_id, &old_username, &username)
+                .await;
+            Json(UserResponse {
+                status: true,
+                msg: None,
+                token: None,
+                username: Some(username),
+            })
+        }
+        Err(DbError::Auth) => Json(UserResponse {
+            status: false,
+            msg: Some("user not found".to_string()),
+            token: None,
             username: None,
         }),
-        Err(DbError::Auth) => Json(GameResponse {
+        Err(DbError::AlreadyExists) => Json(UserResponse {
             status: false,
-            msg: Some("user not found".to_string()),
+            msg: Some("nickname unavailable".to_string()),
             token: None,
             username: None,
         }),
         Err(e) => {
-            warn!("ERROR: {}", e);
-            Json(GameResponse {
+            warn!("API: error: {}", e);
+            Json(UserResponse {
                 status: false,
                 msg: Some("miscellaneous error".to_string()),
                 token: None,
@@ -240,65 +538,70 @@ async fn new_game(state: &State<Api>, game: Json<GameCreateRequest>) -> Json<Gam
     }
 }
 
-#[post("/api/games/hosted", format = "json", data = "<req>")]
-async fn hosted_games(state: &State<Api>, req: Json<Request>) -> Json<ListGamesResponse> {
-    let result = state.db.get_hosted_games(&req.token).await;
+/// Longest pronouns or time zone name that can be stored, in bytes.
+const MAX_PROFILE_FIELD: usize = 64;
+/// Largest preferences blob that can be stored, in bytes of JSON.
+const MAX_PREFERENCES: usize = 16 * 1024;
 
-    match result {
-        Ok(games) => Json(ListGamesResponse {
-            status: true,
-            msg: None,
-            games: Some(games),
-        }),
-        Err(DbError::Auth) => Json(ListGamesResponse {
-            status: false,
-            msg: Some("user not found".to_string()),
-            games: None,
-        }),
-        Err(e) => {
-            warn!("ERROR: {}", e);
-            Json(ListGamesResponse {
-                status: false,
-                msg: Some("miscellaneous error".to_string()),
-                games: None,
-            })
+/// Checks profile settings before they're stored, returning the problem if there is one.
+fn check_profile(settings: &ProfileSettings) -> Result<(), &'static str> {
+    if let Some(colour) = &settings.colour {
+        let hex = colour.strip_prefix('#').unwrap_or_default();
+        if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err("colour must be in the form #rrggbb");
         }
     }
+    let too_long = |field: &Option<String>| {
+        field
+            .as_ref()
+            .map_or(false, |field| field.len() > MAX_PROFILE_FIELD)
+    };
+    if too_long(&settings.pronouns) || too_long(&settings.timezone) {
+        return Err("profile field too long");
+    }
+    if settings.preferences.to_string().len() > MAX_PREFERENCES {
+        return Err("preferences too large");
+    }
+    Ok(())
 }
 
-#[post("/api/games/joined", format = "json", data = "<req>")]
-async fn joined_games(state: &State<Api>, req: Json<Request>) -> Json<ListGamesResponse> {
-    let result = state.db.get_joined_games(&req.token).await;
+#[get("/api/users/profile")]
+async fn get_profile(state: &State<Api>, token: BearerToken) -> Json<ProfileResponse> {
+    let result = state.db.get_profile(&token.0).await;
 
     match result {
-        Ok(games) => Json(ListGamesResponse {
+        Ok(profile) => Json(ProfileResponse {
             status: true,
             msg: None,
-            games: Some(games),
+            profile: Some(profile),
         }),
-        Err(DbError::Auth) => Json(ListGamesResponse {
+        Err(DbError::Auth) => Json(ProfileResponse {
             status: false,
             msg: Some("user not found".to_string()),
-            games: None,
+            profile: None,
         }),
         Err(e) => {
             warn!("ERROR: {}", e);
-            Json(ListGamesResponse {
+            Json(ProfileResponse {
                 status: false,
                 msg: Some("miscellaneous error".to_string()),
-                games: None,
+                profile: None,
             })
         }
     }
 }
 
-#[post("/api/games/<game_token>/join", format = "json", data = "<req>")]
-async fn join_game(
-    state: &State<Api>,
-    game_token: String,
-    req: Json<Request>,
-) -> Json<Response> {
-    let result = state.db.join_game(&req.token, &game_token).await;
+/// Replaces the user's profile settings. Fields left out are cleared.
+#[put("/api/users/profile", format = "json", data = "<req>")]
+async fn set_profile(state: &State<Api>, req: Json<ProfileRequest>) -> Json<Response> {
+    if let Err(msg) = check_profile(&req.settings) {
+        return Json(Response {
+            status: false,
+            msg: Some(msg.to_string()),
+        });
+    }
+
+    let result = state.db.set_profile(&req.token, &req.settings).await;
 
     match result {
         Ok(_) => Json(Response {
@@ -318,80 +621,497 @@ async fn join_game(
         }
     }
 }
-fn write_data(path: &str, data: &Vec<u8>) -> Result<(), Box<dyn Error>> {
-    let mut file = File::create(path)?;
-    file.write_all(data)?;
-    Ok(())
-}
 
-#[post("/api/objs/new", data = "<data>")]
-async fn create_obj(
+#[post("/api/users/profile/avatar", data = "<data>")]
+async fn set_avatar(
     state: &State<Api>,
     content_type: &ContentType,
     data: Data<'_>,
 ) -> Json<Response> {
-    // Encoding the object straight as text is a bit awkward, but we're saving it directly to the
-    // database, not as a file on the filesystem.
     let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
         MultipartFormDataField::bytes("data").size_limit(CONFIG.max_upload_mb),
         MultipartFormDataField::text("token"),
-        MultipartFormDataField::text("name"),
     ]);
 
-    let mut multipart_form_data = MultipartFormData::parse(
-        content_type,
-        data,
-        options,
-    )
-    .await
-    .unwrap();
+    let mut multipart_form_data = match MultipartFormData::parse(content_type, data, options).await
+    {
+        Ok(form) => form,
+        Err(e) => {
+            warn!("ERROR: malformed form data: {}", e);
+            return Json(Response {
+                status: false,
+                msg: Some("malformed form data".to_string()),
+            });
+        }
+    };
     let data = multipart_form_data.raw.remove("data");
     let token = multipart_form_data.texts.remove("token");
-    let name = multipart_form_data.texts.remove("name");
 
     // Validate the inputs.
-    // TODO: check that the data does actually form an image, isn't too large, etc.
-    if data.is_none() {
-        warn!("ERROR: malformed file data");
-        return Json(Response {
-            status: false,
-            msg: Some("malformed file data".to_string()),
-        });
-    }
-
-    if token.is_none() {
-        warn!("ERROR: malformed user token");
-        return Json(Response {
-            status: false,
-            msg: Some("malformed user token".to_string()),
-        });
-    }
-
-    if name.is_none() {
-        warn!("ERROR: malformed map name");
-        return Json(Response {
-            status: false,
-            msg: Some("malformed map name".to_string()),
-        });
-    }
+    let data = match data {
+        Some(mut data) => data.remove(0).raw,
+        None => {
+            warn!("ERROR: malformed file data");
+            return Json(Response {
+                status: false,
+                msg: Some("malformed file data".to_string()),
+            });
+        }
+    };
+    let token = match token {
+        Some(mut token) => token.remove(0).text,
+        None => {
+            warn!("ERROR: malformed user token");
+            return Json(Response {
+                status: false,
+                msg: Some("malformed user token".to_string()),
+            });
+        }
+    };
 
-    let data = data.unwrap().remove(0).raw;
-    let token = token.unwrap().remove(0).text;
-    let name = name.unwrap().remove(0).text;
+    let file = match prepare_upload(data).await {
+        Ok(file) => file,
+        Err(response) => return Json(response),
+    };
 
-    let uuid = Uuid::new_v4();
-    let path = format!("{}/{}.png", CONFIG.upload_dir, uuid);
+    let result = state.db.set_avatar(&token, &file).await;
 
-    // Save data to file
-    if let Err(e) = write_data(&path, &data) {
-        warn!("ERROR saving image file: {}", e);
-        return Json(Response {
-            status: false,
-            msg: Some("upload error".to_string()),
-        });
-    }
+    match result {
+        Ok(_) => Json(Response {
+            status: true,
+            msg: None,
+        }),
+        Err(DbError::Auth) => Json(Response {
+            status: false,
+            msg: Some("user not found".to_string()),
+        }),
+        Err(DbError::QuotaExceeded) => Json(Response {
+            status: false,
+            msg: Some(format!(
+                "storage quota exceeded (limit {} MB)",
+                CONFIG.user_quota_mb / 1024 / 1024
+            )),
+        }),
+        Err(DbError::Storage(e)) => {
+            warn!("ERROR saving image file: {}", e);
+            Json(Response {
+                status: false,
+                msg: Some("upload error".to_string()),
+            })
+        }
+        Err(e) => {
+            warn!("ERROR: {}", e);
+            Json(Response {
+                status: false,
+                msg: Some("miscellaneous error".to_string()),
+            })
+        }
+    }
+}
+
+#[post("/api/games", format = "json", data = "<game>")]
+async fn new_game(state: &State<Api>, game: Json<GameCreateRequest>) -> Json<GameResponse> {
+    let result = state.db.create_game(&game.user_token, &game.name).await;
+
+    match result {
+        Ok(token) => Json(GameResponse {
+            status: true,
+            msg: None,
+            token: Some(token),
+            username: None,
+        }),
+        Err(DbError::Auth) => Json(GameResponse {
+            status: false,
+            msg: Some("user not found".to_string()),
+            token: None,
+            username: None,
+        }),
+        Err(e) => {
+            warn!("ERROR: {}", e);
+            Json(GameResponse {
+                status: false,
+                msg: Some("miscellaneous error".to_string()),
+                token: None,
+                username: None,
+            })
+        }
+    }
+}
+
+#[post("/api/games/hosted", format = "json", data = "<req>")]
+async fn hosted_games(state: &State<Api>, req: Json<Request>) -> Json<ListGamesResponse> {
+    let result = state.db.get_hosted_games(&req.token).await;
+
+    match result {
+        Ok(games) => Json(ListGamesResponse {
+            status: true,
+            msg: None,
+            games: Some(games),
+        }),
+        Err(DbError::Auth) => Json(ListGamesResponse {
+            status: false,
+            msg: Some("user not found".to_string()),
+            games: None,
+        }),
+        Err(e) => {
+            warn!("ERROR: {}", e);
+            Json(ListGamesResponse {
+                status: false,
+                msg: Some("miscellaneous error".to_string()),
+                games: None,
+            })
+        }
+    }
+}
+
+#[post("/api/games/joined", format = "json", data = "<req>")]
+async fn joined_games(state: &State<Api>, req: Json<Request>) -> Json<ListGamesResponse> {
+    let result = state.db.get_joined_games(&req.token).await;
+
+    match result {
+        Ok(games) => Json(ListGamesResponse {
+            status: true,
+            msg: None,
+            games: Some(games),
+        }),
+        Err(DbError::Auth) => Json(ListGamesResponse {
+            status: false,
+            msg: Some("user not found".to_string()),
+            games: None,
+        }),
+        Err(e) => {
+            warn!("ERROR: {}", e);
+            Json(ListGamesResponse {
+                status: false,
+                msg: Some("miscellaneous error".to_string()),
+                games: None,
+            })
+        }
+    }
+}
+
+#[post("/api/games/<game_token>/join", format = "json", data = "<req>")]
+async fn join_game(
+    state: &State<Api>,
+    game_token: String,
+    req: Json<Request>,
+) -> Json<Response> {
+    let result = state.db.join_game(&req.token, &game_token).await;
+
+    match result {
+        Ok(_) => Json(Response {
+            status: true,
+            msg: None,
+        }),
+        Err(DbError::Auth) => Json(Response {
+            status: false,
+            msg: Some("user not found".to_string()),
+        }),
+        Err(e) => {
+            warn!("ERROR: {}", e);
+            Json(Response {
+                status: false,
+                msg: Some("miscellaneous error".to_string()),
+            })
+        }
+    }
+}
+
+/// Checks an uploaded image and prepares it for storage, returning the response to send on
+/// failure.
+async fn prepare_upload(data: Vec<u8>) -> Result<StoredFile, Response> {
+    metrics::UPLOAD_BYTES.inc_by(data.len() as u64);
+    // Check the data is an image and convert it to PNG. Large images take a while to decode and
+    // encode, so this runs on a blocking thread rather than holding up other requests.
+    let result = match tokio::task::spawn_blocking(move || upload::process_image(&data)).await {
+        Ok(result) => result,
+        Err(e) => {
+            warn!("ERROR processing image: {}", e);
+            return Err(Response {
+                status: false,
+                msg: Some("upload error".to_string()),
+            });
+        }
+    };
+    let image = match result {
+        Ok(image) => image,
+        Err(UploadError::NotAnImage) => {
+            warn!("ERROR: uploaded file is not an image");
+            return Err(Response {
+                status: false,
+                msg: Some("file is not an image".to_string()),
+            });
+        }
+        Err(UploadError::TooLarge { width, height }) => {
+            warn!("ERROR: uploaded image too large: {}x{}", width, height);
+            return Err(Response {
+                status: false,
+                msg: Some(format!(
+                    "image too large (maximum {}x{})",
+                    CONFIG.max_image_dim, CONFIG.max_image_dim
+                )),
+            });
+        }
+        Err(e) => {
+            warn!("ERROR processing image: {}", e);
+            return Err(Response {
+                status: false,
+                msg: Some("upload error".to_string()),
+            });
+        }
+    };
+
+    // The database stores the data once it holds a reference to the file
+    Ok(StoredFile::new(image))
+}
+
+#[post("/api/objs/new", data = "<data>")]
+async fn create_obj(
+    state: &State<Api>,
+    content_type: &ContentType,
+    data: Data<'_>,
+) -> Json<Response> {
+    // Encoding the object straight as text is a bit awkward, but we're saving it directly to the
+    // database, not as a file on the filesystem.
+    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
+        MultipartFormDataField::bytes("data").size_limit(CONFIG.max_upload_mb),
+        MultipartFormDataField::text("token"),
+        MultipartFormDataField::text("name"),
+    ]);
+
+    let mut multipart_form_data = match MultipartFormData::parse(content_type, data, options).await
+    {
+        Ok(form) => form,
+        Err(e) => {
+            warn!("ERROR: malformed form data: {}", e);
+            return Json(Response {
+                status: false,
+                msg: Some("malformed form data".to_string()),
+            });
+        }
+    };
+    let data = multipart_form_data.raw.remove("data");
+    let token = multipart_form_data.texts.remove("token");
+    let name = multipart_form_data.texts.remove("name");
+
+    // Validate the inputs.
+    if data.is_none() {
+        warn!("ERROR: malformed file data");
+        return Json(Response {
+            status: false,
+            msg: Some("malformed file data".to_string()),
+        });
+    }
+
+    if token.is_none() {
+        warn!("ERROR: malformed user token");
+        return Json(Response {
+            status: false,
+            msg: Some("malformed user token".to_string()),
+        });
+    }
+
+    if name.is_none() {
+        warn!("ERROR: malformed map name");
+        return Json(Response {
+            status: false,
+            msg: Some("malformed map name".to_string()),
+        });
+    }
+
+    let data = data.unwrap().remove(0).raw;
+    let token = token.unwrap().remove(0).text;
+    let name = name.unwrap().remove(0).text;
+
+    let file = match prepare_upload(data).await {
+        Ok(file) => file,
+        Err(response) => return Json(response),
+    };
+
+    let result = state.db.create_obj(&token, &name, &file).await;
+
+    match result {
+        Ok(_) => Json(Response {
+            status: true,
+            msg: None,
+        }),
+        Err(DbError::Auth) => Json(Response {
+            status: false,
+            msg: Some("user not found".to_string()),
+        }),
+        Err(DbError::AlreadyExists) => Json(Response {
+            status: false,
+            msg: Some("name already used".to_string()),
+        }),
+        Err(DbError::QuotaExceeded) => Json(Response {
+            status: false,
+            msg: Some(format!(
+                "storage quota exceeded (limit {} MB)",
+                CONFIG.user_quota_mb / 1024 / 1024
+            )),
+        }),
+        Err(DbError::Storage(e)) => {
+            warn!("ERROR saving image file: {}", e);
+            Json(Response {
+                status: false,
+                msg: Some("upload error".to_string()),
+            })
+        }
+        Err(e) => {
+            warn!("ERROR: {}", e);
+            Json(Response {
+                status: false,
+                msg: Some("miscellaneous error".to_string()),
+            })
+        }
+    }
+}
+
+#[post("/api/objs/one/<name>/rename", format = "json", data = "<req>")]
+async fn rename_obj(
+    state: &State<Api>,
+    name: String,
+    req: Json<ObjRenameRequest>,
+) -> Json<Response> {
+    let result = state.db.rename_obj(&req.token, &name, &req.name).await;
+
+    match result {
+        Ok(_) => Json(Response {
+            status: true,
+            msg: None,
+        }),
+        Err(DbError::Auth) => Json(Response {
+            status: false,
+            msg: Some("object not found".to_string()),
+        }),
+        Err(DbError::AlreadyExists) => Json(Response {
+            status: false,
+            msg: Some("name already used".to_string()),
+        }),
+        Err(e) => {
+            warn!("ERROR: {}", e);
+            Json(Response {
+                status: false,
+                msg: Some("miscellaneous error".to_string()),
+            })
+        }
+    }
+}
+
+#[post("/api/objs/one/<name>/data", data = "<data>")]
+async fn replace_obj(
+    state: &State<Api>,
+    name: String,
+    content_type: &ContentType,
+    data: Data<'_>,
+) -> Json<Response> {
+    let options = MultipartFormDataOptions::with_multipart_form_data_fields(vec![
+        MultipartFormDataField::bytes("data").size_limit(CONFIG.max_upload_mb),
+        MultipartFormDataField::text("token"),
+    ]);
+
+    let mut multipart_form_data = match MultipartFormData::parse(content_type, data, options).await
+    {
+        Ok(form) => form,
+        Err(e) => {
+            warn!("ERROR: malformed form data: {}", e);
+            return Json(Response {
+                status: false,
+                msg: Some("malformed form data".to_string()),
+            });
+        }
+    };
+    let data = multipart_form_data.raw.remove("data");
+    let token = multipart_form_data.texts.remove("token");
+
+    // Validate the inputs.
+    let data = match data {
+        Some(mut data) => data.remove(0).raw,
+        None => {
+            warn!("ERROR: malformed file data");
+            return Json(Response {
+                status: false,
+                msg: Some("malformed file data".to_string()),
+            });
+        }
+    };
+    let token = match token {
+        Some(mut token) => token.remove(0).text,
+        None => {
+            warn!("ERROR: malformed user token");
+            return Json(Response {
+                status: false,
+                msg: Some("malformed user token".to_string()),
+            });
+        }
+    };
+
+    let file = match prepare_upload(data).await {
+        Ok(file) => file,
+        Err(response) => return Json(response),
+    };
+
+    let result = state.db.replace_obj(&token, &name, &file).await;
+
+    match result {
+        Ok(_) => Json(Response {
+            status: true,
+            msg: None,
+        }),
+        Err(DbError::Auth) => Json(Response {
+            status: false,
+            msg: Some("object not found".to_string()),
+        }),
+        Err(DbError::QuotaExceeded) => Json(Response {
+            status: false,
+            msg: Some(format!(
+                "storage quota exceeded (limit {} MB)",
+                CONFIG.user_quota_mb / 1024 / 1024
+            )),
+        }),
+        Err(DbError::Storage(e)) => {
+            warn!("ERROR saving image file: {}", e);
+            Json(Response {
+                status: false,
+                msg: Some("upload error".to_string()),
+            })
+        }
+        Err(e) => {
+            warn!("ERROR: {}", e);
+            Json(Response {
+                status: false,
+                msg: Some("miscellaneous error".to_string()),
+            })
+        }
+    }
+}
+
+#[post("/api/objs/one/<name>/move", format = "json", data = "<req>")]
+async fn move_obj(state: &State<Api>, name: String, req: Json<ObjMoveRequest>) -> Json<Response> {
+    let result = state.db.move_obj(&req.token, &name, req.folder).await;
+
+    match result {
+        Ok(_) => Json(Response {
+            status: true,
+            msg: None,
+        }),
+        Err(DbError::Auth) => Json(Response {
+            status: false,
+            msg: Some("object or folder not found".to_string()),
+        }),
+        Err(e) => {
+            warn!("ERROR: {}", e);
+            Json(Response {
+                status: false,
+                msg: Some("miscellaneous error".to_string()),
+            })
+        }
+    }
+}
 
-    let result = state.db.create_obj(&token, &name, &path).await;
+#[post("/api/objs/one/<name>/tags", format = "json", data = "<req>")]
+async fn tag_obj(state: &State<Api>, name: String, req: Json<ObjTagRequest>) -> Json<Response> {
+    let result = state.db.set_tags(&req.token, &name, &req.tags).await;
 
     match result {
         Ok(_) => Json(Response {
@@ -399,12 +1119,111 @@ async fn create_obj(
             msg: None,
         }),
         Err(DbError::Auth) => Json(Response {
+            status: false,
+            msg: Some("object not found".to_string()),
+        }),
+        Err(e) => {
+            warn!("ERROR: {}", e);
+            Json(Response {
+                status: false,
+                msg: Some("miscellaneous error".to_string()),
+            })
+        }
+    }
+}
+
+#[post("/api/folders", format = "json", data = "<req>")]
+async fn create_folder(state: &State<Api>, req: Json<FolderCreateRequest>) -> Json<FolderResponse> {
+    let result = state.db.create_folder(&req.token, &req.name).await;
+
+    match result {
+        Ok(id) => Json(FolderResponse {
+            status: true,
+            msg: None,
+            id: Some(id),
+        }),
+        Err(DbError::Auth) => Json(FolderResponse {
             status: false,
             msg: Some("user not found".to_string()),
+            id: None,
         }),
-        Err(DbError::AlreadyExists) => Json(Response {
+        Err(DbError::AlreadyExists) => Json(FolderResponse {
             status: false,
             msg: Some("name already used".to_string()),
+            id: None,
+        }),
+        Err(e) => {
+            warn!("ERROR: {}", e);
+            Json(FolderResponse {
+                status: false,
+                msg: Some("miscellaneous error".to_string()),
+                id: None,
+            })
+        }
+    }
+}
+
+#[post("/api/folders/list", format = "json", data = "<req>")]
+async fn get_folders(state: &State<Api>, req: Json<Request>) -> Json<ListFoldersResponse> {
+    let result = state.db.get_folders(&req.token).await;
+
+    match result {
+        Ok(folders) => Json(ListFoldersResponse {
+            status: true,
+            msg: None,
+            folders: Some(folders),
+        }),
+        Err(DbError::Auth) => Json(ListFoldersResponse {
+            status: false,
+            msg: Some("user not found".to_string()),
+            folders: None,
+        }),
+        Err(e) => {
+            warn!("ERROR: {}", e);
+            Json(ListFoldersResponse {
+                status: false,
+                msg: Some("miscellaneous error".to_string()),
+                folders: None,
+            })
+        }
+    }
+}
+
+#[post("/api/shares", format = "json", data = "<req>")]
+async fn share(state: &State<Api>, req: Json<ShareRequest>) -> Json<Response> {
+    let result = match req.parse() {
+        Some((item, with)) => state.db.share(&req.token, &item, &with).await,
+        None => Err(DbError::Parse),
+    };
+    share_response(result)
+}
+
+#[post("/api/shares/remove", format = "json", data = "<req>")]
+async fn unshare(state: &State<Api>, req: Json<ShareRequest>) -> Json<Response> {
+    let result = match req.parse() {
+        Some((item, with)) => state.db.unshare(&req.token, &item, &with).await,
+        None => Err(DbError::Parse),
+    };
+    share_response(result)
+}
+
+fn share_response(result: Result<(), DbError>) -> Json<Response> {
+    match result {
+        Ok(_) => Json(Response {
+            status: true,
+            msg: None,
+        }),
+        Err(DbError::Auth) => Json(Response {
+            status: false,
+            msg: Some("object, folder, user or game not found".to_string()),
+        }),
+        Err(DbError::Parse) => Json(Response {
+            status: false,
+            msg: Some("specify one of object or folder, and one of username or game".to_string()),
+        }),
+        Err(DbError::InvalidUsername) => Json(Response {
+            status: false,
+            msg: Some("invalid username (expected name#tag)".to_string()),
         }),
         Err(e) => {
             warn!("ERROR: {}", e);
@@ -416,9 +1235,75 @@ async fn create_obj(
     }
 }
 
+#[post("/api/objs/shared", format = "json", data = "<req>")]
+async fn get_shared_objs(state: &State<Api>, req: Json<Request>) -> Json<ListObjsResponse> {
+    let result = state.db.get_shared_objs(&req.token).await;
+
+    match result {
+        Ok(data) => Json(ListObjsResponse {
+            status: true,
+            msg: None,
+            objs: Some(data),
+        }),
+        Err(DbError::Auth) => Json(ListObjsResponse {
+            status: false,
+            msg: Some("user not found".to_string()),
+            objs: None,
+        }),
+        Err(e) => {
+            warn!("ERROR: {}", e);
+            Json(ListObjsResponse {
+                status: false,
+                msg: Some("miscellaneous error".to_string()),
+                objs: None,
+            })
+        }
+    }
+}
+
+#[get("/api/objs/usage")]
+async fn get_usage(state: &State<Api>, token: BearerToken) -> Json<UsageResponse> {
+    let result = state.db.get_usage(&token.0).await;
+
+    match result {
+        Ok((used, quota)) => Json(UsageResponse {
+            status: true,
+            msg: None,
+            used: Some(used),
+            quota: Some(quota),
+            remaining: Some(quota.saturating_sub(used)),
+        }),
+        Err(DbError::Auth) => Json(UsageResponse {
+            status: false,
+            msg: Some("user not found".to_string()),
+            used: None,
+            quota: None,
+            remaining: None,
+        }),
+        Err(e) => {
+            warn!("ERROR: {}", e);
+            Json(UsageResponse {
+                status: false,
+                msg: Some("miscellaneous error".to_string()),
+                used: None,
+                quota: None,
+                remaining: None,
+            })
+        }
+    }
+}
+
 #[post("/api/objs/owned", format = "json", data = "<req>")]
-async fn get_owned_objs(state: &State<Api>, req: Json<Request>) -> Json<ListObjsResponse> {
-    let result = state.db.get_owned_objs(&req.token).await;
+async fn get_owned_objs(
+    state: &State<Api>,
+    req: Json<ObjListRequest>,
+) -> Json<ListObjsResponse> {
+    let filter = ObjFilter {
+        folder: req.folder,
+        tag: req.tag.clone(),
+        search: req.search.clone(),
+    };
+    let result = state.db.get_owned_objs(&req.token, &filter).await;
 
     match result {
         Ok(data) => Json(ListObjsResponse {
@@ -480,10 +1365,14 @@ async fn delete_obj(state: &State<Api>, name: String, req: Json<Request>) -> Jso
     let result = state.db.delete_obj(&req.token, &name).await;
 
     match result {
-        Ok(_) => Json(Response {
-            status: true,
-            msg: None,
-        }),
+        Ok(obj_id) => {
+            // Take it off the table in any running games
+            state.games.remove_placed_obj(obj_id).await;
+            Json(Response {
+                status: true,
+                msg: None,
+            })
+        }
         Err(DbError::Auth) => Json(Response {
             status: false,
             msg: Some("user not found".to_string()),
@@ -498,6 +1387,243 @@ async fn delete_obj(state: &State<Api>, name: String, req: Json<Request>) -> Jso
     }
 }
 
+/// The message to send back when an admin request fails.
+fn admin_error(e: DbError) -> String {
+    match e {
+        DbError::Auth => "user not found",
+        DbError::Forbidden => "not an admin",
+        e => {
+            warn!("ERROR: {}", e);
+            "miscellaneous error"
+        }
+    }
+    .to_string()
+}
+
+#[post("/api/admin/users", format = "json", data = "<req>")]
+async fn admin_users(state: &State<Api>, req: Json<AdminUsersRequest>) -> Json<AdminUsersResponse> {
+    let result = match state.db.check_admin(&req.token).await {
+        Ok(_) => state.db.list_users(req.search.as_deref()).await,
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(users) => Json(AdminUsersResponse {
+            status: true,
+            msg: None,
+            users: Some(users),
+        }),
+        Err(e) => Json(AdminUsersResponse {
+            status: false,
+            msg: Some(admin_error(e)),
+            users: None,
+        }),
+    }
+}
+
+#[post("/api/admin/users/<user_id>/disable", format = "json", data = "<req>")]
+async fn admin_disable_user(
+    state: &State<Api>,
+    user_id: i32,
+    req: Json<AdminDisableRequest>,
+) -> Json<Response> {
+    let admin_id = match state.db.check_admin(&req.token).await {
+        Ok(admin_id) => admin_id,
+        Err(e) => {
+            return Json(Response {
+                status: false,
+                msg: Some(admin_error(e)),
+            })
+        }
+    };
+    // Otherwise an admin could lock themselves out
+    if user_id == admin_id {
+        return Json(Response {
+            status: false,
+            msg: Some("can't disable your own account".to_string()),
+        });
+    }
+
+    match state.db.set_disabled(user_id, req.disabled).await {
+        Ok(_) => Json(Response {
+            status: true,
+            msg: None,
+        }),
+        Err(e) => Json(Response {
+            status: false,
+            msg: Some(admin_error(e)),
+        }),
+    }
+}
+
+#[post("/api/admin/games", format = "json", data = "<req>")]
+async fn admin_games(state: &State<Api>, req: Json<Request>) -> Json<AdminGamesResponse> {
+    let result = match state.db.check_admin(&req.token).await {
+        Ok(_) => state.db.list_games().await,
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(games) => Json(AdminGamesResponse {
+            status: true,
+            msg: None,
+            games: Some(games),
+        }),
+        Err(e) => Json(AdminGamesResponse {
+            status: false,
+            msg: Some(admin_error(e)),
+            games: None,
+        }),
+    }
+}
+
+#[post("/api/admin/servers", format = "json", data = "<req>")]
+async fn admin_servers(state: &State<Api>, req: Json<Request>) -> Json<AdminServersResponse> {
+    match state.db.check_admin(&req.token).await {
+        Ok(_) => Json(AdminServersResponse {
+            status: true,
+            msg: None,
+            servers: Some(state.games.status().await),
+        }),
+        Err(e) => Json(AdminServersResponse {
+            status: false,
+            msg: Some(admin_error(e)),
+            servers: None,
+        }),
+    }
+}
+
+#[post("/api/admin/servers/<game_token>/close", format = "json", data = "<req>")]
+async fn admin_close_server(
+    state: &State<Api>,
+    game_token: String,
+    req: Json<Request>,
+) -> Json<Response> {
+    match state.db.check_admin(&req.token).await {
+        Ok(admin_id) => {
+            info!("admin #{} closed game {}", admin_id, game_token);
+            state
+                .games
+                .close_game(&game_token, "The game was closed by an administrator.")
+                .await;
+            Json(Response {
+                status: true,
+                msg: None,
+            })
+        }
+        Err(e) => Json(Response {
+            status: false,
+            msg: Some(admin_error(e)),
+        }),
+    }
+}
+
+#[post("/api/admin/files/purge", format = "json", data = "<req>")]
+async fn admin_purge_files(state: &State<Api>, req: Json<Request>) -> Json<AdminPurgeResponse> {
+    let result = match state.db.check_admin(&req.token).await {
+        Ok(_) => db::purge_orphaned_files(state.db.as_ref(), db::ORPHAN_MIN_AGE).await,
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(purged) => Json(AdminPurgeResponse {
+            status: true,
+            msg: None,
+            purged: Some(purged),
+        }),
+        Err(e) => Json(AdminPurgeResponse {
+            status: false,
+            msg: Some(admin_error(e)),
+            purged: None,
+        }),
+    }
+}
+
+#[get("/metrics")]
+fn get_metrics() -> String {
+    metrics::encode()
+}
+
+/// Answers as long as the process is running.
+#[get("/healthz")]
+fn healthz() -> Json<Response> {
+    Json(Response {
+        status: true,
+        msg: None,
+    })
+}
+
+async fn run_check<E: Display>(check: impl Future<Output = Result<(), E>>) -> CheckResult {
+    match tokio::time::timeout(READY_CHECK_TIMEOUT, check).await {
+        Ok(Ok(())) => CheckResult {
+            ok: true,
+            msg: None,
+        },
+        Ok(Err(e)) => CheckResult {
+            ok: false,
+            msg: Some(e.to_string()),
+        },
+        Err(_) => CheckResult {
+            ok: false,
+            msg: Some("timed out".to_string()),
+        },
+    }
+}
+
+/// Checks everything the server needs to do its job: the database, somewhere to store uploads,
+/// and the listener for game connections.
+#[get("/readyz")]
+async fn readyz(state: &State<Api>) -> (Status, Json<ReadyResponse>) {
+    let storage = state.db.storage();
+    // Unique, so probes running at the same time don't trip over each other
+    let probe = format!(".readyz-{}", Uuid::new_v4());
+    let (database, storage) = futures::join!(
+        run_check(state.db.ping()),
+        run_check(async {
+            storage.put(&probe, b"").await?;
+            storage.delete(&probe).await
+        })
+    );
+    let websocket = if state.games.is_listening() {
+        CheckResult {
+            ok: true,
+            msg: None,
+        }
+    } else {
+        CheckResult {
+            ok: false,
+            msg: Some("not accepting game connections".to_string()),
+        }
+    };
+
+    let status = database.ok && storage.ok && websocket.ok;
+    let code = if status {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    (
+        code,
+        Json(ReadyResponse {
+            status,
+            database,
+            storage,
+            websocket,
+        }),
+    )
+}
+
+#[get("/<key>")]
+async fn get_image(state: &State<Api>, key: String) -> Option<(ContentType, Vec<u8>)> {
+    match state.db.storage().get(&key).await {
+        Ok(data) => Some((ContentType::PNG, data)),
+        Err(e) => {
+            warn!("ERROR fetching image {}: {}", key, e);
+            None
+        }
+    }
+}
+
 fn process_html(html: String) -> String {
     html.replace("./node_modules/react/umd", "/react")
         .replace("./node_modules/react-dom/umd", "/react-dom")
@@ -1,86 +1,165 @@
-#[macro_use]
-extern crate log;
-
 #[cfg(test)]
 mod tests {
-    use rolecall::db::DbManager;
-    use rolecall::web::Api;
-    use std::collections::HashMap;
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::local::asynchronous::Client;
+    use rolecall::db::{self, Database};
+    use rolecall::game::bus::LoopbackBus;
+    use rolecall::game::server::Games;
+    use rolecall::web::{
+        AdminPurgeResponse, AdminServersResponse, AdminUsersResponse, Api, ListObjsResponse,
+        ProfileResponse, ReadyResponse, Response, UserResponse,
+    };
+    use serde_json::{json, Value};
     use std::sync::Arc;
-    use std::thread;
 
-    async fn new_test_user(db: &DbManager, name: &str) -> String {
+    /// Starts the API on a fresh in-memory database, without binding a port.
+    async fn test_api() -> (Client, Arc<dyn Database>) {
+        let db = db::create_memory_database().await.unwrap();
+        let games = Games::start(Arc::new(LoopbackBus::new()), db.clone())
+            .await
+            .unwrap();
+        let api = Api::new(db.clone(), games).unwrap();
+        let client = Client::tracked(api.build()).await.unwrap();
+        (client, db)
+    }
+
+    async fn new_test_user(db: &dyn Database, name: &str) -> String {
         let token = db.create_user(name, "password", name).await.unwrap();
         db.confirm_user(name, &token).await.unwrap()
     }
 
+    fn test_image(width: u32, height: u32) -> Vec<u8> {
+        let mut data = std::io::Cursor::new(Vec::new());
+        image::DynamicImage::new_rgb8(width, height)
+            .write_to(&mut data, image::ImageOutputFormat::Png)
+            .unwrap();
+        data.into_inner()
+    }
+
+    /// Encodes an upload as a multipart form, with the file in the `data` field.
+    fn upload_form(fields: &[(&str, &str)], data: &[u8]) -> (ContentType, Vec<u8>) {
+        let boundary = "rolecall-test-boundary";
+        let mut body = Vec::new();
+        for (field, value) in fields {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                    boundary, field, value
+                )
+                .as_bytes(),
+            );
+        }
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"data\"; filename=\"upload\"\r\n\
+                 Content-Type: application/octet-stream\r\n\r\n",
+                boundary
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(data);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+        let content_type =
+            ContentType::new("multipart", "form-data").with_params(("boundary", boundary));
+        (content_type, body)
+    }
+
+    async fn upload(client: &Client, token: &str, name: &str, data: &[u8]) -> Response {
+        let (content_type, body) = upload_form(&[("token", token), ("name", name)], data);
+        client
+            .post("/api/objs/new")
+            .header(content_type)
+            .body(body)
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap()
+    }
+
     #[tokio::test]
     async fn test_user_api() {
-        // TODO: refactor repeated test code; check that username is returned on successful user auth
-        dotenv::dotenv().unwrap();
-        let db = DbManager::new().await.unwrap();
-        db.clear_tables().await.unwrap();
-        db.create_tables().await.unwrap();
-        let db = Arc::new(db);
-        let api = Api::new(db.clone()).unwrap();
-        thread::spawn(move || api.start());
+        let (client, db) = test_api().await;
 
         // Post a new user
-        let email = "post@email.com";
-        let pw = "password";
-        let mut user_map = HashMap::new();
-        user_map.insert("email", email);
-        user_map.insert("password", pw);
-        user_map.insert("nickname", email);
-
-        let client = reqwest::Client::new();
-        let res: rolecall::web::UserResponse = client
-            .post("http://localhost:8000/api/users")
-            .json(&user_map)
-            .send()
+        let user = json!({
+            "email": "post@email.com",
+            "password": "password",
+            "nickname": "post",
+        });
+        let res: UserResponse = client
+            .post("/api/users")
+            .json(&user)
+            .dispatch()
             .await
-            .unwrap()
-            .json()
+            .into_json()
             .await
             .unwrap();
         assert!(res.status);
         assert!(res.msg.is_none());
         assert!(res.token.is_none());
 
-        // Create a test user
-        let email = "test@email.com";
-        let pw = "password";
-
-        // Attempt to authenticate
-        new_test_user(&db, email).await;
-        let mut user_map = HashMap::new();
-        user_map.insert("email", email);
-        user_map.insert("password", pw);
-        user_map.insert("nickname", email);
-        let res: rolecall::web::UserResponse = client
-            .post("http://localhost:8000/api/users/auth")
-            .json(&user_map)
-            .send()
+        // Authenticate as a confirmed user, and get their username back
+        new_test_user(db.as_ref(), "test@email.com").await;
+        let mut user = json!({
+            "email": "test@email.com",
+            "password": "password",
+        });
+        let res: UserResponse = client
+            .post("/api/users/auth")
+            .json(&user)
+            .dispatch()
             .await
-            .unwrap()
-            .json()
+            .into_json()
             .await
             .unwrap();
         assert!(res.status);
         assert!(res.msg.is_none());
         assert!(res.token.is_some());
-        let host_token = res.token.unwrap();
+        assert!(res.username.unwrap().starts_with("test@email.com#"));
+
+        // The token is valid
+        let token = res.token.unwrap();
+        let res: Response = client
+            .post("/api/users/check")
+            .json(&json!({ "token": token }))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert!(res.status);
+
+        // Change nickname, which comes with a new tag
+        let res: UserResponse = client
+            .post("/api/users/nickname")
+            .json(&json!({ "token": token, "nickname": "renamed" }))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert!(res.status);
+        assert!(res.username.unwrap().starts_with("renamed#"));
+        let res: UserResponse = client
+            .post("/api/users/nickname")
+            .json(&json!({ "token": token, "nickname": "bad#name" }))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert!(!res.status);
 
         // Try with incorrect password
-        let pw = "not-password";
-        user_map.insert("password", pw);
-        let res: rolecall::web::UserResponse = client
-            .post("http://localhost:8000/api/users/auth")
-            .json(&user_map)
-            .send()
+        user["password"] = json!("not-password");
+        let res: UserResponse = client
+            .post("/api/users/auth")
+            .json(&user)
+            .dispatch()
             .await
-            .unwrap()
-            .json()
+            .into_json()
             .await
             .unwrap();
         assert!(!res.status);
@@ -88,102 +167,419 @@ mod tests {
         assert!(res.token.is_none());
 
         // Try with incorrect email
-        let email = "not-email@email.com";
-        user_map.insert("email", email);
-        let res: rolecall::web::UserResponse = client
-            .post("http://localhost:8000/api/users/auth")
-            .json(&user_map)
-            .send()
+        user["password"] = json!("password");
+        user["email"] = json!("not-email@email.com");
+        let res: UserResponse = client
+            .post("/api/users/auth")
+            .json(&user)
+            .dispatch()
             .await
-            .unwrap()
-            .json()
+            .into_json()
             .await
             .unwrap();
         assert!(!res.status);
         assert!(res.msg.is_some());
         assert!(res.token.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_profile_api() {
+        let (client, db) = test_api().await;
+        let token = new_test_user(db.as_ref(), "test_profile").await;
+        let auth = Header::new("Authorization", format!("Bearer {}", token));
 
-        // Try to create a game
-        let mut game_map = HashMap::new();
-        game_map.insert("user_token", host_token.clone());
-        game_map.insert("name", "Test Game".to_string());
-        let res: rolecall::web::GameResponse = client
-            .post("http://localhost:8000/api/games")
-            .json(&game_map)
-            .send()
+        // Store some settings
+        let settings = json!({
+            "token": token,
+            "colour": "#ff8800",
+            "pronouns": "they/them",
+            "timezone": "Australia/Melbourne",
+            "preferences": { "grid": true },
+        });
+        let res: Response = client
+            .put("/api/users/profile")
+            .json(&settings)
+            .dispatch()
             .await
-            .unwrap()
-            .json()
+            .into_json()
+            .await
+            .unwrap();
+        assert!(res.status);
+
+        // Colours have to be valid
+        let mut bad_settings = settings.clone();
+        bad_settings["colour"] = json!("orange");
+        let res: Response = client
+            .put("/api/users/profile")
+            .json(&bad_settings)
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert!(!res.status);
+
+        // Upload an avatar
+        let (content_type, body) = upload_form(&[("token", token.as_str())], &test_image(64, 64));
+        let res: Response = client
+            .post("/api/users/profile/avatar")
+            .header(content_type)
+            .body(body)
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert!(res.status);
+
+        // Everything comes back together
+        let res: ProfileResponse = client
+            .get("/api/users/profile")
+            .header(auth)
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert!(res.status);
+        let profile = res.profile.unwrap();
+        assert!(profile.username.starts_with("test_profile#"));
+        assert!(profile.avatar.is_some());
+        assert_eq!(profile.settings.colour, Some("#ff8800".to_string()));
+        assert_eq!(profile.settings.preferences, json!({ "grid": true }));
+
+        // Without the token in the header, there's no profile
+        let res: ProfileResponse = client
+            .get("/api/users/profile")
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert!(!res.status);
+        assert!(res.profile.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_game_api() {
+        let (client, db) = test_api().await;
+        let host_token = new_test_user(db.as_ref(), "test_host").await;
+        let player_token = new_test_user(db.as_ref(), "test_player").await;
+
+        // Create a game
+        let game = json!({
+            "user_token": host_token,
+            "name": "Test Game",
+        });
+        let res: UserResponse = client
+            .post("/api/games")
+            .json(&game)
+            .dispatch()
+            .await
+            .into_json()
             .await
             .unwrap();
         assert!(res.status);
         assert!(res.msg.is_none());
-        assert!(res.token.is_some());
         let game_token = res.token.unwrap();
 
-        // Log in as player
-        user_map.insert("email", "player");
-        user_map.insert("password", "password");
-        let res: rolecall::web::UserResponse = client
-            .post("http://localhost:8000/api/users/auth")
-            .json(&user_map)
-            .send()
+        // Join it as a player
+        let join_uri = format!("/api/games/{}/join", game_token);
+        let res: Response = client
+            .post(join_uri.as_str())
+            .json(&json!({ "token": player_token }))
+            .dispatch()
             .await
-            .unwrap()
-            .json()
+            .into_json()
             .await
             .unwrap();
-        info!("{:?}", res.msg);
-        let player_token = res.token.unwrap();
+        assert!(res.status);
+        assert!(res.msg.is_none());
 
-        // Attempt to join game
-        let mut req_map = HashMap::new();
-        req_map.insert("token", player_token.clone());
-        req_map.insert("nick", "nickname".to_string());
-        let addr = format!("http://localhost:8000/api/games/{}/join", game_token);
-        let res: rolecall::web::Response = client
-            .post(&addr)
-            .json(&req_map)
-            .send()
+        // Each user sees the game in the right list
+        let res: Value = client
+            .post("/api/games/hosted")
+            .json(&json!({ "token": host_token }))
+            .dispatch()
             .await
-            .unwrap()
-            .json()
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(res["games"][0]["token"], json!(game_token));
+        let res: Value = client
+            .post("/api/games/joined")
+            .json(&json!({ "token": player_token }))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(res["games"][0]["token"], json!(game_token));
+
+        // Unknown games can't be joined
+        let res: Response = client
+            .post("/api/games/not-a-game/join")
+            .json(&json!({ "token": player_token }))
+            .dispatch()
+            .await
+            .into_json()
             .await
             .unwrap();
+        assert!(!res.status);
+    }
+
+    #[tokio::test]
+    async fn test_obj_api() {
+        let (client, db) = test_api().await;
+        let token = new_test_user(db.as_ref(), "test_owner").await;
+
+        // Data that isn't an image is rejected
+        let res = upload(&client, &token, "foobar", &[0xde, 0xad, 0xbe, 0xef]).await;
+        assert!(!res.status);
+        assert!(res.msg.is_some());
+
+        // So is a body that isn't a multipart form
+        let res: Response = client
+            .post("/api/objs/new")
+            .header(ContentType::FormData)
+            .body("not a form")
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert!(!res.status);
+
+        // Create an object from an image
+        let data = test_image(40, 30);
+        let res = upload(&client, &token, "foobar", &data).await;
         assert!(res.status);
         assert!(res.msg.is_none());
+        let res = upload(&client, &token, "foobar", &data).await;
+        assert!(!res.status);
 
-        // Attempt to create map
-        let map_data = vec![0xde, 0xad, 0xbe, 0xef];
-        let form = reqwest::multipart::Form::new()
-            .text("token", host_token.clone())
-            .text("name", "foobar".to_string())
-            .part("data", reqwest::multipart::Part::bytes(map_data.clone()));
-        let res: rolecall::web::Response = client
-            .post("http://localhost:8000/api/maps/new")
-            .multipart(form)
-            .send()
+        // It shows up in the owner's list
+        let res: ListObjsResponse = client
+            .post("/api/objs/owned")
+            .json(&json!({ "token": token }))
+            .dispatch()
             .await
-            .unwrap()
-            .json()
+            .into_json()
             .await
             .unwrap();
         assert!(res.status);
-        assert!(res.msg.is_none());
+        assert_eq!(res.objs.unwrap().len(), 1);
+
+        // Its image counts towards the owner's usage
+        let res: Value = client
+            .get("/api/objs/usage")
+            .header(Header::new("Authorization", format!("Bearer {}", token)))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(res["status"], true);
+        let used = res["used"].as_u64().unwrap();
+        let quota = res["quota"].as_u64().unwrap();
+        assert!(used > 0);
+        assert_eq!(res["remaining"].as_u64().unwrap(), quota - used);
+        let res: Value = client
+            .get("/api/objs/usage")
+            .header(Header::new("Authorization", "Bearer not-a-token"))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(res["status"], false);
+
+        // The stored image can be fetched back
+        let res: Value = client
+            .post("/api/objs/owned")
+            .json(&json!({ "token": token }))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        let url = res["objs"][0]["url"].as_str().unwrap();
+        let image = client.get(url).dispatch().await;
+        assert_eq!(image.status(), Status::Ok);
+        let image = image::load_from_memory(&image.into_bytes().await.unwrap()).unwrap();
+        assert_eq!((image.width(), image.height()), (40, 30));
+
+        // Delete it again
+        let res: Response = client
+            .delete("/api/objs/one/foobar")
+            .json(&json!({ "token": token }))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert!(res.status);
+        let res: ListObjsResponse = client
+            .post("/api/objs/owned")
+            .json(&json!({ "token": token }))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert!(res.objs.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_admin_api() {
+        let (client, db) = test_api().await;
+        let admin_token = new_test_user(db.as_ref(), "test_admin").await;
+        let user_token = new_test_user(db.as_ref(), "test_user").await;
+
+        // Only admins can use the admin API
+        let res: AdminUsersResponse = client
+            .post("/api/admin/users")
+            .json(&json!({ "token": user_token }))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert!(!res.status);
+        assert_eq!(res.msg.unwrap(), "not an admin");
+
+        // Find a user, and disable their account
+        db.set_admin("test_admin", true).await.unwrap();
+        let res: AdminUsersResponse = client
+            .post("/api/admin/users")
+            .json(&json!({ "token": admin_token, "search": "test_user" }))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert!(res.status);
+        let users = res.users.unwrap();
+        assert_eq!(users.len(), 1);
+        let disable_uri = format!("/api/admin/users/{}/disable", users[0].id);
+        let res: Response = client
+            .post(disable_uri.as_str())
+            .json(&json!({ "token": admin_token, "disabled": true }))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert!(res.status);
+        assert!(db.get_account(&user_token).await.is_err());
+
+        // Admins can't disable themselves
+        let (admin_id, _) = db.get_account(&admin_token).await.unwrap();
+        let disable_uri = format!("/api/admin/users/{}/disable", admin_id);
+        let res: Response = client
+            .post(disable_uri.as_str())
+            .json(&json!({ "token": admin_token, "disabled": true }))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert!(!res.status);
 
-        // let mut req_map = HashMap::new();
-        // req_map.insert("token", host_token.clone());
-        // let res: rolecall::web::ObjResponse = client
-        //     .post("http://localhost:8000/api/maps/one/foobar")
-        //     .json(&req_map)
-        //     .send()
-        //     .await
-        //     .unwrap()
-        //     .json()
-        //     .await
-        //     .unwrap();
-        // assert!(res.status);
-        // assert!(res.msg.is_none());
-        // assert_eq!(res.data.unwrap(), base64::encode(&map_data));
+        // List games, and the servers running them
+        let game_token = db.create_game(&admin_token, "game").await.unwrap();
+        let res: Value = client
+            .post("/api/admin/games")
+            .json(&json!({ "token": admin_token }))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        // Debug mode adds a game of its own
+        let games = res["games"].as_array().unwrap();
+        let game = games
+            .iter()
+            .find(|game| game["token"] == json!(game_token))
+            .unwrap();
+        assert_eq!(game["players"], json!(1));
+        let res: AdminServersResponse = client
+            .post("/api/admin/servers")
+            .json(&json!({ "token": admin_token }))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert!(res.servers.unwrap().is_empty());
+        let close_uri = format!("/api/admin/servers/{}/close", game_token);
+        let res: Response = client
+            .post(close_uri.as_str())
+            .json(&json!({ "token": admin_token }))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert!(res.status);
+
+        // Stored files that nothing refers to are purged
+        db.storage().put("orphan.png", b"data").await.unwrap();
+        let res: AdminPurgeResponse = client
+            .post("/api/admin/files/purge")
+            .json(&json!({ "token": admin_token }))
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        assert_eq!(res.purged.unwrap(), vec!["orphan.png".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let (client, db) = test_api().await;
+        let token = new_test_user(db.as_ref(), "test_metrics").await;
+        let res = upload(&client, &token, "metered", &test_image(10, 10)).await;
+        assert!(res.status);
+
+        // Uploads and the requests that made them show up
+        let res = client.get("/metrics").dispatch().await;
+        assert_eq!(res.status(), Status::Ok);
+        let metrics = res.into_string().await.unwrap();
+        assert!(metrics.contains("rolecall_upload_bytes_total"));
+        assert!(metrics.contains("route=\"create_obj\""));
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let (client, _) = test_api().await;
+        let res = client.get("/healthz").dispatch().await;
+        assert_eq!(res.status(), Status::Ok);
+
+        // Without a game listener the server isn't ready, but everything else is fine
+        let res = client.get("/readyz").dispatch().await;
+        assert_eq!(res.status(), Status::ServiceUnavailable);
+        let res: ReadyResponse = res.into_json().await.unwrap();
+        assert!(!res.status);
+        assert!(res.database.ok);
+        assert!(res.storage.ok);
+        assert!(!res.websocket.ok);
+    }
+
+    #[tokio::test]
+    async fn test_request_ids() {
+        let (client, _) = test_api().await;
+
+        // Every response says which request it was, so it can be found in the logs
+        let first = client.get("/healthz").dispatch().await;
+        let second = client.get("/healthz").dispatch().await;
+        let first = first.headers().get_one("X-Request-Id").unwrap().to_string();
+        let second = second
+            .headers()
+            .get_one("X-Request-Id")
+            .unwrap()
+            .to_string();
+        assert_ne!(first, second);
     }
 }
@@ -0,0 +1,746 @@
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+    use rolecall::config::OverflowPolicy;
+    use rolecall::db::{self, Database, ProfileSettings};
+    use rolecall::game::bus::LoopbackBus;
+    use rolecall::game::conn::ws_serve;
+    use rolecall::game::protocol::{PlacedObj, ProtocolMessage, Token};
+    use rolecall::game::server::{Games, ServerStatus};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::time::timeout;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+    const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A game server listening on an ephemeral port, backed by an in-memory database.
+    struct TestGame {
+        db: Arc<dyn Database>,
+        bus: Arc<LoopbackBus>,
+        games: Arc<Games>,
+        addr: SocketAddr,
+        game_token: String,
+        host: User,
+    }
+
+    #[derive(Clone)]
+    struct User {
+        token: String,
+        id: i32,
+        username: String,
+        colour: Option<String>,
+    }
+
+    struct TestClient {
+        ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    }
+
+    impl TestGame {
+        async fn new() -> Self {
+            let db = db::create_memory_database().await.unwrap();
+            let bus = Arc::new(LoopbackBus::new());
+            let games = Games::start(bus.clone(), db.clone()).await.unwrap();
+            Self::hosting(db, bus, games).await
+        }
+
+        /// Like `new`, but with clients' queues set up to test what happens when they fill up.
+        async fn with_queue(buffer: usize, overflow: OverflowPolicy) -> Self {
+            let db = db::create_memory_database().await.unwrap();
+            let bus = Arc::new(LoopbackBus::new());
+            let games = Games::start_with_queue(bus.clone(), db.clone(), buffer, overflow)
+                .await
+                .unwrap();
+            Self::hosting(db, bus, games).await
+        }
+
+        async fn hosting(db: Arc<dyn Database>, bus: Arc<LoopbackBus>, games: Arc<Games>) -> Self {
+            let addr = listen(db.clone(), games.clone()).await;
+            let host = new_test_user(db.as_ref(), "test_host").await;
+            let game_token = db.create_game(&host.token, "game").await.unwrap();
+            Self {
+                db,
+                bus,
+                games,
+                addr,
+                game_token,
+                host,
+            }
+        }
+
+        /// Starts another instance with the same database and bus, as if behind a load
+        /// balancer.
+        async fn other_instance(&self) -> Self {
+            let (games, addr) = serve(self.db.clone(), self.bus.clone()).await;
+            Self {
+                db: self.db.clone(),
+                bus: self.bus.clone(),
+                games,
+                addr,
+                game_token: self.game_token.clone(),
+                host: self.host.clone(),
+            }
+        }
+
+        /// Creates a user who has joined the game.
+        async fn player(&self, name: &str) -> User {
+            let user = new_test_user(self.db.as_ref(), name).await;
+            self.db
+                .join_game(&user.token, &self.game_token)
+                .await
+                .unwrap();
+            user
+        }
+
+        /// Opens a connection and sends the user and game tokens, without waiting for a reply.
+        async fn connect_raw(&self, user_token: &str, game_token: &str) -> TestClient {
+            let (mut ws, _) = connect_async(format!("ws://{}", self.addr)).await.unwrap();
+            ws.send(Message::Text(user_token.to_string()))
+                .await
+                .unwrap();
+            ws.send(Message::Text(game_token.to_string()))
+                .await
+                .unwrap();
+            TestClient { ws }
+        }
+
+        /// Connects a user, reading everything up to and including their own connection message.
+        async fn connect(&self, user: &User) -> (TestClient, Vec<ProtocolMessage>) {
+            let mut client = self.connect_raw(&user.token, &self.game_token).await;
+            let own = self.connect_msg(user);
+            let mut received = Vec::new();
+            loop {
+                let msg = client.recv().await;
+                if msg == own {
+                    return (client, received);
+                }
+                received.push(msg);
+            }
+        }
+
+        fn connect_msg(&self, user: &User) -> ProtocolMessage {
+            ProtocolMessage::Connect {
+                username: user.username.clone(),
+                host: user.id == self.host.id,
+                host_id: self.host.id,
+                avatar: None,
+                colour: user.colour.clone(),
+            }
+        }
+    }
+
+    impl TestClient {
+        async fn send(&mut self, msg: &ProtocolMessage) {
+            let text = serde_json::to_string(msg).unwrap();
+            self.ws.send(Message::Text(text)).await.unwrap();
+        }
+
+        async fn recv(&mut self) -> ProtocolMessage {
+            loop {
+                let msg = timeout(RECV_TIMEOUT, self.ws.next())
+                    .await
+                    .expect("timed out waiting for a message")
+                    .expect("connection closed")
+                    .unwrap();
+                if let Message::Text(text) = msg {
+                    return serde_json::from_str(&text).unwrap();
+                }
+            }
+        }
+
+        async fn close(mut self) {
+            self.ws.close(None).await.unwrap();
+        }
+
+        /// Waits for the server to close the connection, skipping anything sent before then.
+        async fn wait_closed(mut self) {
+            loop {
+                let msg = timeout(RECV_TIMEOUT, self.ws.next())
+                    .await
+                    .expect("timed out waiting for the connection to close");
+                if let None | Some(Err(_)) | Some(Ok(Message::Close(_))) = msg {
+                    return;
+                }
+            }
+        }
+    }
+
+    async fn serve(db: Arc<dyn Database>, bus: Arc<LoopbackBus>) -> (Arc<Games>, SocketAddr) {
+        let games = Games::start(bus, db.clone()).await.unwrap();
+        let addr = listen(db, games.clone()).await;
+        (games, addr)
+    }
+
+    async fn listen(db: Arc<dyn Database>, games: Arc<Games>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(ws_serve(db, games, listener));
+        addr
+    }
+
+    async fn new_test_user(db: &dyn Database, name: &str) -> User {
+        let token = db.create_user(name, "password", name).await.unwrap();
+        let token = db.confirm_user(name, &token).await.unwrap();
+        let (id, username) = db.get_account(&token).await.unwrap();
+        User {
+            token,
+            id,
+            username,
+            colour: None,
+        }
+    }
+
+    fn token(x: i16, y: i16) -> Token {
+        Token {
+            id: None,
+            name: Some("token".to_string()),
+            kind: "circle".to_string(),
+            x,
+            y,
+            colour: "red".to_string(),
+            controller: None,
+        }
+    }
+
+    fn placed_obj(obj_id: i32) -> PlacedObj {
+        PlacedObj {
+            id: None,
+            obj_id,
+            x: 1,
+            y: 2,
+            width: 3,
+            height: 4,
+        }
+    }
+
+    fn move_token(token_id: &str, dx: i16, dy: i16) -> ProtocolMessage {
+        ProtocolMessage::MoveToken {
+            id: "move".to_string(),
+            token_id: token_id.to_string(),
+            dx,
+            dy,
+            merged_ids: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection() {
+        let game = TestGame::new().await;
+        let player = game.player("test_player").await;
+
+        // Nobody else is connected yet, so the host only hears about themself
+        let (mut host, received) = game.connect(&game.host).await;
+        assert!(received.is_empty());
+
+        // The player hears about the host before themself, and the host hears about the player
+        let (_player, received) = game.connect(&player).await;
+        assert_eq!(received, vec![game.connect_msg(&game.host)]);
+        assert_eq!(host.recv().await, game.connect_msg(&player));
+
+        // A second connection for the same user is refused
+        let mut client = game.connect_raw(&player.token, &game.game_token).await;
+        assert!(matches!(
+            client.recv().await,
+            ProtocolMessage::FailedConnection { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_connect_profile() {
+        let game = TestGame::new().await;
+        let mut player = game.player("test_player").await;
+        let settings = ProfileSettings {
+            colour: Some("#0000ff".to_string()),
+            ..Default::default()
+        };
+        game.db.set_profile(&player.token, &settings).await.unwrap();
+        player.colour = settings.colour;
+
+        // Others are sent the player's colour when they connect
+        let (mut host, _) = game.connect(&game.host).await;
+        let (_player, _) = game.connect(&player).await;
+        let msg = host.recv().await;
+        assert_eq!(msg, game.connect_msg(&player));
+        assert!(matches!(
+            msg,
+            ProtocolMessage::Connect {
+                colour: Some(_),
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_failed_connection() {
+        let game = TestGame::new().await;
+        let outsider = new_test_user(game.db.as_ref(), "test_outsider").await;
+
+        // Unknown games are refused
+        let mut client = game.connect_raw(&game.host.token, "not-a-game").await;
+        assert!(matches!(
+            client.recv().await,
+            ProtocolMessage::FailedConnection { .. }
+        ));
+
+        // So are users who haven't joined the game
+        let mut client = game.connect_raw(&outsider.token, &game.game_token).await;
+        assert!(matches!(
+            client.recv().await,
+            ProtocolMessage::FailedConnection { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_placement_and_movement() {
+        let game = TestGame::new().await;
+        let player = game.player("test_player").await;
+        let (mut host, _) = game.connect(&game.host).await;
+        let (mut player_client, _) = game.connect(&player).await;
+        host.recv().await;
+
+        // Placed tokens are given an id and the host as controller, and sent to everyone
+        host.send(&ProtocolMessage::PlaceToken(token(0, 0))).await;
+        let mut placed = token(0, 0);
+        placed.id = Some("0".to_string());
+        placed.controller = Some(game.host.username.clone());
+        let placed = ProtocolMessage::PlaceToken(placed);
+        assert_eq!(host.recv().await, placed);
+        assert_eq!(player_client.recv().await, placed);
+
+        // Tokens can't be stacked on the same square
+        host.send(&ProtocolMessage::PlaceToken(token(0, 0))).await;
+        host.send(&move_token("0", 1, 2)).await;
+        assert_eq!(host.recv().await, move_token("0", 1, 2));
+        assert_eq!(player_client.recv().await, move_token("0", 1, 2));
+
+        // Moving a token that doesn't exist does nothing
+        host.send(&move_token("100", 1, 1)).await;
+
+        // Objects are placed and moved the same way
+        host.send(&ProtocolMessage::PlaceObj(placed_obj(1))).await;
+        let mut obj = placed_obj(1);
+        obj.id = Some("0".to_string());
+        let obj = ProtocolMessage::PlaceObj(obj);
+        assert_eq!(host.recv().await, obj);
+        assert_eq!(player_client.recv().await, obj);
+
+        let move_obj = ProtocolMessage::MoveObj {
+            obj_id: "0".to_string(),
+            x: 5,
+            y: 6,
+            w: 7,
+            h: 8,
+        };
+        host.send(&move_obj).await;
+        assert_eq!(host.recv().await, move_obj);
+        assert_eq!(player_client.recv().await, move_obj);
+
+        let delete_obj = ProtocolMessage::DeleteObj {
+            obj_id: "0".to_string(),
+        };
+        host.send(&delete_obj).await;
+        assert_eq!(host.recv().await, delete_obj);
+        assert_eq!(player_client.recv().await, delete_obj);
+    }
+
+    #[tokio::test]
+    async fn test_authorisation() {
+        let game = TestGame::new().await;
+        let player = game.player("test_player").await;
+        let (mut host, _) = game.connect(&game.host).await;
+        let (mut player_client, _) = game.connect(&player).await;
+        host.recv().await;
+
+        host.send(&ProtocolMessage::PlaceToken(token(0, 0))).await;
+        host.recv().await;
+        player_client.recv().await;
+
+        // Players can't place anything, or move tokens they don't control. Messages are
+        // delivered in order, so if the host's next message arrives first, these were dropped.
+        player_client
+            .send(&ProtocolMessage::PlaceToken(token(1, 1)))
+            .await;
+        player_client
+            .send(&ProtocolMessage::PlaceObj(placed_obj(1)))
+            .await;
+        player_client.send(&move_token("0", 1, 0)).await;
+
+        // Once the host hands over control, the player can move the token
+        let set_controller = ProtocolMessage::SetController {
+            token_id: "0".to_string(),
+            new_controller: player.username.clone(),
+        };
+        host.send(&set_controller).await;
+        assert_eq!(host.recv().await, set_controller);
+        assert_eq!(player_client.recv().await, set_controller);
+
+        player_client.send(&move_token("0", 0, 1)).await;
+        assert_eq!(host.recv().await, move_token("0", 0, 1));
+        assert_eq!(player_client.recv().await, move_token("0", 0, 1));
+    }
+
+    #[tokio::test]
+    async fn test_rename() {
+        let game = TestGame::new().await;
+        let player = game.player("test_player").await;
+        let (mut host, _) = game.connect(&game.host).await;
+        let (mut player_client, _) = game.connect(&player).await;
+        host.recv().await;
+
+        host.send(&ProtocolMessage::PlaceToken(token(0, 0))).await;
+        host.send(&ProtocolMessage::SetController {
+            token_id: "0".to_string(),
+            new_controller: player.username.clone(),
+        })
+        .await;
+        for _ in 0..2 {
+            host.recv().await;
+            player_client.recv().await;
+        }
+
+        // Everyone is told about the player's new name
+        let (_, new_username) = game
+            .db
+            .set_nickname(&player.token, "renamed")
+            .await
+            .unwrap();
+        game.games
+            .rename_user(player.id, &player.username, &new_username)
+            .await;
+        let renamed = ProtocolMessage::RenameUser {
+            username: player.username.clone(),
+            new_username,
+        };
+        assert_eq!(host.recv().await, renamed);
+        assert_eq!(player_client.recv().await, renamed);
+
+        // They still control their token under the new name
+        player_client.send(&move_token("0", 0, 1)).await;
+        assert_eq!(host.recv().await, move_token("0", 0, 1));
+
+        // Clients can't rename anyone themselves
+        player_client.send(&renamed).await;
+        host.send(&move_token("0", 1, 0)).await;
+        assert_eq!(host.recv().await, move_token("0", 1, 0));
+    }
+
+    #[tokio::test]
+    async fn test_remove_placed_obj() {
+        let game = TestGame::new().await;
+        let player = game.player("test_player").await;
+        let late_player = game.player("test_late_player").await;
+        let (mut host, _) = game.connect(&game.host).await;
+        let (mut player_client, _) = game.connect(&player).await;
+        host.recv().await;
+
+        for obj_id in [1, 2, 1].iter() {
+            host.send(&ProtocolMessage::PlaceObj(placed_obj(*obj_id)))
+                .await;
+            host.recv().await;
+            player_client.recv().await;
+        }
+
+        // Deleting an object removes every placed copy of it, for everyone
+        game.games.remove_placed_obj(1).await;
+        for client in [&mut host, &mut player_client].iter_mut() {
+            let mut deleted = vec![client.recv().await, client.recv().await];
+            deleted.sort_by_key(|msg| format!("{:?}", msg));
+            assert_eq!(
+                deleted,
+                vec![
+                    ProtocolMessage::DeleteObj {
+                        obj_id: "0".to_string(),
+                    },
+                    ProtocolMessage::DeleteObj {
+                        obj_id: "2".to_string(),
+                    },
+                ]
+            );
+        }
+
+        // Players joining later only see what's left
+        let (_late_client, received) = game.connect(&late_player).await;
+        let mut obj = placed_obj(2);
+        obj.id = Some("1".to_string());
+        let placed: Vec<_> = received
+            .into_iter()
+            .filter(|msg| matches!(msg, ProtocolMessage::PlaceObj(_)))
+            .collect();
+        assert_eq!(placed, vec![ProtocolMessage::PlaceObj(obj)]);
+    }
+
+    #[tokio::test]
+    async fn test_overflow() {
+        for &overflow in [OverflowPolicy::Resync, OverflowPolicy::Disconnect].iter() {
+            let game = TestGame::with_queue(2, overflow).await;
+            let player = game.player("test_player").await;
+            let (mut host, _) = game.connect(&game.host).await;
+            let (mut player_client, _) = game.connect(&player).await;
+            host.recv().await;
+            for _ in 0..5 {
+                host.send(&ProtocolMessage::PlaceObj(placed_obj(1))).await;
+                host.recv().await;
+                player_client.recv().await;
+            }
+
+            // Removing the object sends a message per copy at once, more than the queues hold
+            game.games.remove_placed_obj(1).await;
+            match overflow {
+                OverflowPolicy::Resync => {
+                    // Clients get what fit, then the whole state again once they catch up
+                    let mut users = vec![game.connect_msg(&game.host), game.connect_msg(&player)];
+                    users.sort_by_key(|msg| format!("{:?}", msg));
+                    for client in [&mut host, &mut player_client].iter_mut() {
+                        loop {
+                            match client.recv().await {
+                                ProtocolMessage::DeleteObj { .. } => {}
+                                ProtocolMessage::Resync {} => break,
+                                msg => panic!("unexpected message {:?}", msg),
+                            }
+                        }
+                        let mut state = vec![client.recv().await, client.recv().await];
+                        state.sort_by_key(|msg| format!("{:?}", msg));
+                        assert_eq!(state, users);
+                    }
+
+                    // After which they're sent everything again
+                    host.send(&ProtocolMessage::PlaceToken(token(0, 0))).await;
+                    let placed = host.recv().await;
+                    assert!(matches!(placed, ProtocolMessage::PlaceToken(_)));
+                    assert_eq!(player_client.recv().await, placed);
+                }
+                OverflowPolicy::Disconnect => {
+                    host.wait_closed().await;
+                    player_client.wait_closed().await;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_update_saved_state() {
+        let game = TestGame::new().await;
+        let player = game.player("test_player").await;
+        let (mut host, _) = game.connect(&game.host).await;
+        host.send(&ProtocolMessage::PlaceToken(token(0, 0))).await;
+        host.send(&ProtocolMessage::SetController {
+            token_id: "0".to_string(),
+            new_controller: player.username.clone(),
+        })
+        .await;
+        host.send(&ProtocolMessage::PlaceObj(placed_obj(1))).await;
+        for _ in 0..3 {
+            host.recv().await;
+        }
+
+        // Stop the game, and wait for its state to be saved
+        game.games.close_game(&game.game_token, "closed").await;
+        host.wait_closed().await;
+        timeout(RECV_TIMEOUT, async {
+            while !game.games.status().await.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("timed out waiting for the game to stop");
+
+        // Renames and deleted objects still reach the game while nobody is playing
+        let (_, new_username) = game
+            .db
+            .set_nickname(&player.token, "renamed")
+            .await
+            .unwrap();
+        game.games
+            .rename_user(player.id, &player.username, &new_username)
+            .await;
+        game.games.remove_placed_obj(1).await;
+
+        let (mut host, received) = game.connect(&game.host).await;
+        let mut placed = token(0, 0);
+        placed.id = Some("0".to_string());
+        placed.controller = Some(new_username.clone());
+        assert_eq!(received, vec![ProtocolMessage::PlaceToken(placed)]);
+
+        // So the player can still move their token
+        let player = User {
+            username: new_username,
+            ..player
+        };
+        let (mut player_client, _) = game.connect(&player).await;
+        host.recv().await;
+        player_client.send(&move_token("0", 1, 0)).await;
+        assert_eq!(host.recv().await, move_token("0", 1, 0));
+    }
+
+    #[tokio::test]
+    async fn test_disconnect() {
+        let game = TestGame::new().await;
+        let player = game.player("test_player").await;
+        let (mut host, _) = game.connect(&game.host).await;
+        let (player_client, _) = game.connect(&player).await;
+        host.recv().await;
+
+        // The host is told when the player leaves
+        player_client.close().await;
+        assert_eq!(
+            host.recv().await,
+            ProtocolMessage::Disconnect {
+                username: player.username.clone(),
+            }
+        );
+
+        // The player can then reconnect
+        let (_player_client, received) = game.connect(&player).await;
+        assert_eq!(received, vec![game.connect_msg(&game.host)]);
+        assert_eq!(host.recv().await, game.connect_msg(&player));
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let game = TestGame::new().await;
+        let player = game.player("test_player").await;
+        let (mut host, _) = game.connect(&game.host).await;
+
+        host.send(&ProtocolMessage::PlaceToken(token(0, 0))).await;
+        host.send(&move_token("0", 3, 4)).await;
+        host.send(&ProtocolMessage::PlaceObj(placed_obj(1))).await;
+        for _ in 0..3 {
+            host.recv().await;
+        }
+
+        // A player joining late is sent the current state, after the other users
+        let (_player_client, received) = game.connect(&player).await;
+        let mut placed = token(3, 4);
+        placed.id = Some("0".to_string());
+        placed.controller = Some(game.host.username.clone());
+        let mut obj = placed_obj(1);
+        obj.id = Some("0".to_string());
+        assert_eq!(
+            received,
+            vec![
+                game.connect_msg(&game.host),
+                ProtocolMessage::PlaceToken(placed),
+                ProtocolMessage::PlaceObj(obj),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_instances() {
+        let game = TestGame::new().await;
+        let other = game.other_instance().await;
+        let player = game.player("test_player").await;
+        let (mut host, _) = game.connect(&game.host).await;
+        host.send(&ProtocolMessage::PlaceToken(token(0, 0))).await;
+        let placed = host.recv().await;
+
+        // A player connecting to the other instance catches up, and everyone sees them join
+        let (mut player_client, received) = other.connect(&player).await;
+        assert_eq!(received, vec![game.connect_msg(&game.host), placed]);
+        assert_eq!(host.recv().await, game.connect_msg(&player));
+
+        // Messages from either instance reach both
+        let set_controller = ProtocolMessage::SetController {
+            token_id: "0".to_string(),
+            new_controller: player.username.clone(),
+        };
+        host.send(&set_controller).await;
+        assert_eq!(host.recv().await, set_controller);
+        assert_eq!(player_client.recv().await, set_controller);
+        player_client.send(&move_token("0", 1, 1)).await;
+        assert_eq!(host.recv().await, move_token("0", 1, 1));
+        assert_eq!(player_client.recv().await, move_token("0", 1, 1));
+
+        // So do renames, wherever they come from
+        let (_, new_username) = game
+            .db
+            .set_nickname(&player.token, "renamed")
+            .await
+            .unwrap();
+        other
+            .games
+            .rename_user(player.id, &player.username, &new_username)
+            .await;
+        let renamed = ProtocolMessage::RenameUser {
+            username: player.username.clone(),
+            new_username: new_username.clone(),
+        };
+        assert_eq!(host.recv().await, renamed);
+        assert_eq!(player_client.recv().await, renamed);
+
+        // Leaving too
+        player_client.close().await;
+        assert_eq!(
+            host.recv().await,
+            ProtocolMessage::Disconnect {
+                username: new_username,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let game = TestGame::new().await;
+        let (mut host, _) = game.connect(&game.host).await;
+        host.send(&ProtocolMessage::PlaceToken(token(0, 0))).await;
+        let placed = host.recv().await;
+
+        // Clients are told why they're being dropped
+        game.games.shutdown("testing").await;
+        assert_eq!(
+            host.recv().await,
+            ProtocolMessage::ServerShutdown {
+                reason: "testing".to_string(),
+            }
+        );
+        host.wait_closed().await;
+
+        // No more connections are accepted
+        assert!(connect_async(format!("ws://{}", game.addr)).await.is_err());
+
+        // The game carries on from where it was on another instance
+        let other = game.other_instance().await;
+        let (_host, received) = other.connect(&game.host).await;
+        assert_eq!(received, vec![placed]);
+    }
+
+    #[tokio::test]
+    async fn test_close_game() {
+        let game = TestGame::new().await;
+        let other = game.other_instance().await;
+        let player = game.player("test_player").await;
+        let (mut host, _) = game.connect(&game.host).await;
+        host.send(&ProtocolMessage::PlaceToken(token(0, 0))).await;
+        let placed = host.recv().await;
+        let (player_client, _) = other.connect(&player).await;
+        assert_eq!(host.recv().await, game.connect_msg(&player));
+
+        // Each instance counts its own clients apart from everyone else's
+        assert_eq!(
+            game.games.status().await,
+            vec![ServerStatus {
+                game_token: game.game_token.clone(),
+                clients: 1,
+                remote_clients: 1,
+            }]
+        );
+
+        // Closing the game drops everyone, on every instance
+        game.games.close_game(&game.game_token, "closed").await;
+        assert_eq!(
+            host.recv().await,
+            ProtocolMessage::ServerShutdown {
+                reason: "closed".to_string(),
+            }
+        );
+        host.wait_closed().await;
+        player_client.wait_closed().await;
+
+        // Reconnecting starts a fresh server, from where the game was left
+        let (_host, received) = game.connect(&game.host).await;
+        assert_eq!(received, vec![placed]);
+    }
+}